@@ -89,6 +89,12 @@ impl<Long, Short> Table<Long, Short> {
     }
 }
 
+impl<Long, Short> Default for Table<Long, Short> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Id Compressor optimized for numerically clustered ids.
 /// Uses Table to shorten all but the low `SHIFT` bits, which are unchanged.
 ///
@@ -124,6 +130,12 @@ where
     }
 }
 
+impl<Long, Short> Default for RangeTable<Long, Short> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<Long, Short> RangeTable<Long, Short> {
     pub fn new() -> Self {
         Self {
@@ -26,6 +26,12 @@ pub struct UuidShortener {
     table: id_compress::RangeTable<u128, usize>,
 }
 
+impl Default for UuidShortener {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[wasm_bindgen]
 impl UuidShortener {
     pub fn new() -> Self {
@@ -44,15 +50,13 @@ impl UuidShortener {
             base[9], base[10], base[11], base[12], base[13], base[14], base[15],
         ];
         let base_id = u128::from_be_bytes(array);
-        let short = self.table.shorten(base_id);
-        return short;
+        self.table.shorten(base_id)
     }
 
     #[wasm_bindgen]
     pub fn shorten_string(&mut self, base: String) -> usize {
         let base_id = uuid::Uuid::parse_str(&base).unwrap().as_u128();
-        let short = self.table.shorten(base_id);
-        return short;
+        self.table.shorten(base_id)
     }
 
     #[wasm_bindgen]
@@ -97,7 +101,7 @@ pub fn reserve_random1000() {
 
 #[wasm_bindgen]
 pub fn add(a: usize, b: usize) -> usize {
-    return a + b;
+    a + b
 }
 
 #[wasm_bindgen]
@@ -108,9 +112,11 @@ pub fn insert_bench(size: usize, per_chunk: usize, check_parents: bool) {
     }
 }
 
+#[allow(dead_code)]
 struct FullId(u128);
 typed_number_for_struct!(FullId, u128);
 
+#[allow(dead_code)]
 struct ShortId(usize);
 typed_number_for_struct!(ShortId, usize);
 
@@ -130,6 +136,12 @@ pub struct TestMap {
     map: HashMap<u128, u32, ahash::RandomState>,
 }
 
+impl Default for TestMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[wasm_bindgen]
 impl TestMap {
     #[wasm_bindgen]
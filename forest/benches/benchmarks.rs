@@ -4,14 +4,15 @@ use criterion::{
 use forest::{
     example_node,
     forest::ChunkId,
-    test_stuff::{chunked_tree, walk_all, walk_direct_all},
+    test_stuff::{chunked_tree, walk_all, walk_direct_all, wide_tree, COLORS},
     Def, Label, NodeId,
 };
 use rand::Rng;
 
 use std::{cell::RefCell, rc::Rc};
 
-fn big_basic_tree(size: usize) {
+/// Tree with a sequence of `size` bytes (each as a node with a one byte payload) under its root.
+fn big_basic_tree(size: usize) -> example_node::BasicNode {
     let rng = Rc::new(RefCell::new(rand::thread_rng()));
     let new_node_id = || -> NodeId { NodeId(rng.borrow_mut().gen()) };
     let new_label = || -> Label { Label(rng.borrow_mut().gen()) };
@@ -25,19 +26,20 @@ fn big_basic_tree(size: usize) {
     };
 
     let label = new_label();
+    let byte_def = new_def();
 
     b.traits.insert(
         label,
         (0..size)
-            .into_iter()
-            .map(|_| example_node::BasicNode {
-                def: new_def(),
+            .map(|i| example_node::BasicNode {
+                def: byte_def,
                 id: new_node_id(),
-                payload: None,
+                payload: Some(forest::im::vector![i as u8]),
                 traits: std::collections::HashMap::new(),
             })
             .collect(),
     );
+    b
 }
 
 fn walk_bench(b: &mut Bencher<WallTime>, size: usize, per_chunk: usize) {
@@ -56,14 +58,58 @@ fn walk_direct_bench(b: &mut Bencher<WallTime>, size: usize) {
     b.iter(|| black_box(walk_direct_all(&forest, ChunkId(id))));
 }
 
-fn insert_bench(b: &mut Bencher<WallTime>, size: usize, per_chunk: usize, check_parents: bool) {
-    b.iter(|| {
-        let (t, _id) = chunked_tree(size, per_chunk);
-        if check_parents {
-            t.get_parent_data();
-        }
-        black_box(t)
-    });
+fn insert_bench(b: &mut Bencher<WallTime>, size: usize, per_chunk: usize) {
+    b.iter(|| black_box(chunked_tree(size, per_chunk)));
+}
+
+/// Appends one child to a node which already has `size` children.
+fn append_child_bench(b: &mut Bencher<WallTime>, size: usize) {
+    let (tree, root) = wide_tree(size);
+    let mut forest = forest::Forest::from(tree);
+    let leaf = || example_node::BasicNode {
+        def: Def(3),
+        id: NodeId(0),
+        payload: None,
+        traits: std::collections::HashMap::new(),
+    };
+    // Build the cached trait offsets, which appends then keep up to date.
+    forest.insert_child(root, COLORS, size, leaf()).unwrap();
+    b.iter_batched(
+        || forest.clone(),
+        |mut forest| {
+            forest.insert_child(root, COLORS, size + 1, leaf()).unwrap();
+            black_box(forest)
+        },
+        criterion::BatchSize::SmallInput,
+    );
+}
+
+/// Diffs a node which has `size` children against a copy with one child appended.
+fn diff_append_bench(b: &mut Bencher<WallTime>, size: usize) {
+    let (tree, root) = wide_tree(size);
+    let old = forest::Forest::from(tree);
+    let mut new = old.clone();
+    let leaf = example_node::BasicNode {
+        def: Def(3),
+        id: NodeId(0),
+        payload: None,
+        traits: std::collections::HashMap::new(),
+    };
+    new.insert_child(root, COLORS, size, leaf).unwrap();
+    assert_eq!(forest::Forest::diff(&old, &new).len(), 1);
+    b.iter(|| black_box(forest::Forest::diff(&old, &new)));
+}
+
+fn insert_tree_bench(b: &mut Bencher<WallTime>, size: usize) {
+    b.iter_batched(
+        || big_basic_tree(size),
+        |tree| {
+            let mut forest = forest::Forest::new();
+            forest.insert_tree(tree);
+            black_box(forest)
+        },
+        criterion::BatchSize::LargeInput,
+    );
 }
 
 pub fn criterion_benchmark(c: &mut Criterion) {
@@ -74,10 +120,10 @@ pub fn criterion_benchmark(c: &mut Criterion) {
 
     for count in [1_000_000].iter().cloned() {
         group.bench_function(format!("{} node insert", count), |b| {
-            insert_bench(b, count, 0, false)
+            insert_bench(b, count, 0)
         });
-        group.bench_function(format!("{} node insert + update parents", count), |b| {
-            insert_bench(b, count, 0, true)
+        group.bench_function(format!("{} byte insert_tree", count), |b| {
+            insert_tree_bench(b, count)
         });
         group.bench_function(format!("{} node walk", count), |b| {
             walk_direct_bench(b, count)
@@ -89,14 +135,7 @@ pub fn criterion_benchmark(c: &mut Criterion) {
         for chunk_size in [5, 1_000].iter().cloned() {
             group.bench_function(
                 format!("{} node insert in chunks of {}", count, chunk_size),
-                |b| insert_bench(b, count, chunk_size, false),
-            );
-            group.bench_function(
-                format!(
-                    "{} node insert in chunks of {} + update parents",
-                    count, chunk_size
-                ),
-                |b| insert_bench(b, count, chunk_size, true),
+                |b| insert_bench(b, count, chunk_size),
             );
             group.bench_function(
                 format!("{} node walk with nav over chunks of {}", count, chunk_size),
@@ -105,6 +144,17 @@ pub fn criterion_benchmark(c: &mut Criterion) {
         }
     }
 
+    for count in [100_000].iter().cloned() {
+        group.bench_function(
+            format!("append child to node with {} children", count),
+            |b| append_child_bench(b, count),
+        );
+        group.bench_function(
+            format!("diff append to node with {} children", count),
+            |b| diff_append_bench(b, count),
+        );
+    }
+
     group.finish();
 }
 
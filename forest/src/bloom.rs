@@ -0,0 +1,242 @@
+//! Bloom filter which supports adding large ranges of ids efficiently.
+//!
+//! See "Chunk Specialized Bloom filter" in the README:
+//! ids are added to one of several bloom filters, each of which holds ids truncated to a different number of bits.
+//! Ranges are added to the finest filter where they cover at most [MAX_PREFIXES] truncated ids,
+//! so adding any range is `O(1)`.
+
+use std::hash::BuildHasher;
+
+use crate::{
+    chunk::{Chunk, ChunkId},
+    forest::Forest,
+    node_id::NodeId,
+    snapshot::{put_u32, put_u64, Input, SnapshotError},
+    tree::{IdBase, NodeNav},
+};
+
+/// Number of low bits truncated from ids for each level.
+const SHIFTS: [u32; 5] = [0, 8, 16, 24, 32];
+
+/// Most truncated ids added to a level for a single range.
+pub const MAX_PREFIXES: IdBase = 256;
+
+const HASHES: u64 = 4;
+
+/// Bits per expected entry, per level.
+const BITS_PER_ENTRY: usize = 10;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RangeBloom {
+    /// Bits for each level in [SHIFTS].
+    levels: Vec<Vec<u64>>,
+    /// Set when a range too large for any level is added: everything may be contained.
+    saturated: bool,
+}
+
+impl RangeBloom {
+    /// Filter sized for about `expected` truncated ids in each level.
+    pub fn new(expected: usize) -> Self {
+        Self::with_sizes([expected; SHIFTS.len()])
+    }
+
+    fn with_sizes(expected: [usize; SHIFTS.len()]) -> Self {
+        RangeBloom {
+            levels: expected
+                .iter()
+                .map(|e| vec![0; (e.max(&1) * BITS_PER_ENTRY).div_ceil(64)])
+                .collect(),
+            saturated: false,
+        }
+    }
+
+    /// Filter containing the given ranges (inclusive), sized to fit them.
+    pub fn from_ranges(ranges: &[(NodeId, NodeId)]) -> Self {
+        let mut sizes = [0; SHIFTS.len()];
+        for (first, last) in ranges {
+            if let Some((level, start, end)) = level_for(*first, *last) {
+                sizes[level] += (end - start) as usize + 1;
+            }
+        }
+        let mut filter = Self::with_sizes(sizes);
+        for (first, last) in ranges {
+            filter.insert_range(*first, *last);
+        }
+        filter
+    }
+
+    /// Filter containing the ids of all the chunks in the tree under `root`.
+    pub fn for_tree<TChunk>(forest: &Forest<TChunk>, root: ChunkId) -> Self
+    where
+        TChunk: Clone + PartialEq<TChunk>,
+        for<'a> &'a TChunk: Chunk,
+    {
+        let mut ranges = vec![];
+        let mut pending = vec![root];
+        while let Some(id) = pending.pop() {
+            if let Some(chunk) = forest.find_nodes(id) {
+                let extent = chunk.id_extent().0 as IdBase;
+                if extent > 0 {
+                    ranges.push((id.0, NodeId(id.0 .0 + extent - 1)));
+                }
+                for label in chunk.get_traits() {
+                    pending.extend(chunk.get_trait(label));
+                }
+            }
+        }
+
+        Self::from_ranges(&ranges)
+    }
+
+    pub fn insert_id(&mut self, id: NodeId) {
+        self.add(0, id.0);
+    }
+
+    /// Adds the ids from `first` to `last` (inclusive).
+    pub fn insert_range(&mut self, first: NodeId, last: NodeId) {
+        match level_for(first, last) {
+            Some((level, start, end)) => {
+                for prefix in start..=end {
+                    self.add(level, prefix);
+                }
+            }
+            None => self.saturated = true,
+        }
+    }
+
+    /// False if `id` was definitely not added. True if it may have been.
+    pub fn may_contain(&self, id: NodeId) -> bool {
+        self.saturated
+            || SHIFTS.iter().enumerate().any(|(level, shift)| {
+                self.positions(level, id.0 >> shift)
+                    .all(|bit| self.get(level, bit))
+            })
+    }
+
+    fn add(&mut self, level: usize, prefix: IdBase) {
+        let positions: Vec<usize> = self.positions(level, prefix).collect();
+        for bit in positions {
+            self.levels[level][bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    fn get(&self, level: usize, bit: usize) -> bool {
+        self.levels[level][bit / 64] & (1 << (bit % 64)) != 0
+    }
+
+    /// Bits used for `prefix` in `level`, using double hashing.
+    fn positions(&self, level: usize, prefix: IdBase) -> impl Iterator<Item = usize> {
+        let hash = ahash::RandomState::with_seeds(1, 2, 3, 4).hash_one((level, prefix));
+        let (h1, h2) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+        let bits = self.levels[level].len() as u64 * 64;
+        (0..HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bits) as usize)
+    }
+
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.push(self.saturated as u8);
+        for level in self.levels.iter() {
+            put_u32(out, level.len() as u32);
+            for word in level.iter() {
+                put_u64(out, *word);
+            }
+        }
+    }
+
+    pub(crate) fn read(input: &mut Input) -> Result<Self, SnapshotError> {
+        let saturated = input.flag()?;
+        let mut levels = vec![];
+        for _ in SHIFTS.iter() {
+            let words = input.u32()?;
+            if words == 0 {
+                return Err(SnapshotError::Malformed("empty bloom filter"));
+            }
+            levels.push((0..words).map(|_| input.u64()).collect::<Result<_, _>>()?);
+        }
+        Ok(RangeBloom { levels, saturated })
+    }
+}
+
+/// Finest level where the range `first..=last` covers at most [MAX_PREFIXES] truncated ids,
+/// along with the first and last truncated ids.
+fn level_for(first: NodeId, last: NodeId) -> Option<(usize, IdBase, IdBase)> {
+    debug_assert!(first <= last);
+    SHIFTS.iter().enumerate().find_map(|(level, shift)| {
+        let (start, end) = (first.0 >> shift, last.0 >> shift);
+        (end - start < MAX_PREFIXES).then_some((level, start, end))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_stuff::uniform_tree;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    #[test]
+    fn contains_inserted() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut filter = RangeBloom::new(10_000);
+        let ids: Vec<NodeId> = (0..500).map(|_| NodeId(rng.gen())).collect();
+        let ranges: Vec<(NodeId, NodeId)> = (0..500)
+            .map(|i| {
+                let first: IdBase = rng.gen::<u64>() as IdBase;
+                (NodeId(first), NodeId(first + (1 << (i % 40))))
+            })
+            .collect();
+        for id in ids.iter() {
+            filter.insert_id(*id);
+        }
+        for (first, last) in ranges.iter() {
+            filter.insert_range(*first, *last);
+        }
+        assert!(!filter.saturated);
+
+        assert!(ids.iter().all(|id| filter.may_contain(*id)));
+        for (first, last) in ranges {
+            assert!(filter.may_contain(first));
+            assert!(filter.may_contain(last));
+            assert!(filter.may_contain(NodeId(first.0 + (last.0 - first.0) / 2)));
+        }
+    }
+
+    #[test]
+    fn false_positives() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let ranges: Vec<(NodeId, NodeId)> = (0..1000)
+            .map(|_| {
+                let first: IdBase = rng.gen();
+                (NodeId(first), NodeId(first.saturating_add(1_000_000)))
+            })
+            .collect();
+        let filter = RangeBloom::from_ranges(&ranges);
+        assert!(ranges.iter().all(|(first, _)| filter.may_contain(*first)));
+        let hits = (0..10_000)
+            .filter(|_| filter.may_contain(NodeId(rng.gen())))
+            .count();
+        assert!(hits < 500, "{} false positives", hits);
+    }
+
+    #[test]
+    fn huge_range() {
+        let mut filter = RangeBloom::new(1);
+        filter.insert_range(NodeId(0), NodeId(IdBase::MAX));
+        assert!(filter.may_contain(NodeId(12345)));
+    }
+
+    #[test]
+    fn tree() {
+        let (forest, root) = uniform_tree(1000);
+        let filter = RangeBloom::for_tree(&forest, ChunkId(root));
+        assert!((0..5001).all(|i| filter.may_contain(NodeId(i))));
+        let mut rng = StdRng::seed_from_u64(0);
+        let hits = (0..1000)
+            .filter(|_| filter.may_contain(NodeId(rng.gen())))
+            .count();
+        assert!(hits < 50, "{} false positives", hits);
+
+        let mut data = vec![];
+        filter.write(&mut data);
+        let read = RangeBloom::read(&mut Input { data: &data }).unwrap();
+        assert_eq!(read, filter);
+    }
+}
@@ -0,0 +1,574 @@
+//! Serializable edits to a [crate::Forest], applied atomically with [crate::Forest::apply].
+//!
+//! Subtrees are carried as chunks (see [Subtree]), so inserting or deleting a run of nodes stored in a
+//! [crate::uniform_chunk::UniformChunk] stays compact.
+//!
+//! The serialized format is (all integers little endian):
+//! - A u32 op count, then that many ops: a tag byte followed by the op's fields.
+//! - The chunks of all inserted subtrees, in order, as a snapshot (see [snapshot::write_chunks]).
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    io::{self, Read, Write},
+};
+
+use crate::{
+    chunk::ChunkId,
+    chunker,
+    error::ForestError,
+    example_node::BasicNode,
+    indirect::enum_chunk,
+    node_id::NodeId,
+    snapshot::{self, put_u128, put_u32, put_u64, Input, SnapshotError},
+    tree::{Label, NodeNav},
+    ShapeLibrary,
+};
+
+const INSERT: u8 = 0;
+const DELETE_RANGE: u8 = 1;
+const SET_PAYLOAD: u8 = 2;
+const MOVE: u8 = 3;
+
+/// Position in a trait.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Place {
+    pub parent: NodeId,
+    pub label: Label,
+    pub index: usize,
+}
+
+/// Tree which is not in a forest, stored as its chunks.
+#[derive(Clone)]
+pub struct Subtree {
+    pub root: NodeId,
+    /// Chunks of the tree, including the one with `root` as its only top level node.
+    pub chunks: Vec<(ChunkId, enum_chunk::Chunk)>,
+}
+
+impl Subtree {
+    /// Chunks `tree`, allocating its ids sequentially from `first_id` (the ids in `tree` are ignored).
+    pub fn new(tree: &BasicNode, first_id: NodeId) -> Self {
+        let tree = chunker::Analyzed::new(tree);
+        Subtree {
+            root: first_id,
+            chunks: chunker::chunk(&tree, first_id, &mut ShapeLibrary::default()),
+        }
+    }
+
+    /// Checks the chunks form a single tree under `root`:
+    /// `root` is the only top level node of one of the chunks, every child of a chunk is another chunk of this subtree with no other parent,
+    /// and every chunk is under `root` (so there are no cycles).
+    pub fn validate(&self) -> Result<(), ForestError> {
+        let chunks: HashMap<ChunkId, &enum_chunk::Chunk> =
+            self.chunks.iter().map(|(id, chunk)| (*id, chunk)).collect();
+        let root = ChunkId(self.root);
+        match chunks.get(&root) {
+            None => return Err(ForestError::UnknownId(self.root)),
+            Some(enum_chunk::Chunk::Uniform(c)) if c.get_count() != 1 => {
+                return Err(ForestError::SchemaMismatch(root))
+            }
+            Some(_) => {}
+        }
+        if chunks.len() != self.chunks.len() {
+            let mut ids = HashSet::new();
+            if let Some((id, _)) = self.chunks.iter().find(|(id, _)| !ids.insert(*id)) {
+                return Err(ForestError::OverlappingChunks(*id, *id));
+            }
+        }
+        let mut children = HashSet::new();
+        for (id, chunk) in self.chunks.iter() {
+            for label in chunk.get_traits() {
+                for child in chunk.get_trait(label) {
+                    if !chunks.contains_key(&child) {
+                        return Err(ForestError::DanglingChild { parent: *id, child });
+                    }
+                    if child == root || !children.insert(child) {
+                        return Err(ForestError::MultipleParents(child));
+                    }
+                }
+            }
+        }
+        // Each chunk has at most one parent, so this visits each chunk under root once.
+        let mut visited = HashSet::new();
+        let mut pending = vec![root];
+        while let Some(id) = pending.pop() {
+            visited.insert(id);
+            let chunk = chunks[&id];
+            for label in chunk.get_traits() {
+                pending.extend(chunk.get_trait(label));
+            }
+        }
+        if let Some((id, _)) = self.chunks.iter().find(|(id, _)| !visited.contains(id)) {
+            // A chunk with a parent which is not under root is in a cycle.
+            return Err(match children.contains(id) {
+                true => ForestError::Cycle(id.0),
+                false => ForestError::Unreachable(*id),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Subtree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Subtree")
+            .field("root", &self.root)
+            .field(
+                "chunks",
+                &self.chunks.iter().map(|(id, _)| id).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Op {
+    /// Inserts the trees as consecutive children starting at `place`.
+    Insert { place: Place, trees: Vec<Subtree> },
+    /// Deletes `count` consecutive children starting at `place`, and their descendants.
+    DeleteRange { place: Place, count: usize },
+    SetPayload {
+        id: NodeId,
+        payload: Option<Vec<u8>>,
+    },
+    /// Moves the subtree under `id` to `to` (where `index` is the position after it is removed from its old parent),
+    /// or makes it a root if `to` is None.
+    Move { id: NodeId, to: Option<Place> },
+}
+
+/// Sequence of [Op]s, applied in order.
+#[derive(Clone, Default, Debug)]
+pub struct Changeset {
+    pub ops: Vec<Op>,
+}
+
+impl Changeset {
+    pub fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        let mut data = vec![];
+        put_u32(&mut data, self.ops.len() as u32);
+        let mut chunks = vec![];
+        for op in self.ops.iter() {
+            match op {
+                Op::Insert { place, trees } => {
+                    data.push(INSERT);
+                    put_place(&mut data, place);
+                    put_u32(&mut data, trees.len() as u32);
+                    for tree in trees.iter() {
+                        put_u128(&mut data, tree.root.0);
+                        put_u32(&mut data, tree.chunks.len() as u32);
+                        chunks.extend(tree.chunks.iter().map(|(id, chunk)| (*id, chunk)));
+                    }
+                }
+                Op::DeleteRange { place, count } => {
+                    data.push(DELETE_RANGE);
+                    put_place(&mut data, place);
+                    put_u64(&mut data, *count as u64);
+                }
+                Op::SetPayload { id, payload } => {
+                    data.push(SET_PAYLOAD);
+                    put_u128(&mut data, id.0);
+                    match payload {
+                        Some(payload) => {
+                            data.push(1);
+                            put_u32(&mut data, payload.len() as u32);
+                            data.extend(payload);
+                        }
+                        None => data.push(0),
+                    }
+                }
+                Op::Move { id, to } => {
+                    data.push(MOVE);
+                    put_u128(&mut data, id.0);
+                    match to {
+                        Some(place) => {
+                            data.push(1);
+                            put_place(&mut data, place);
+                        }
+                        None => data.push(0),
+                    }
+                }
+            }
+        }
+        out.write_all(&data)?;
+        snapshot::write_chunks(chunks, out)
+    }
+
+    pub fn read(input: &mut impl Read) -> Result<Self, SnapshotError> {
+        let mut data = vec![];
+        input.read_to_end(&mut data)?;
+        let mut input = Input { data: &data };
+
+        // Inserted trees with their chunk counts: their chunks are read after all the ops.
+        let mut ops = vec![];
+        for _ in 0..input.u32()? {
+            let op = match input.u8()? {
+                INSERT => {
+                    let place = read_place(&mut input)?;
+                    let trees = (0..input.u32()?)
+                        .map(|_| {
+                            let root = NodeId(input.u128()?);
+                            Ok((root, input.u32()? as usize))
+                        })
+                        .collect::<Result<Vec<_>, SnapshotError>>()?;
+                    (
+                        Some(trees),
+                        Op::Insert {
+                            place,
+                            trees: vec![],
+                        },
+                    )
+                }
+                DELETE_RANGE => {
+                    let place = read_place(&mut input)?;
+                    let count = read_usize(&mut input)?;
+                    (None, Op::DeleteRange { place, count })
+                }
+                SET_PAYLOAD => {
+                    let id = NodeId(input.u128()?);
+                    let payload = if input.flag()? {
+                        let len = input.u32()? as usize;
+                        Some(input.take(len)?.to_vec())
+                    } else {
+                        None
+                    };
+                    (None, Op::SetPayload { id, payload })
+                }
+                MOVE => {
+                    let id = NodeId(input.u128()?);
+                    let to = if input.flag()? {
+                        Some(read_place(&mut input)?)
+                    } else {
+                        None
+                    };
+                    (None, Op::Move { id, to })
+                }
+                _ => return Err(SnapshotError::Malformed("unknown op")),
+            };
+            ops.push(op);
+        }
+
+        let mut chunks = snapshot::read_chunks(&mut input.data)?.into_iter();
+        let ops = ops
+            .into_iter()
+            .map(|(trees, mut op)| {
+                if let (Some(trees), Op::Insert { trees: out, .. }) = (trees, &mut op) {
+                    for (root, count) in trees {
+                        let tree_chunks: Vec<_> = chunks.by_ref().take(count).collect();
+                        if tree_chunks.len() != count {
+                            return Err(SnapshotError::Malformed("missing subtree chunks"));
+                        }
+                        out.push(Subtree {
+                            root,
+                            chunks: tree_chunks,
+                        });
+                    }
+                }
+                Ok(op)
+            })
+            .collect::<Result<_, _>>()?;
+        if chunks.next().is_some() {
+            return Err(SnapshotError::Malformed("unused subtree chunks"));
+        }
+        Ok(Changeset { ops })
+    }
+}
+
+fn put_place(out: &mut Vec<u8>, place: &Place) {
+    put_u128(out, place.parent.0);
+    put_u128(out, place.label.0);
+    put_u64(out, place.index as u64);
+}
+
+fn read_place(input: &mut Input) -> Result<Place, SnapshotError> {
+    Ok(Place {
+        parent: NodeId(input.u128()?),
+        label: Label(input.u128()?),
+        index: read_usize(input)?,
+    })
+}
+
+fn read_usize(input: &mut Input) -> Result<usize, SnapshotError> {
+    usize::try_from(input.u64()?).map_err(|_| SnapshotError::Malformed("index out of range"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        indirect_node::IndirectChunk,
+        node_id::HasId,
+        test_stuff::{check_parents, uniform_tree, CHANNELS, COLORS},
+        tree::{Def, NodeData, NodeNav},
+        Forest,
+    };
+    use std::collections::HashMap;
+
+    fn bytes(values: &[u8]) -> BasicNode {
+        let byte = |value: &u8| BasicNode {
+            id: NodeId(0),
+            def: Def(5),
+            payload: Some(im::vector![*value]),
+            traits: HashMap::new(),
+        };
+        BasicNode {
+            id: NodeId(0),
+            def: Def(4),
+            payload: None,
+            traits: [(Label(1), values.iter().map(byte).collect())]
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    fn at(parent: u128, label: Label, index: usize) -> Place {
+        Place {
+            parent: NodeId(parent),
+            label,
+            index,
+        }
+    }
+
+    fn edits() -> Changeset {
+        Changeset {
+            ops: vec![
+                Op::Insert {
+                    place: at(0, COLORS, 1),
+                    trees: vec![Subtree::new(&bytes(&[1, 2, 3]), NodeId(1000))],
+                },
+                Op::SetPayload {
+                    id: NodeId(7),
+                    payload: Some(vec![42]),
+                },
+                Op::Move {
+                    id: NodeId(8),
+                    to: Some(at(0, COLORS, 0)),
+                },
+                Op::DeleteRange {
+                    place: at(0, COLORS, 2),
+                    count: 2,
+                },
+                Op::SetPayload {
+                    id: NodeId(12),
+                    payload: None,
+                },
+            ],
+        }
+    }
+
+    fn payload(forest: &Forest, id: u128) -> Option<Vec<u8>> {
+        let node = forest.get_tree(NodeId(id)).unwrap();
+        node.get_payload().map(|p| p.into_iter().cloned().collect())
+    }
+
+    #[test]
+    fn apply_and_undo() {
+        let (tree, root) = uniform_tree(3);
+        let original = Forest::from(tree);
+        let mut forest = original.clone();
+        let inverse = forest.apply(&edits()).unwrap();
+
+        let nav = forest.forest.nav_from(root).unwrap();
+        let children: Vec<u128> = nav.get_trait(COLORS).map(|c| c.get_id().0).collect();
+        assert_eq!(children, vec![8, 1, 11]);
+        assert!(forest.get_tree(NodeId(7)).is_none());
+        assert_eq!(payload(&forest, 12), None);
+        check_parents(nav);
+        forest.forest.validate().unwrap();
+        assert!(!Forest::diff(&original, &forest).is_empty());
+
+        let redo = forest.apply(&inverse).unwrap();
+        assert!(Forest::diff(&original, &forest).is_empty());
+        assert_eq!(payload(&forest, 7), Some(vec![4]));
+        assert_eq!(
+            forest.get_parent(NodeId(8)).unwrap().unwrap().label,
+            CHANNELS[1]
+        );
+        forest.forest.validate().unwrap();
+
+        // The inverse of the inverse redoes the edits.
+        let mut expected = original.clone();
+        expected.apply(&edits()).unwrap();
+        forest.apply(&redo).unwrap();
+        assert!(Forest::diff(&expected, &forest).is_empty());
+    }
+
+    #[test]
+    fn atomic() {
+        let (tree, _) = uniform_tree(3);
+        let original = Forest::from(tree);
+        let mut forest = original.clone();
+        let mut changeset = edits();
+        changeset.ops.push(Op::DeleteRange {
+            place: at(0, COLORS, 5),
+            count: 1,
+        });
+        assert_eq!(
+            forest.apply(&changeset).err(),
+            Some(ForestError::IndexOutOfRange {
+                parent: NodeId(0),
+                label: COLORS,
+                index: 5
+            })
+        );
+        assert!(Forest::diff(&original, &forest).is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_subtrees() {
+        let (tree, _) = uniform_tree(3);
+        let original = Forest::from(tree);
+        let mut forest = original.clone();
+        let insert = |tree: Subtree| Changeset {
+            ops: vec![Op::Insert {
+                place: at(0, COLORS, 0),
+                trees: vec![tree],
+            }],
+        };
+
+        // Reusing the id of an existing chunk.
+        assert_eq!(
+            forest
+                .apply(&insert(Subtree::new(&bytes(&[]), NodeId(1))))
+                .err(),
+            Some(ForestError::OverlappingChunks(
+                ChunkId(NodeId(1)),
+                ChunkId(NodeId(1))
+            ))
+        );
+
+        // Taking over an existing chunk as a child.
+        let mut tree = Subtree::new(&bytes(&[]), NodeId(1000));
+        tree.chunks[0].1 = IndirectChunk {
+            def: Def(4),
+            payload: None,
+            payload_layout: None,
+            traits: [(Label(1), vec![ChunkId(NodeId(1))])].into_iter().collect(),
+        }
+        .into();
+        assert_eq!(
+            forest.apply(&insert(tree)).err(),
+            Some(ForestError::DanglingChild {
+                parent: ChunkId(NodeId(1000)),
+                child: ChunkId(NodeId(1))
+            })
+        );
+
+        // Chunks which are not under the root: a cycle, and a second root.
+        let node = |children: &[u128]| -> enum_chunk::Chunk {
+            IndirectChunk {
+                def: Def(4),
+                payload: None,
+                payload_layout: None,
+                traits: match children.is_empty() {
+                    true => Default::default(),
+                    false => [(
+                        Label(1),
+                        children.iter().map(|c| ChunkId(NodeId(*c))).collect(),
+                    )]
+                    .into_iter()
+                    .collect(),
+                },
+            }
+            .into()
+        };
+        let tree = |chunks: &[(u128, &[u128])]| Subtree {
+            root: NodeId(1000),
+            chunks: chunks
+                .iter()
+                .map(|(id, children)| (ChunkId(NodeId(*id)), node(children)))
+                .collect(),
+        };
+        assert_eq!(
+            forest
+                .apply(&insert(tree(&[
+                    (1000, &[]),
+                    (2000, &[3000]),
+                    (3000, &[2000])
+                ])))
+                .err(),
+            Some(ForestError::Cycle(NodeId(2000)))
+        );
+        assert_eq!(
+            forest
+                .apply(&insert(tree(&[(1000, &[]), (2000, &[])])))
+                .err(),
+            Some(ForestError::Unreachable(ChunkId(NodeId(2000))))
+        );
+
+        // A root chunk with more than one top level node.
+        let colors = original
+            .forest
+            .find_nodes(ChunkId(NodeId(1)))
+            .unwrap()
+            .clone();
+        let tree = Subtree {
+            root: NodeId(1000),
+            chunks: vec![(ChunkId(NodeId(1000)), colors)],
+        };
+        assert_eq!(
+            forest.apply(&insert(tree)).err(),
+            Some(ForestError::SchemaMismatch(ChunkId(NodeId(1000))))
+        );
+        assert!(Forest::diff(&original, &forest).is_empty());
+    }
+
+    #[test]
+    fn rejects_overflowing_ranges() {
+        let (tree, _) = uniform_tree(3);
+        let mut forest = Forest::from(tree);
+        let out_of_range = |index| {
+            Some(ForestError::IndexOutOfRange {
+                parent: NodeId(0),
+                label: COLORS,
+                index,
+            })
+        };
+        let delete = Changeset {
+            ops: vec![Op::DeleteRange {
+                place: at(0, COLORS, 1),
+                count: usize::MAX,
+            }],
+        };
+        assert_eq!(forest.apply(&delete).err(), out_of_range(3));
+        let insert = Changeset {
+            ops: vec![Op::Insert {
+                place: at(0, COLORS, usize::MAX),
+                trees: vec![
+                    Subtree::new(&bytes(&[1]), NodeId(1000)),
+                    Subtree::new(&bytes(&[2]), NodeId(2000)),
+                ],
+            }],
+        };
+        assert_eq!(forest.apply(&insert).err(), out_of_range(usize::MAX));
+    }
+
+    #[test]
+    fn round_trip() {
+        let (tree, _) = uniform_tree(3);
+        let original = Forest::from(tree);
+        let mut data = vec![];
+        edits().write(&mut data).unwrap();
+        let read = Changeset::read(&mut data.as_slice()).unwrap();
+        assert_eq!(read.ops.len(), edits().ops.len());
+
+        let mut expected = original.clone();
+        let inverse = expected.apply(&edits()).unwrap();
+        let mut forest = original.clone();
+        forest.apply(&read).unwrap();
+        assert!(Forest::diff(&expected, &forest).is_empty());
+
+        // Inverses contain chunks copied from the forest.
+        let mut data = vec![];
+        inverse.write(&mut data).unwrap();
+        forest
+            .apply(&Changeset::read(&mut data.as_slice()).unwrap())
+            .unwrap();
+        assert!(Forest::diff(&original, &forest).is_empty());
+
+        assert!(matches!(
+            Changeset::read(&mut &data[..data.len() - 1]),
+            Err(SnapshotError::Malformed(_))
+        ));
+    }
+}
@@ -1,13 +1,13 @@
 //! A `Chunk` of a Tree.
 
 use crate::{
-    node_id::{HasId, NodeId},
+    node_id::{HasId, IdOffset, NodeId},
     tree::{Node, NodeNav},
 };
 
 /// Id under which a Chunk is stored.
 /// Must be equal to or precede all [NodeId]s present in the chunk.
-#[derive(Ord, PartialOrd, Eq, PartialEq, Clone, Copy, Hash)]
+#[derive(Ord, PartialOrd, Eq, PartialEq, Clone, Copy, Hash, Debug)]
 pub struct ChunkId(pub NodeId);
 
 /// A `Chunk` of a Tree.
@@ -25,6 +25,9 @@ pub trait Chunk: Clone + PartialEq + NodeNav<ChunkId> {
     fn get(&self, first_id: NodeId, id: NodeId) -> Option<Self::View>;
 
     fn top_level_nodes(&self, first_id: NodeId) -> Self::Expander;
+
+    /// Number of ids owned by this chunk, starting at its `first_id`.
+    fn id_extent(&self) -> IdOffset;
 }
 
 // /// A chunk that uses all ids in a range.
@@ -0,0 +1,584 @@
+//! Heuristic chunking of trees into [enum_chunk::Chunk]s.
+//!
+//! Runs of sibling subtrees with identical shape are stored as [UniformChunk]s, everything else as [IndirectChunk]s.
+//! Ids are allocated sequentially in depth first pre-order (visiting traits in label order),
+//! which is the id layout [UniformChunk] uses, so chunks can be formed anywhere in the tree.
+//!
+//! [rechunk] does the same for trees which already have ids, keeping them.
+
+use std::{collections::HashMap, rc::Rc};
+
+use crate::{
+    chunk::ChunkId,
+    example_node::BasicNode,
+    indirect::enum_chunk,
+    indirect_node::IndirectChunk,
+    node_id::{HasId, IdOffset, NodeId},
+    payload::PayloadLayout,
+    sync::Shared,
+    tree::{Def, IdBase, Label, Node},
+    uniform_chunk::{ChunkSchema, OffsetSchema, UniformChunk, MAX_ID_STRIDE, MAX_SCHEMA_DEPTH},
+    util::ImHashMap,
+    ShapeLibrary,
+};
+
+/// Shape of a subtree which can be stored in a [UniformChunk].
+#[derive(PartialEq, Eq, Hash)]
+pub struct Shape {
+    pub def: Def,
+    pub payload_size: Option<u16>,
+    /// Non-empty traits in label order, with the number of children in each.
+    pub traits: Vec<(Label, u32, Rc<Shape>)>,
+    /// Number of nodes in the subtree.
+    pub id_stride: u32,
+    pub bytes_per_node: u32,
+    /// Number of levels of nodes in the subtree.
+    pub depth: u32,
+}
+
+impl Shape {
+    /// Most subtrees of this shape one [UniformChunk] can hold while its id and byte offsets fit in a u32.
+    pub fn max_run(&self) -> usize {
+        let max = u32::MAX / self.id_stride;
+        match self.bytes_per_node {
+            0 => max as usize,
+            bytes => max.min(u32::MAX / bytes) as usize,
+        }
+    }
+
+    /// Schema for a sequence of `node_count` subtrees of this shape.
+    pub fn schema(&self, node_count: u32) -> ChunkSchema {
+        let mut id_offset = 1;
+        let mut byte_offset = self.payload_size.unwrap_or(0) as u32;
+        let mut traits = std::collections::HashMap::default();
+        for (label, count, shape) in self.traits.iter() {
+            traits.insert(
+                *label,
+                OffsetSchema {
+                    id_offset: IdOffset(id_offset),
+                    byte_offset,
+                    schema: Shared::new(shape.schema(*count)),
+                },
+            );
+            id_offset += count * shape.id_stride;
+            byte_offset += count * shape.bytes_per_node;
+        }
+        ChunkSchema {
+            def: self.def,
+            node_count,
+            bytes_per_node: self.bytes_per_node,
+            id_stride: self.id_stride,
+            payload_size: self.payload_size,
+            payload_layout: None,
+            traits,
+        }
+    }
+}
+
+/// A [BasicNode] with its traits in label order, annotated with the information needed to chunk it.
+pub struct Analyzed<'a> {
+    pub node: &'a BasicNode,
+    /// Number of nodes in the subtree.
+    pub size: IdBase,
+    /// True if the ids in the subtree are sequential in depth first pre-order (see [BasicNode::id]).
+    pub sequential: bool,
+    /// None if the subtree can not be stored in a [UniformChunk].
+    pub shape: Option<Rc<Shape>>,
+    pub traits: Vec<(Label, Vec<Analyzed<'a>>)>,
+}
+
+impl<'a> Analyzed<'a> {
+    pub fn new(node: &'a BasicNode) -> Self {
+        let mut labels: Vec<&Label> = node.traits.keys().collect();
+        labels.sort();
+        let traits: Vec<(Label, Vec<Analyzed>)> = labels
+            .into_iter()
+            .map(|label| {
+                (
+                    *label,
+                    node.traits[label].iter().map(Analyzed::new).collect(),
+                )
+            })
+            .filter(|(_, children): &(Label, Vec<Analyzed>)| !children.is_empty())
+            .collect();
+
+        let size = 1 + traits
+            .iter()
+            .flat_map(|(_, children)| children.iter())
+            .map(|child| child.size)
+            .sum::<IdBase>();
+        let mut next_id = node.id.0.checked_add(1);
+        let sequential = traits
+            .iter()
+            .flat_map(|(_, children)| children.iter())
+            .all(|child| {
+                let expected = next_id;
+                next_id = next_id.and_then(|id| id.checked_add(child.size));
+                child.sequential && Some(child.node.id.0) == expected
+            });
+        let shape = Self::shape(node, &traits);
+        Analyzed {
+            node,
+            size,
+            sequential,
+            shape,
+            traits,
+        }
+    }
+
+    fn shape(node: &BasicNode, traits: &[(Label, Vec<Analyzed>)]) -> Option<Rc<Shape>> {
+        let payload_size = match &node.payload {
+            Some(p) => Some(u16::try_from(p.len()).ok()?),
+            None => None,
+        };
+        let mut id_stride: u32 = 1;
+        let mut bytes_per_node = payload_size.unwrap_or(0) as u32;
+        let mut depth = 1;
+        let mut shape_traits = vec![];
+        for (label, children) in traits.iter() {
+            let shape = children[0].shape.as_ref()?;
+            if children.iter().any(|c| c.shape.as_ref() != Some(shape)) {
+                return None;
+            }
+            let count = u32::try_from(children.len()).ok()?;
+            id_stride = id_stride.checked_add(count.checked_mul(shape.id_stride)?)?;
+            bytes_per_node =
+                bytes_per_node.checked_add(count.checked_mul(shape.bytes_per_node)?)?;
+            depth = depth.max(shape.depth + 1);
+            shape_traits.push((*label, count, shape.clone()));
+        }
+        if id_stride > MAX_ID_STRIDE || depth > MAX_SCHEMA_DEPTH {
+            return None;
+        }
+        Some(Rc::new(Shape {
+            def: node.def,
+            payload_size,
+            traits: shape_traits,
+            id_stride,
+            bytes_per_node,
+            depth,
+        }))
+    }
+
+    /// Appends the [UniformChunk] data for this subtree.
+    fn write_data(&self, data: &mut Vec<u8>) {
+        if let Some(payload) = &self.node.payload {
+            data.extend(payload.iter());
+        }
+        for (_, children) in self.traits.iter() {
+            for child in children {
+                child.write_data(data);
+            }
+        }
+    }
+
+    /// Should this run of subtrees be stored as a [UniformChunk] instead of [IndirectChunk]s.
+    fn use_uniform(run: &[Analyzed]) -> bool {
+        run.len() as IdBase * run[0].size > 1
+    }
+}
+
+/// Converts `tree` into chunks, with `first_id` as the id of its root.
+/// The tree uses the ids from `first_id` to `first_id + tree.size`.
+pub fn chunk(
+    tree: &Analyzed,
+    first_id: NodeId,
+    shapes: &mut ShapeLibrary,
+) -> Vec<(ChunkId, enum_chunk::Chunk)> {
+    let mut chunks = vec![];
+    emit_run(std::slice::from_ref(tree), first_id, shapes, &mut chunks);
+    chunks
+}
+
+/// Emits a single chunk for `run` (or an [IndirectChunk] and its descendants if `run` is a single non-uniform node).
+/// Returns the number of ids used.
+fn emit_run(
+    run: &[Analyzed],
+    first_id: NodeId,
+    shapes: &mut ShapeLibrary,
+    chunks: &mut Vec<(ChunkId, enum_chunk::Chunk)>,
+) -> IdBase {
+    if let Some(shape) = &run[0].shape {
+        if Analyzed::use_uniform(run) {
+            let mut data = Vec::with_capacity(run.len() * shape.bytes_per_node as usize);
+            for node in run {
+                node.write_data(&mut data);
+            }
+            chunks.push((
+                ChunkId(first_id),
+                UniformChunk {
+                    data: Box::new(data.into()),
+                    schema: shapes.intern(shape.schema(run.len() as u32)),
+                }
+                .into(),
+            ));
+            return run.len() as IdBase * shape.id_stride as IdBase;
+        }
+    }
+
+    debug_assert_eq!(run.len(), 1);
+    let tree = &run[0];
+    let mut traits = ImHashMap::default();
+    let mut next_id = NodeId(first_id.0 + 1);
+    for (label, children) in tree.traits.iter() {
+        let mut ids = vec![];
+        let mut i = 0;
+        while i < children.len() {
+            let mut end = i + 1;
+            if let Some(shape) = &children[i].shape {
+                let max_end = i.saturating_add(shape.max_run()).min(children.len());
+                while end < max_end && children[end].shape.as_ref() == Some(shape) {
+                    end += 1;
+                }
+                if !Analyzed::use_uniform(&children[i..end]) {
+                    end = i + 1;
+                }
+            }
+            ids.push(ChunkId(next_id));
+            next_id = NodeId(next_id.0 + emit_run(&children[i..end], next_id, shapes, chunks));
+            i = end;
+        }
+        traits.insert(*label, ids);
+    }
+    chunks.push((
+        ChunkId(first_id),
+        IndirectChunk {
+            def: tree.node.def,
+            payload: tree.node.payload.clone().map(Box::new),
+            payload_layout: None,
+            traits,
+        }
+        .into(),
+    ));
+    tree.size
+}
+
+/// Copies the subtree under `node` (keeping its ids),
+/// adding the [PayloadLayout] of each [Def] which has one to `layouts`.
+pub fn copy_tree<N: Node<N> + HasId>(
+    node: &N,
+    layouts: &mut HashMap<Def, PayloadLayout>,
+) -> BasicNode {
+    if let Some(layout) = node.get_payload_layout() {
+        layouts
+            .entry(node.get_def())
+            .or_insert_with(|| layout.clone());
+    }
+    BasicNode {
+        id: node.get_id(),
+        def: node.get_def(),
+        payload: node.get_payload().map(|p| p.into_iter().cloned().collect()),
+        traits: node
+            .get_traits()
+            .map(|label| {
+                let children = node
+                    .get_trait(label)
+                    .map(|child| copy_tree(&child, layouts))
+                    .collect();
+                (label, children)
+            })
+            .collect(),
+    }
+}
+
+/// Controls which runs of sibling subtrees [rechunk] stores as [UniformChunk]s.
+#[derive(Clone, Debug)]
+pub struct RechunkPolicy {
+    /// Runs with fewer nodes (including descendants) are stored as [IndirectChunk]s.
+    pub min_nodes: IdBase,
+    /// Runs with more nodes are split into several [UniformChunk]s.
+    pub max_nodes: IdBase,
+}
+
+impl Default for RechunkPolicy {
+    /// Same choices as [chunk], with no limit on chunk size (beyond what [UniformChunk] supports).
+    fn default() -> Self {
+        RechunkPolicy {
+            min_nodes: 2,
+            max_nodes: u32::MAX as IdBase,
+        }
+    }
+}
+
+/// Converts `tree` into chunks, keeping its ids.
+/// Only runs of sibling subtrees with sequential ids (the layout [chunk] allocates) can be stored in [UniformChunk]s.
+/// The root of `tree` is the first node of its chunk.
+///
+/// `layouts` gives the [PayloadLayout] for payloads of each [Def] (see [copy_tree]).
+pub fn rechunk(
+    tree: &Analyzed,
+    policy: &RechunkPolicy,
+    layouts: &HashMap<Def, PayloadLayout>,
+    shapes: &mut ShapeLibrary,
+) -> Vec<(ChunkId, enum_chunk::Chunk)> {
+    let mut rechunker = Rechunker {
+        policy,
+        layouts,
+        shapes,
+        chunks: vec![],
+    };
+    rechunker.siblings(std::slice::from_ref(tree));
+    rechunker.chunks
+}
+
+struct Rechunker<'a> {
+    policy: &'a RechunkPolicy,
+    layouts: &'a HashMap<Def, PayloadLayout>,
+    shapes: &'a mut ShapeLibrary,
+    chunks: Vec<(ChunkId, enum_chunk::Chunk)>,
+}
+
+impl Rechunker<'_> {
+    /// Emits chunks for a sequence of siblings, returning the ids of the top level ones.
+    fn siblings(&mut self, nodes: &[Analyzed]) -> Vec<ChunkId> {
+        let mut ids = vec![];
+        let mut i = 0;
+        while i < nodes.len() {
+            let id = ChunkId(nodes[i].node.id);
+            ids.push(id);
+            let run = self.run_length(&nodes[i..]);
+            if run == 0 {
+                self.indirect(&nodes[i]);
+                i += 1;
+                continue;
+            }
+
+            let run = &nodes[i..i + run];
+            let shape = run[0].shape.as_ref().unwrap();
+            let mut data = Vec::with_capacity(run.len() * shape.bytes_per_node as usize);
+            for node in run {
+                node.write_data(&mut data);
+            }
+            let mut schema = shape.schema(run.len() as u32);
+            self.add_layouts(&mut schema);
+            self.chunks.push((
+                id,
+                UniformChunk {
+                    data: Box::new(data.into()),
+                    schema: self.shapes.intern(schema),
+                }
+                .into(),
+            ));
+            i += run.len();
+        }
+        ids
+    }
+
+    /// Number of subtrees at the start of `nodes` to store in one [UniformChunk] (0 for none).
+    fn run_length(&self, nodes: &[Analyzed]) -> usize {
+        let first = &nodes[0];
+        let shape = match &first.shape {
+            Some(shape) if first.sequential => shape,
+            _ => return 0,
+        };
+        let max_len = (self.policy.max_nodes / first.size)
+            .min(shape.max_run() as IdBase)
+            .min(nodes.len() as IdBase) as usize;
+        let mut len = 1;
+        while len < max_len {
+            let (prev, next) = (&nodes[len - 1], &nodes[len]);
+            if !next.sequential
+                || next.shape.as_ref() != Some(shape)
+                || prev.node.id.0.checked_add(prev.size) != Some(next.node.id.0)
+            {
+                break;
+            }
+            len += 1;
+        }
+        if len > max_len || (len as IdBase * first.size) < self.policy.min_nodes.max(1) {
+            0
+        } else {
+            len
+        }
+    }
+
+    fn indirect(&mut self, tree: &Analyzed) {
+        let traits = tree
+            .traits
+            .iter()
+            .map(|(label, children)| (*label, self.siblings(children)))
+            .collect();
+        self.chunks.push((
+            ChunkId(tree.node.id),
+            IndirectChunk {
+                def: tree.node.def,
+                payload: tree.node.payload.clone().map(Box::new),
+                payload_layout: None,
+                traits,
+            }
+            .with_layout(self.layouts.get(&tree.node.def))
+            .into(),
+        ));
+    }
+
+    fn add_layouts(&self, schema: &mut ChunkSchema) {
+        if let (Some(layout), Some(size)) = (self.layouts.get(&schema.def), schema.payload_size) {
+            if layout.fits(size as usize) {
+                schema.payload_layout = Some(layout.clone());
+            }
+        }
+        for offset_schema in schema.traits.values_mut() {
+            self.add_layouts(Shared::make_mut(&mut offset_schema.schema));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        indirect_nav::Forest,
+        node_id::HasId,
+        test_stuff::{check_parents, walk_all},
+        tree::{NodeData, NodeNav},
+    };
+    use std::collections::HashMap;
+
+    fn node(def: u128, payload: &[u8], traits: Vec<(Label, Vec<BasicNode>)>) -> BasicNode {
+        BasicNode {
+            id: NodeId(0),
+            def: Def(def),
+            payload: Some(payload.iter().cloned().collect()),
+            traits: traits.into_iter().collect(),
+        }
+    }
+
+    fn insert(tree: &BasicNode, first_id: NodeId) -> (Forest, Vec<u128>) {
+        let mut forest = Forest::new();
+        let mut ids = vec![];
+        for (id, chunk) in chunk(&Analyzed::new(tree), first_id, &mut ShapeLibrary::default()) {
+            ids.push(id.0 .0);
+            forest.insert(id, chunk);
+        }
+        ids.sort();
+        (forest, ids)
+    }
+
+    #[test]
+    fn byte_sequence() {
+        let bytes = (0..1000).map(|i| node(2, &[i as u8], vec![])).collect();
+        let tree = node(1, &[], vec![(Label(1), bytes)]);
+        let (forest, ids) = insert(&tree, NodeId(10));
+        // Entire tree has a uniform shape, so it ends up in a single chunk.
+        assert_eq!(ids, vec![10]);
+
+        let nav = forest.nav_from(NodeId(10)).unwrap();
+        assert_eq!(walk_all(nav.clone()), 1001);
+        let child = nav.get_trait(Label(1)).nth(500).unwrap();
+        assert_eq!(child.get_id(), NodeId(511));
+        assert_eq!(
+            *child.get_payload().unwrap().get(0).unwrap(),
+            (500 % 256) as u8
+        );
+        check_parents(nav);
+    }
+
+    #[test]
+    fn mixed_runs() {
+        let color = |i: u8| {
+            node(
+                4,
+                &[],
+                vec![
+                    (
+                        Label(5),
+                        vec![
+                            node(5, &[i], vec![]),
+                            node(5, &[i + 1], vec![]),
+                            node(5, &[i + 2], vec![]),
+                        ],
+                    ),
+                    (Label(6), vec![node(5, &[i + 3], vec![])]),
+                ],
+            )
+        };
+        let children = vec![
+            node(2, &[1], vec![]),
+            node(2, &[2], vec![]),
+            node(3, &[3], vec![]),
+            color(10),
+            color(20),
+            color(30),
+        ];
+        let tree = node(1, &[], vec![(Label(1), children)]);
+        let (forest, ids) = insert(&tree, NodeId(0));
+        assert_eq!(ids, vec![0, 1, 3, 4]);
+        assert!(matches!(
+            forest.find_nodes(ChunkId(NodeId(4))),
+            Some(enum_chunk::Chunk::Uniform(_))
+        ));
+
+        let nav = forest.nav_from(NodeId(0)).unwrap();
+        assert_eq!(walk_all(nav.clone()), 1 + 3 + 3 * 5);
+        check_parents(nav);
+
+        // Second channel under Label(5) of the second color.
+        let channel = forest.find_node(NodeId(9 + 2)).unwrap();
+        assert_eq!(*channel.get_payload().unwrap().get(0).unwrap(), 21);
+        let channel = forest.find_node(NodeId(9 + 4)).unwrap();
+        assert_eq!(*channel.get_payload().unwrap().get(0).unwrap(), 23);
+    }
+
+    #[test]
+    fn max_run() {
+        let shape = |id_stride: u32, bytes_per_node: u32| Shape {
+            def: Def(1),
+            payload_size: None,
+            traits: vec![],
+            id_stride,
+            bytes_per_node,
+            depth: 1,
+        };
+        assert_eq!(shape(1, 0).max_run(), u32::MAX as usize);
+        assert_eq!(shape(1 << 20, 0).max_run(), (1 << 12) - 1);
+        assert_eq!(shape(1 << 20, 1 << 24).max_run(), (1 << 8) - 1);
+        assert_eq!(shape(u32::MAX, u32::MAX).max_run(), 1);
+    }
+
+    #[test]
+    fn uniform_root() {
+        let tree = node(1, &[7], vec![(Label(1), vec![node(2, &[8], vec![])])]);
+        let (forest, ids) = insert(&tree, NodeId(0));
+        assert_eq!(ids, vec![0]);
+        let traits: HashMap<Label, usize> = forest
+            .nav_from(NodeId(0))
+            .map(|n| {
+                n.get_traits()
+                    .map(|l| (l, n.get_trait(l).count()))
+                    .collect()
+            })
+            .unwrap();
+        assert_eq!(traits, [(Label(1), 1)].into_iter().collect());
+    }
+
+    #[test]
+    fn rechunk_sequential_runs() {
+        let leaf = |id: u128, def: u128| BasicNode {
+            id: NodeId(id),
+            ..node(def, &[id as u8], vec![])
+        };
+        let children = vec![leaf(1, 2), leaf(2, 2), leaf(5, 2), leaf(6, 2), leaf(7, 3)];
+        let tree = BasicNode {
+            id: NodeId(0),
+            ..node(1, &[], vec![(Label(1), children)])
+        };
+        let analyzed = Analyzed::new(&tree);
+        assert!(!analyzed.sequential);
+        let chunks = rechunk(
+            &analyzed,
+            &RechunkPolicy::default(),
+            &HashMap::new(),
+            &mut ShapeLibrary::default(),
+        );
+        let mut forest = Forest::new();
+        for (id, chunk) in chunks {
+            forest.insert(id, chunk);
+        }
+        let chunk_ids: Vec<u128> = forest.map_keys().map(|id| id.0 .0).collect();
+        assert_eq!(chunk_ids, vec![0, 1, 5, 7]);
+        let nav = forest.nav_from(NodeId(0)).unwrap();
+        let ids: Vec<u128> = nav.get_trait(Label(1)).map(|c| c.get_id().0).collect();
+        assert_eq!(ids, vec![1, 2, 5, 6, 7]);
+        check_parents(nav);
+    }
+}
@@ -0,0 +1,409 @@
+//! Node level differences between two versions of a forest.
+//!
+//! Only chunks which differ between the versions are inspected (see [Forest::changed_chunks]), and only their changed parts:
+//! - For an [IndirectChunk], the children of each trait after those it starts and ends with in both versions.
+//! - For a [UniformChunk] of the same shape in both, the top level nodes whose bytes differ.
+//!   Data shared between the versions (copy on write) is skipped without being compared.
+//!
+//! So diffing a forest against an edited clone of itself looks up nodes proportional to the size of the edit,
+//! after a fast scan of the edited traits and chunk data. Editing one byte of a large chunk reports just the node owning it.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::{
+    chunk::{Chunk, ChunkId},
+    indirect::enum_chunk,
+    indirect_nav::Forest,
+    indirect_node::IndirectChunk,
+    nav::Resolver,
+    node_id::{HasId, NodeId},
+    tree::{Label, NodeData, NodeNav},
+    uniform_chunk::UniformChunk,
+};
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Change {
+    Inserted(NodeId),
+    Removed(NodeId),
+    DefChanged(NodeId),
+    PayloadChanged(NodeId),
+    /// The node's parent, or the label it is under, changed.
+    Moved(NodeId),
+    /// The children of the trait which are in both versions are in a different order.
+    TraitReordered {
+        parent: NodeId,
+        label: Label,
+    },
+}
+
+/// Changed range of a trait of an indirect chunk: its label, and the ids of the children in the range in each version.
+type TraitRange = (Label, Vec<NodeId>, Vec<NodeId>);
+
+/// Changes from `old` to `new`, ordered by node id.
+pub fn diff(old: &Forest, new: &Forest) -> Vec<Change> {
+    let changed: HashSet<ChunkId> = old.changed_chunks(new).collect();
+    // Changed chunks in each trait (in either version), which can't be skipped as unchanged children.
+    let mut changed_children: HashMap<(ChunkId, Label), HashSet<ChunkId>> = HashMap::new();
+    for id in changed.iter() {
+        for forest in [old, new] {
+            if let Some(parent) = forest.get_parent_data().get(id) {
+                changed_children
+                    .entry((parent.node, parent.label))
+                    .or_default()
+                    .insert(*id);
+            }
+        }
+    }
+
+    let mut ids = BTreeSet::new();
+    // Nodes whose traits are compared as a whole for reordering.
+    let mut reorder_all = HashSet::new();
+    let mut trait_ranges: HashMap<NodeId, Vec<TraitRange>> = HashMap::new();
+    for chunk_id in changed.iter() {
+        match (old.find_nodes(*chunk_id), new.find_nodes(*chunk_id)) {
+            (
+                Some(enum_chunk::Chunk::Indirect(before)),
+                Some(enum_chunk::Chunk::Indirect(after)),
+            ) => {
+                ids.insert(chunk_id.0);
+                let labels: BTreeSet<Label> =
+                    before.get_traits().chain(after.get_traits()).collect();
+                let ranges = trait_ranges.entry(chunk_id.0).or_default();
+                for label in labels {
+                    let empty = HashSet::new();
+                    let skip = changed_children.get(&(*chunk_id, label)).unwrap_or(&empty);
+                    let (old_range, new_range) = changed_range(
+                        trait_children(before, label),
+                        trait_children(after, label),
+                        skip,
+                    );
+                    let old_ids = expand(old, old_range);
+                    let new_ids = expand(new, new_range);
+                    ids.extend(old_ids.iter().chain(new_ids.iter()));
+                    ranges.push((label, old_ids, new_ids));
+                }
+            }
+            // Nodes of the same shape have their children in the same order, so only their content can change.
+            (Some(enum_chunk::Chunk::Uniform(before)), Some(enum_chunk::Chunk::Uniform(after)))
+                if before.schema.same_shape(&after.schema) =>
+            {
+                for index in changed_top_level(before, after) {
+                    for chunk in [before, after] {
+                        let top = chunk.top_level_nodes(chunk_id.0).nth(index);
+                        let nodes = subtree_nodes(top.map(enum_chunk::Node::Uniform));
+                        ids.extend(nodes.iter().map(|node| node.get_id()));
+                    }
+                }
+            }
+            (before, after) => {
+                for (forest, chunk) in [(old, before), (new, after)] {
+                    for node in chunk.map_or(vec![], |chunk| chunk_nodes(chunk, chunk_id.0)) {
+                        ids.insert(node.get_id());
+                        reorder_all.insert(node.get_id());
+                        // Children can be moved by changing just their parent's chunk.
+                        for label in node.get_traits() {
+                            ids.extend(children(forest, &node, label));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    let mut changes = vec![];
+    for id in ids {
+        let (before, after) = match (old.find_node(id), new.find_node(id)) {
+            (Some(before), Some(after)) => (before, after),
+            (None, Some(_)) => {
+                changes.push(Change::Inserted(id));
+                continue;
+            }
+            (Some(_), None) => {
+                changes.push(Change::Removed(id));
+                continue;
+            }
+            (None, None) => continue,
+        };
+
+        if before.get_def() != after.get_def() {
+            changes.push(Change::DefChanged(id));
+        }
+        if payload(&before) != payload(&after) {
+            changes.push(Change::PayloadChanged(id));
+        }
+        if parent(old, &before) != parent(new, &after) {
+            changes.push(Change::Moved(id));
+        }
+        if reorder_all.contains(&id) {
+            let labels: BTreeSet<Label> = before.get_traits().chain(after.get_traits()).collect();
+            for label in labels {
+                if reordered(
+                    &children(old, &before, label),
+                    &children(new, &after, label),
+                ) {
+                    changes.push(Change::TraitReordered { parent: id, label });
+                }
+            }
+        } else if let Some(ranges) = trait_ranges.get(&id) {
+            for (label, old_children, new_children) in ranges {
+                if reordered(old_children, new_children) {
+                    changes.push(Change::TraitReordered {
+                        parent: id,
+                        label: *label,
+                    });
+                }
+            }
+        }
+    }
+    changes
+}
+
+/// True if the children in both `old_children` and `new_children` are in a different order in each.
+fn reordered(old_children: &[NodeId], new_children: &[NodeId]) -> bool {
+    let old_set: HashSet<&NodeId> = old_children.iter().collect();
+    let new_set: HashSet<&NodeId> = new_children.iter().collect();
+    !old_children
+        .iter()
+        .filter(|c| new_set.contains(c))
+        .eq(new_children.iter().filter(|c| old_set.contains(c)))
+}
+
+fn trait_children(chunk: &IndirectChunk, label: Label) -> &[ChunkId] {
+    chunk
+        .traits
+        .get(&label)
+        .map_or(&[], |children| children.as_slice())
+}
+
+/// `old` and `new` without the children they start and end with which are the same chunk in both (and not in `changed`).
+///
+/// Unchanged children keep their position relative to each other, so only the remaining ones can be inserted, removed or reordered.
+fn changed_range<'a>(
+    old: &'a [ChunkId],
+    new: &'a [ChunkId],
+    changed: &HashSet<ChunkId>,
+) -> (&'a [ChunkId], &'a [ChunkId]) {
+    let same =
+        |(a, b): &(&ChunkId, &ChunkId)| a == b && (changed.is_empty() || !changed.contains(a));
+    let start = old.iter().zip(new).take_while(same).count();
+    let (old, new) = (&old[start..], &new[start..]);
+    let end = old
+        .iter()
+        .rev()
+        .zip(new.iter().rev())
+        .take_while(same)
+        .count();
+    (&old[..old.len() - end], &new[..new.len() - end])
+}
+
+/// Ids of the top level nodes of `chunks`.
+fn expand(forest: &Forest, chunks: &[ChunkId]) -> Vec<NodeId> {
+    chunks
+        .iter()
+        .flat_map(|id| forest.expand(enum_chunk::Child::Indirect(*id)))
+        .map(|node| node.get_id())
+        .collect()
+}
+
+/// Indices of the top level nodes which differ between `before` and `after`, two versions of a chunk with the same shape.
+/// Parts of their data which are shared (copy on write) are skipped without comparing them.
+fn changed_top_level(before: &UniformChunk, after: &UniformChunk) -> Vec<usize> {
+    let bytes_per_node = before.schema.schema.bytes_per_node as usize;
+    let common = before.get_count().min(after.get_count());
+    let len = common * bytes_per_node;
+    let (mut old_data, mut new_data) = (before.data.focus(), after.data.focus());
+    let mut changed: Vec<usize> = vec![];
+    let mut start = 0;
+    while start < len {
+        let (old_range, old_bytes) = old_data.chunk_at(start);
+        let (new_range, new_bytes) = new_data.chunk_at(start);
+        let end = old_range.end.min(new_range.end).min(len);
+        let old_bytes = &old_bytes[start - old_range.start..end - old_range.start];
+        let new_bytes = &new_bytes[start - new_range.start..end - new_range.start];
+        if !std::ptr::eq(old_bytes, new_bytes) {
+            for (i, (a, b)) in old_bytes.iter().zip(new_bytes).enumerate() {
+                let node = (start + i) / bytes_per_node;
+                if a != b && changed.last() != Some(&node) {
+                    changed.push(node);
+                }
+            }
+        }
+        start = end;
+    }
+    changed.extend(common..before.get_count().max(after.get_count()));
+    changed
+}
+
+/// All nodes stored in `chunk`, including nodes nested within its top level nodes.
+fn chunk_nodes(chunk: &enum_chunk::Chunk, id: NodeId) -> Vec<enum_chunk::Node<'_>> {
+    subtree_nodes(chunk.top_level_nodes(id))
+}
+
+/// `top` and the nodes nested within them in their chunk.
+fn subtree_nodes<'a>(
+    top: impl IntoIterator<Item = enum_chunk::Node<'a>>,
+) -> Vec<enum_chunk::Node<'a>> {
+    let mut pending: Vec<_> = top.into_iter().collect();
+    let mut nodes = vec![];
+    while let Some(node) = pending.pop() {
+        if let enum_chunk::Node::Uniform(n) = &node {
+            for label in n.get_traits() {
+                pending.extend(n.get_trait(label).map(enum_chunk::Node::Uniform));
+            }
+        }
+        nodes.push(node);
+    }
+    nodes
+}
+
+fn children<'a>(forest: &'a Forest, node: &enum_chunk::Node<'a>, label: Label) -> Vec<NodeId> {
+    node.get_trait(label)
+        .flat_map(|child| forest.expand(child))
+        .map(|child| child.get_id())
+        .collect()
+}
+
+fn parent<'a>(forest: &'a Forest, node: &enum_chunk::Node<'a>) -> Option<(NodeId, Label)> {
+    forest
+        .try_get_parent(node)
+        .ok()
+        .flatten()
+        .map(|p| (p.node.get_id(), p.label))
+}
+
+fn payload(node: &enum_chunk::Node<'_>) -> Option<Vec<u8>> {
+    node.get_payload().map(|p| p.into_iter().cloned().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        example_node::BasicNode,
+        test_stuff::{uniform_tree, wide_tree, CHANNELS, COLORS},
+        tree::Def,
+    };
+
+    fn forest() -> (crate::Forest, NodeId) {
+        let (tree, root) = uniform_tree(3);
+        (crate::Forest::from(tree), root)
+    }
+
+    #[test]
+    fn unchanged() {
+        let (forest, _) = forest();
+        assert!(crate::Forest::diff(&forest, &forest.clone()).is_empty());
+    }
+
+    #[test]
+    fn payload_in_chunk() {
+        let (old, _) = forest();
+        let mut new = old.clone();
+        new.set_value(NodeId(7), &[42]).unwrap();
+        assert_eq!(
+            crate::Forest::diff(&old, &new),
+            vec![Change::PayloadChanged(NodeId(7))]
+        );
+    }
+
+    #[test]
+    fn moves() {
+        let (old, root) = forest();
+        let mut new = old.clone();
+        // Splits the chunk, but only the moved node changes.
+        new.move_subtree(NodeId(8), root, COLORS, 0).unwrap();
+        assert_eq!(
+            crate::Forest::diff(&old, &new),
+            vec![Change::Moved(NodeId(8))]
+        );
+
+        let mut new = old.clone();
+        new.move_subtree(NodeId(1), root, COLORS, 2).unwrap();
+        assert_eq!(
+            crate::Forest::diff(&old, &new),
+            vec![Change::TraitReordered {
+                parent: root,
+                label: COLORS
+            }]
+        );
+    }
+
+    #[test]
+    fn insert_and_remove() {
+        let (old, root) = forest();
+        let mut new = old.clone();
+        let leaf = BasicNode {
+            id: NodeId(0),
+            def: Def(100),
+            payload: None,
+            traits: Default::default(),
+        };
+        let inserted = new.insert_child(root, COLORS, 0, leaf).unwrap();
+        new.delete_subtree(NodeId(11)).unwrap();
+
+        let mut expected: Vec<Change> = (11..16).map(|i| Change::Removed(NodeId(i))).collect();
+        expected.push(Change::Inserted(inserted));
+        expected.sort();
+        let mut changes = crate::Forest::diff(&old, &new);
+        changes.sort();
+        assert_eq!(changes, expected);
+    }
+
+    #[test]
+    fn large_trait() {
+        let (tree, root) = wide_tree(1000);
+        let old = crate::Forest::from(tree);
+        let leaf = BasicNode {
+            id: NodeId(0),
+            def: Def(100),
+            payload: None,
+            traits: Default::default(),
+        };
+
+        let mut new = old.clone();
+        let appended = new.insert_child(root, COLORS, 1000, leaf).unwrap();
+        new.delete_subtree(NodeId(500)).unwrap();
+        assert_eq!(
+            crate::Forest::diff(&old, &new),
+            vec![Change::Removed(NodeId(500)), Change::Inserted(appended)]
+        );
+
+        let mut new = old.clone();
+        new.move_subtree(NodeId(500), root, COLORS, 0).unwrap();
+        assert_eq!(
+            crate::Forest::diff(&old, &new),
+            vec![Change::TraitReordered {
+                parent: root,
+                label: COLORS
+            }]
+        );
+    }
+
+    #[test]
+    fn large_chunk() {
+        let (tree, _) = uniform_tree(1000);
+        let old = crate::Forest::from(tree);
+        let mut new = old.clone();
+        new.set_value(NodeId(2), &[42]).unwrap();
+        new.set_value(NodeId(4000), &[42]).unwrap();
+        assert_eq!(
+            crate::Forest::diff(&old, &new),
+            vec![
+                Change::PayloadChanged(NodeId(2)),
+                Change::PayloadChanged(NodeId(4000))
+            ]
+        );
+
+        // Resizing a payload splits the node (color 799) and its channels out of the chunk.
+        let mut new = old.clone();
+        new.set_value(NodeId(3996), &[1, 2]).unwrap();
+        assert_eq!(
+            new.get_parent(NodeId(4000)).unwrap().unwrap().label,
+            CHANNELS[3]
+        );
+        assert_eq!(
+            crate::Forest::diff(&old, &new),
+            vec![Change::PayloadChanged(NodeId(3996))]
+        );
+    }
+}
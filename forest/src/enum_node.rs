@@ -56,11 +56,16 @@ macro_rules! fromMembers {
                         Node::$name(n) => crate::tree::NodeData::get_def(n),
                     )*}
                 }
-                fn get_payload(&self) -> Option<crate::util::ImSlice>{
+                fn get_payload(&self) -> Option<crate::util::ImSlice<'_>>{
                     match self {$(
                         Node::$name(n) => crate::tree::NodeData::get_payload(n),
                     )*}
                 }
+                fn get_payload_layout(&self) -> Option<&crate::payload::PayloadLayout> {
+                    match self {$(
+                        Node::$name(n) => crate::tree::NodeData::get_payload_layout(n),
+                    )*}
+                }
             }
 
             impl<'a> crate::node_id::HasId for Node<'a> {
@@ -111,6 +116,13 @@ macro_rules! fromMembers {
                         Expander::Indirect(ref mut c) => c.next().map(Node::Indirect),
                     }
                 }
+
+                fn nth(&mut self, n: usize) -> Option<Self::Item> {
+                    match self {
+                        Expander::Uniform(ref mut c) => c.nth(n).map(Node::Uniform),
+                        Expander::Indirect(ref mut c) => c.nth(n).map(Node::Indirect),
+                    }
+                }
             }
 
             impl<'a> crate::chunk::Chunk for &'a Chunk {
@@ -128,6 +140,12 @@ macro_rules! fromMembers {
                         Chunk::$name(c) => c.top_level_nodes(id).into(),
                     )*}
                 }
+
+                fn id_extent(&self) -> crate::node_id::IdOffset {
+                    match self {$(
+                        Chunk::$name(c) => c.id_extent(),
+                    )*}
+                }
             }
 
             /// For parent info: Allow viewing the tree of chunks as Node.
@@ -0,0 +1,75 @@
+//! Errors reported by [crate::Forest] operations.
+
+use std::fmt;
+
+use crate::{chunk::ChunkId, node_id::NodeId, payload::PayloadError, tree::Label};
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ForestError {
+    /// No node with this id is in the forest.
+    UnknownId(NodeId),
+    /// A chunk refers to a child chunk which is not in the forest.
+    DanglingChild { parent: ChunkId, child: ChunkId },
+    /// The ids used by the two chunks overlap.
+    OverlappingChunks(ChunkId, ChunkId),
+    /// The chunk is a child of more than one chunk.
+    MultipleParents(ChunkId),
+    /// The chunk could not be loaded from its store.
+    LoadFailed(ChunkId),
+    /// The chunk does not have the content its schema (or its use) requires.
+    SchemaMismatch(ChunkId),
+    /// The node can not be deleted on its own since it has children.
+    HasChildren(NodeId),
+    /// The node would be (or is) its own ancestor.
+    Cycle(NodeId),
+    /// The chunk is not under the root of the subtree it was given with.
+    Unreachable(ChunkId),
+    /// The trait has no child at this index.
+    IndexOutOfRange {
+        parent: NodeId,
+        label: Label,
+        index: usize,
+    },
+    /// The node's payload could not be read or written as requested.
+    InvalidPayload(NodeId, PayloadError),
+}
+
+impl fmt::Display for ForestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ForestError::UnknownId(id) => write!(f, "node {:?} is not in the forest", id),
+            ForestError::DanglingChild { parent, child } => write!(
+                f,
+                "chunk {:?} has child {:?} which is not in the forest",
+                parent, child
+            ),
+            ForestError::OverlappingChunks(a, b) => {
+                write!(f, "chunks {:?} and {:?} overlap", a, b)
+            }
+            ForestError::MultipleParents(id) => {
+                write!(f, "chunk {:?} has more than one parent", id)
+            }
+            ForestError::LoadFailed(id) => write!(f, "chunk {:?} failed to load", id),
+            ForestError::SchemaMismatch(id) => {
+                write!(f, "chunk {:?} does not match its schema", id)
+            }
+            ForestError::HasChildren(id) => write!(f, "node {:?} has children", id),
+            ForestError::Cycle(id) => write!(f, "node {:?} would be its own ancestor", id),
+            ForestError::Unreachable(id) => {
+                write!(f, "chunk {:?} is not under the root of its subtree", id)
+            }
+            ForestError::IndexOutOfRange {
+                parent,
+                label,
+                index,
+            } => write!(
+                f,
+                "trait {:?} of node {:?} has no child at index {}",
+                label, parent, index
+            ),
+            ForestError::InvalidPayload(id, error) => write!(f, "node {:?}: {}", id, error),
+        }
+    }
+}
+
+impl std::error::Error for ForestError {}
@@ -16,7 +16,7 @@ use crate::{
 pub struct BasicNode {
     pub id: NodeId,
     pub def: Def,
-    pub payload: Option<im_rc::Vector<u8>>,
+    pub payload: Option<im::Vector<u8>>,
     pub traits: HashMap<Label, Vec<BasicNode>>, // TODO: Use hash map from im_rc
 }
 
@@ -38,7 +38,7 @@ impl NodeData for BasicNode {
         self.def
     }
 
-    fn get_payload(&self) -> Option<ImSlice> {
+    fn get_payload(&self) -> Option<ImSlice<'_>> {
         self.payload.as_ref().map(|p| p.focus())
     }
 }
@@ -4,25 +4,53 @@
 //!
 //! This is used by [crate::indirect_nav] to store and lookup [crate::indirect_nav::EnumChunk]s.
 
-use std::cell::{Ref, RefCell};
+use std::collections::HashSet;
+
+use im::ordmap::DiffItem;
 
 use crate::{
-    chunk::{Chunk, ChunkId},
-    node_id::NodeId,
-    tree::{NodeNav, ParentInfo},
+    chunk::Chunk,
+    error::ForestError,
+    node_id::{IdOffset, NodeId},
+    sync::{Lock, Shared},
+    tree::{IdBase, Label, NodeNav, ParentInfo},
     util::ImHashMap,
 };
-use im_rc::ordmap::DiffItem;
+
+pub use crate::chunk::ChunkId;
 
 // Chunks added to forest must have non-overlapping ranges of Ids.
+// This is only checked by [Forest::try_insert] and [Forest::validate].
 #[derive(Clone, Default)]
 pub struct Forest<TChunk> {
     /// Up to date actual data of tree
-    map: im_rc::OrdMap<ChunkId, TChunk>,
-    /// Snapshot from last time parent_data was updated
-    old_map: RefCell<im_rc::OrdMap<ChunkId, TChunk>>,
-    /// Lazily updated parent data
-    parent_data: RefCell<ImHashMap<ChunkId, ParentInfo<ChunkId>>>,
+    map: im::OrdMap<ChunkId, TChunk>,
+    /// Parent of each chunk which is a child of another chunk.
+    /// Updated by every change to `map`.
+    parent_data: ImHashMap<ChunkId, ParentInfo<ChunkId>>,
+    /// Cache for [Forest::trait_offsets].
+    /// Entries for a chunk are dropped when it or one of its children changes.
+    trait_offsets: Lock<ImHashMap<ChunkId, TraitOffsets>>,
+    /// Cache for [Forest::child_index]: for each trait of a chunk, the index of each child in it.
+    /// Entries for a chunk are dropped when it changes.
+    child_indices: Lock<ImHashMap<ChunkId, ImHashMap<Label, ChildIndices>>>,
+}
+
+/// [Forest::trait_offsets] for each trait of a chunk.
+type TraitOffsets = ImHashMap<Label, Shared<[usize]>>;
+
+/// Index of each child in a trait.
+type ChildIndices = ImHashMap<ChunkId, usize>;
+
+/// Change made to the children of trait `label` of a chunk (see [Forest::edit]).
+pub struct ChildEdit {
+    pub label: Label,
+    /// Position in the trait of the first removed (and first added) child.
+    pub index: usize,
+    /// Children no longer in the trait.
+    pub removed: Vec<ChunkId>,
+    /// Children added to the trait.
+    pub added: Vec<ChunkId>,
 }
 
 impl<TChunk> Forest<TChunk>
@@ -32,12 +60,18 @@ where
 {
     pub fn new() -> Self {
         Forest {
-            map: im_rc::OrdMap::default(),
-            old_map: im_rc::OrdMap::default().into(),
-            parent_data: ImHashMap::default().into(),
+            map: im::OrdMap::default(),
+            parent_data: ImHashMap::default(),
+            trait_offsets: Lock::default(),
+            child_indices: Lock::default(),
         }
     }
 
+    /// Ids of all chunks, in order.
+    pub fn map_keys(&self) -> impl Iterator<Item = &ChunkId> {
+        self.map.keys()
+    }
+
     pub fn find_nodes(&self, id: ChunkId) -> Option<&TChunk> {
         self.map.get(&id)
     }
@@ -47,18 +81,137 @@ where
         self.map.get_prev(&ChunkId(id))
     }
 
-    pub fn find_nodes_mut(&mut self, id: ChunkId) -> Option<&mut TChunk> {
-        self.map.get_mut(&id)
+    /// Modifies the chunk `id` (if present) with `f`.
+    ///
+    /// Compares all the children of the chunk before and after `f` to update the parent index:
+    /// use [Forest::edit] for small edits to large traits.
+    pub fn update<R>(&mut self, id: ChunkId, f: impl FnOnce(&mut TChunk) -> R) -> Option<R> {
+        let chunk = self.map.get_mut(&id)?;
+        let old = chunk.clone();
+        let result = f(chunk);
+        update_parents(&mut self.parent_data, id, Some(&old), Some(chunk));
+        self.invalidate_offsets(id);
+        Some(result)
+    }
+
+    /// Modifies the chunk `id` (if present) with `f`, which returns its result along with the changes it made to the chunk's children.
+    /// `f` must not change the number of top level nodes in the chunk.
+    ///
+    /// Only the children in the returned [ChildEdit]s have their parent updated,
+    /// and cached [Forest::trait_offsets] for the edited traits are spliced using `count` (as passed to [Forest::trait_offsets]),
+    /// so unlike [Forest::update] this does not depend on the number of children.
+    pub fn edit<R>(
+        &mut self,
+        id: ChunkId,
+        f: impl FnOnce(&mut TChunk) -> (R, Vec<ChildEdit>),
+        count: impl Fn(&Self, ChunkId) -> Result<usize, ForestError>,
+    ) -> Option<R> {
+        let (result, edits) = f(self.map.get_mut(&id)?);
+        // Remove before adding, so children moved between traits are not removed.
+        for edit in edits.iter() {
+            for child in edit.removed.iter() {
+                remove_parent(&mut self.parent_data, *child, id);
+            }
+        }
+        for edit in edits.iter() {
+            for child in edit.added.iter() {
+                self.parent_data.insert(
+                    *child,
+                    ParentInfo {
+                        label: edit.label,
+                        node: id,
+                    },
+                );
+            }
+            self.splice_offsets(id, edit, &count);
+            self.splice_indices(id, edit);
+        }
+        Some(result)
     }
 
     /// Inserts a new chunk. May replace an existing one.
     pub fn insert(&mut self, id: ChunkId, value: TChunk) {
-        self.map.insert(id, value);
+        let old = self.map.insert(id, value);
+        update_parents(&mut self.parent_data, id, old.as_ref(), self.map.get(&id));
+        self.invalidate_offsets(id);
     }
 
-    /// Inserts a new chunk. May replace an existing one.
-    pub fn entry(&mut self, id: ChunkId) -> im_rc::ordmap::Entry<ChunkId, TChunk> {
-        self.map.entry(id)
+    /// Inserts a new chunk.
+    /// Fails if there is already a chunk with the same id, or the ids of the chunk overlap those of any other chunk.
+    /// Use [Forest::insert] to replace a chunk.
+    pub fn try_insert(&mut self, id: ChunkId, value: TChunk) -> Result<(), ForestError> {
+        if self.map.contains_key(&id) {
+            return Err(ForestError::OverlappingChunks(id, id));
+        }
+        if let Some(prev) = id.0 .0.checked_sub(1) {
+            if let Some((prev_id, prev)) = self.map.get_prev(&ChunkId(NodeId(prev))) {
+                if reaches(*prev_id, prev.id_extent(), id) {
+                    return Err(ForestError::OverlappingChunks(*prev_id, id));
+                }
+            }
+        }
+        if let Some(next) = id.0 .0.checked_add(1) {
+            if let Some((next_id, _)) = self.map.get_next(&ChunkId(NodeId(next))) {
+                if reaches(id, (&value).id_extent(), *next_id) {
+                    return Err(ForestError::OverlappingChunks(id, *next_id));
+                }
+            }
+        }
+        self.insert(id, value);
+        Ok(())
+    }
+
+    /// Checks the invariants of the forest:
+    /// chunks don't overlap, all children are present, no chunk has more than one parent,
+    /// and every chunk is under a root (so there are no cycles).
+    pub fn validate(&self) -> Result<(), ForestError> {
+        let mut prev: Option<(&ChunkId, &TChunk)> = None;
+        let mut parents: ImHashMap<ChunkId, ChunkId> = ImHashMap::default();
+        for (id, chunk) in self.map.iter() {
+            if let Some((prev_id, prev)) = prev {
+                if reaches(*prev_id, prev.id_extent(), *id) {
+                    return Err(ForestError::OverlappingChunks(*prev_id, *id));
+                }
+            }
+            prev = Some((id, chunk));
+
+            for label in chunk.get_traits() {
+                for child in chunk.get_trait(label) {
+                    if !self.map.contains_key(&child) {
+                        return Err(ForestError::DanglingChild { parent: *id, child });
+                    }
+                    if parents.insert(child, *id).is_some() {
+                        return Err(ForestError::MultipleParents(child));
+                    }
+                }
+            }
+        }
+
+        // Chunks already known to be under a root.
+        let mut rooted = HashSet::new();
+        for id in self.map.keys() {
+            let mut path = HashSet::new();
+            let mut current = *id;
+            while !rooted.contains(&current) {
+                if !path.insert(current) {
+                    return Err(ForestError::Cycle(current.0));
+                }
+                match parents.get(&current) {
+                    Some(parent) => current = *parent,
+                    None => break,
+                }
+            }
+            rooted.extend(path);
+        }
+        Ok(())
+    }
+
+    /// Removes a chunk, returning it if it was present.
+    pub fn remove(&mut self, id: ChunkId) -> Option<TChunk> {
+        let old = self.map.remove(&id);
+        update_parents(&mut self.parent_data, id, old.as_ref(), None);
+        self.invalidate_offsets(id);
+        old
     }
 
     pub fn find_node(&self, id: NodeId) -> Option<<&TChunk as Chunk>::View> {
@@ -68,57 +221,261 @@ where
         }
     }
 
-    pub fn get_parent_data(&self) -> Ref<ImHashMap<ChunkId, ParentInfo<ChunkId>>> {
+    /// Ids of the chunks which differ between `self` and `other`, in order.
+    /// Skips structure shared between them, so this is fast when one is an edited clone of the other.
+    pub fn changed_chunks<'a>(&'a self, other: &'a Self) -> impl Iterator<Item = ChunkId> + 'a {
+        self.map.diff(&other.map).map(|item| match item {
+            DiffItem::Add(id, _) | DiffItem::Remove(id, _) => *id,
+            DiffItem::Update { new: (id, _), .. } => *id,
+        })
+    }
+
+    /// Parent of each chunk which is a child of another chunk.
+    pub fn get_parent_data(&self) -> &ImHashMap<ChunkId, ParentInfo<ChunkId>> {
+        &self.parent_data
+    }
+
+    /// Start of each child of trait `label` of chunk `id` within the trait, followed by the length of the trait.
+    /// `count` gives the number of top level nodes in a child.
+    ///
+    /// Cached until the chunk or one of its children changes, so indexing into large traits can use a binary search.
+    pub fn trait_offsets(
+        &self,
+        id: ChunkId,
+        label: Label,
+        count: impl Fn(ChunkId) -> Result<usize, ForestError>,
+    ) -> Result<Shared<[usize]>, ForestError> {
+        if let Some(offsets) = self
+            .trait_offsets
+            .borrow()
+            .get(&id)
+            .and_then(|traits| traits.get(&label))
+        {
+            return Ok(offsets.clone());
+        }
+        let chunk = self.find_nodes(id).ok_or(ForestError::UnknownId(id.0))?;
+        let mut offsets = vec![0];
+        let mut total = 0;
+        for child in chunk.get_trait(label) {
+            total += count(child)?;
+            offsets.push(total);
+        }
+        let offsets: Shared<[usize]> = offsets.into();
+        self.trait_offsets
+            .borrow_mut()
+            .entry(id)
+            .or_default()
+            .insert(label, offsets.clone());
+        Ok(offsets)
+    }
+
+    /// Updates the cached [Forest::trait_offsets] for the trait changed by `edit`.
+    /// Drops them instead if an added child is not in the forest yet or its count fails.
+    fn splice_offsets(
+        &mut self,
+        id: ChunkId,
+        edit: &ChildEdit,
+        count: impl Fn(&Self, ChunkId) -> Result<usize, ForestError>,
+    ) {
+        let old = match self
+            .trait_offsets
+            .get_mut()
+            .get(&id)
+            .and_then(|traits| traits.get(&edit.label))
         {
-            let mut parent_data = self.parent_data.borrow_mut();
-            for d in self.old_map.borrow().diff(&self.map) {
-                match d {
-                    DiffItem::Add(k, v) => {
-                        for label in v.get_traits() {
-                            for child in v.get_trait(label) {
-                                parent_data.insert(child, ParentInfo { label, node: *k });
-                            }
-                        }
-                    }
-                    DiffItem::Update { old, new } => {
-                        // TODO: Performance: could support efficient diff on Nodes, and do a much more optimal update here.
-                        // For now, treat like remove then insert.
-                        // TODO: quality: either do above, or deduplicate this.
-                        let (_k, v) = old;
-                        for label in v.get_traits() {
-                            for child in v.get_trait(label) {
-                                parent_data.remove(&child);
-                            }
-                        }
-                        let (k, v) = new;
-                        for label in v.get_traits() {
-                            for child in v.get_trait(label) {
-                                parent_data.insert(child, ParentInfo { label, node: *k });
-                            }
-                        }
-                    }
-                    DiffItem::Remove(_k, v) => {
-                        for label in v.get_traits() {
-                            for child in v.get_trait(label) {
-                                parent_data.remove(&child);
-                            }
-                        }
-                    }
+            Some(offsets) => offsets.clone(),
+            None => return,
+        };
+        let end = edit.index + edit.removed.len();
+        let counts: Option<Vec<usize>> = edit
+            .added
+            .iter()
+            .map(|child| match self.map.contains_key(child) {
+                true => count(self, *child).ok(),
+                false => None,
+            })
+            .collect();
+        let traits = self.trait_offsets.get_mut().entry(id).or_default();
+        match counts {
+            Some(counts) if end < old.len() => {
+                let mut offsets = old[..=edit.index].to_vec();
+                let mut total = old[edit.index];
+                for count in counts {
+                    total += count;
+                    offsets.push(total);
                 }
+                offsets.extend(
+                    old[end + 1..]
+                        .iter()
+                        .map(|offset| offset - old[end] + total),
+                );
+                traits.insert(edit.label, offsets.into());
+            }
+            _ => {
+                traits.remove(&edit.label);
             }
+        }
+    }
 
-            self.old_map.replace(self.map.clone());
+    /// Updates the cached [Forest::child_index] for the trait changed by `edit` if it only changed the end of the trait,
+    /// otherwise drops them.
+    fn splice_indices(&mut self, id: ChunkId, edit: &ChildEdit) {
+        let traits = match self.child_indices.get_mut().get_mut(&id) {
+            Some(traits) => traits,
+            None => return,
+        };
+        let indices = match traits.get_mut(&edit.label) {
+            Some(indices) if edit.index + edit.removed.len() == indices.len() => indices,
+            _ => {
+                traits.remove(&edit.label);
+                return;
+            }
+        };
+        for child in edit.removed.iter() {
+            indices.remove(child);
+        }
+        for (i, child) in edit.added.iter().enumerate() {
+            indices.insert(*child, edit.index + i);
         }
-        self.parent_data.borrow()
     }
 
+    /// Index of the chunk `id` in its parent's trait, or None if it is a root.
+    ///
+    /// Cached until the parent changes, so repeated lookups in large traits do not search the trait.
+    pub fn child_index(&self, id: ChunkId) -> Result<Option<usize>, ForestError> {
+        let parent = match self.parent_data.get(&id) {
+            Some(parent) => parent,
+            None => return Ok(None),
+        };
+        let cached = self
+            .child_indices
+            .borrow()
+            .get(&parent.node)
+            .and_then(|traits| traits.get(&parent.label))
+            .map(|indices| indices.get(&id).cloned());
+        let index = match cached {
+            Some(index) => index,
+            None => {
+                let chunk = self
+                    .find_nodes(parent.node)
+                    .ok_or(ForestError::UnknownId(parent.node.0))?;
+                let indices: ChildIndices = chunk
+                    .get_trait(parent.label)
+                    .enumerate()
+                    .map(|(i, child)| (child, i))
+                    .collect();
+                let index = indices.get(&id).cloned();
+                self.child_indices
+                    .borrow_mut()
+                    .entry(parent.node)
+                    .or_default()
+                    .insert(parent.label, indices);
+                index
+            }
+        };
+        index.map(Some).ok_or(ForestError::DanglingChild {
+            parent: parent.node,
+            child: id,
+        })
+    }
+
+    /// Drops the cached [Forest::trait_offsets] and [Forest::child_index] which depend on the chunk `id`.
+    fn invalidate_offsets(&mut self, id: ChunkId) {
+        let offsets = self.trait_offsets.get_mut();
+        offsets.remove(&id);
+        if let Some(parent) = self.parent_data.get(&id) {
+            offsets.remove(&parent.node);
+        }
+        self.child_indices.get_mut().remove(&id);
+    }
+
+    /// Gets the parent of the chunk `id`, or None if it is a root.
     pub fn get_parent_from_chunk_id(
         &self,
         id: ChunkId,
-    ) -> Option<ParentInfo<<&TChunk as Chunk>::View>> {
-        self.get_parent_data().get(&id).map(|x| ParentInfo {
-            node: self.find_node(x.node.0).unwrap(),
-            label: x.label,
-        })
+    ) -> Result<Option<ParentInfo<<&TChunk as Chunk>::View>>, ForestError> {
+        let parent = match self.get_parent_data().get(&id) {
+            Some(parent) => parent.clone(),
+            None => return Ok(None),
+        };
+        let node = self
+            .find_node(parent.node.0)
+            .ok_or(ForestError::UnknownId(parent.node.0))?;
+        Ok(Some(ParentInfo {
+            node,
+            label: parent.label,
+        }))
+    }
+}
+
+/// Whether the ids of a chunk at `id` with `extent` reach `other` (which is after `id`).
+/// Extents running past the largest id reach everything after the chunk.
+fn reaches(id: ChunkId, extent: IdOffset, other: ChunkId) -> bool {
+    id.0 .0
+        .checked_add(extent.0 as IdBase)
+        .is_none_or(|end| end > other.0 .0)
+}
+
+/// Updates `parent_data` for the children of chunk `id` changing from those in `old` to those in `new`.
+///
+/// Only children outside the common prefix and suffix of each trait are updated,
+/// so small edits to large traits only do a few hash map operations.
+fn update_parents<TChunk>(
+    parent_data: &mut ImHashMap<ChunkId, ParentInfo<ChunkId>>,
+    id: ChunkId,
+    old: Option<&TChunk>,
+    new: Option<&TChunk>,
+) where
+    for<'a> &'a TChunk: Chunk,
+{
+    let children = |chunk: Option<&TChunk>, label: Label| -> Vec<ChunkId> {
+        chunk.map_or(vec![], |chunk| chunk.get_trait(label).collect())
+    };
+    let mut labels: Vec<Label> = old
+        .iter()
+        .chain(new.iter())
+        .flat_map(|chunk| chunk.get_traits())
+        .collect();
+    labels.sort();
+    labels.dedup();
+
+    let mut added = vec![];
+    for label in labels {
+        let (before, after) = (children(old, label), children(new, label));
+        let prefix = before
+            .iter()
+            .zip(after.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let suffix = before[prefix..]
+            .iter()
+            .rev()
+            .zip(after[prefix..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        for child in before[prefix..before.len() - suffix].iter() {
+            remove_parent(parent_data, *child, id);
+        }
+        added.extend(
+            after[prefix..after.len() - suffix]
+                .iter()
+                .map(|child| (*child, label)),
+        );
+    }
+    // Add after all removals, so children moved between traits are not removed.
+    for (child, label) in added {
+        parent_data.insert(child, ParentInfo { label, node: id });
+    }
+}
+
+/// Removes the parent entry for `child` if it is still `parent`.
+/// The child may have already been moved to a new parent.
+fn remove_parent(
+    parent_data: &mut ImHashMap<ChunkId, ParentInfo<ChunkId>>,
+    child: ChunkId,
+    parent: ChunkId,
+) {
+    if parent_data.get(&child).map(|p| p.node) == Some(parent) {
+        parent_data.remove(&child);
     }
 }
@@ -0,0 +1,371 @@
+//! Undo / redo history of a [Forest].
+//!
+//! Every revision keeps a whole [Forest], which is cheap since clones share their structure (copy on write).
+//! Revisions form a tree: committing after an undo starts a new branch instead of discarding the undone revisions.
+
+use std::{collections::BTreeMap, mem::size_of};
+
+use crate::{
+    changeset::Changeset, chunk::ChunkId, error::ForestError, indirect::enum_chunk,
+    indirect_node::IndirectChunk, Forest,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct RevisionId(u64);
+
+pub struct Revision {
+    pub forest: Forest,
+    pub label: String,
+    /// The edit which produced this revision from its parent, if known.
+    pub changeset: Option<Changeset>,
+    pub parent: Option<RevisionId>,
+    /// Child to go to on redo: the one most recently committed or checked out.
+    redo: Option<RevisionId>,
+}
+
+/// How much of the revisions' content is shared between them.
+///
+/// Chunks are counted as unique to a revision if they differ from its parent revision's,
+/// and as shared otherwise (along with the subtrees of the forest's map containing them).
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct MemoryUsage {
+    pub revisions: usize,
+    /// Chunks in all revisions, counted once per revision they are in.
+    pub total_chunks: usize,
+    pub unique_chunks: usize,
+    /// Estimated size of the chunks, counted once per revision they are in.
+    pub total_bytes: usize,
+    /// Estimated size of the unique chunks.
+    /// This is an upper bound since edited chunks share most of their content with the originals.
+    pub unique_bytes: usize,
+}
+
+impl MemoryUsage {
+    pub fn shared_chunks(&self) -> usize {
+        self.total_chunks - self.unique_chunks
+    }
+
+    pub fn shared_bytes(&self) -> usize {
+        self.total_bytes - self.unique_bytes
+    }
+}
+
+pub struct History {
+    revisions: BTreeMap<RevisionId, Revision>,
+    current: RevisionId,
+    next_id: u64,
+}
+
+impl History {
+    pub fn new(forest: Forest, label: impl Into<String>) -> Self {
+        let root = Revision {
+            forest,
+            label: label.into(),
+            changeset: None,
+            parent: None,
+            redo: None,
+        };
+        History {
+            revisions: std::iter::once((RevisionId(0), root)).collect(),
+            current: RevisionId(0),
+            next_id: 1,
+        }
+    }
+
+    pub fn current(&self) -> &Forest {
+        &self.revisions[&self.current].forest
+    }
+
+    pub fn current_id(&self) -> RevisionId {
+        self.current
+    }
+
+    pub fn revision(&self, id: RevisionId) -> Option<&Revision> {
+        self.revisions.get(&id)
+    }
+
+    /// Ids of all revisions, oldest first.
+    pub fn revision_ids(&self) -> impl Iterator<Item = RevisionId> + '_ {
+        self.revisions.keys().cloned()
+    }
+
+    /// Revisions committed on top of `id`, oldest first. More than one means history branched.
+    pub fn children(&self, id: RevisionId) -> Vec<RevisionId> {
+        self.revisions
+            .iter()
+            .filter(|(_, revision)| revision.parent == Some(id))
+            .map(|(child, _)| *child)
+            .collect()
+    }
+
+    /// Records `forest` as a new revision after the current one, and makes it current.
+    pub fn commit(
+        &mut self,
+        forest: Forest,
+        label: impl Into<String>,
+        changeset: Option<Changeset>,
+    ) -> RevisionId {
+        let id = RevisionId(self.next_id);
+        self.next_id += 1;
+        self.revisions.insert(
+            id,
+            Revision {
+                forest,
+                label: label.into(),
+                changeset,
+                parent: Some(self.current),
+                redo: None,
+            },
+        );
+        self.revisions.get_mut(&self.current).unwrap().redo = Some(id);
+        self.current = id;
+        id
+    }
+
+    /// Applies `changeset` to the current forest and commits the result, recording the changeset.
+    pub fn apply(
+        &mut self,
+        changeset: Changeset,
+        label: impl Into<String>,
+    ) -> Result<RevisionId, ForestError> {
+        let mut forest = self.current().clone();
+        forest.apply(&changeset)?;
+        Ok(self.commit(forest, label, Some(changeset)))
+    }
+
+    /// Goes back to the parent revision. Returns None (doing nothing) if there is none.
+    pub fn undo(&mut self) -> Option<&Forest> {
+        self.current = self.revisions[&self.current].parent?;
+        Some(self.current())
+    }
+
+    /// Goes forward to the child revision most recently committed or checked out.
+    /// Returns None (doing nothing) if there is none.
+    pub fn redo(&mut self) -> Option<&Forest> {
+        self.current = self.revisions[&self.current].redo?;
+        Some(self.current())
+    }
+
+    /// Makes `id` the current revision, and the one redo returns to from its ancestors.
+    pub fn checkout(&mut self, id: RevisionId) -> Option<&Forest> {
+        self.revisions.get(&id)?;
+        self.current = id;
+        let mut child = id;
+        while let Some(parent) = self.revisions[&child].parent {
+            self.revisions.get_mut(&parent).unwrap().redo = Some(child);
+            child = parent;
+        }
+        Some(self.current())
+    }
+
+    /// Drops all revisions except `root` and its descendants, making `root` the oldest revision.
+    /// Returns false (doing nothing) if the current revision is not `root` or one of its descendants.
+    pub fn prune(&mut self, root: RevisionId) -> bool {
+        if !self.is_ancestor(root, self.current) {
+            return false;
+        }
+        let ids: Vec<RevisionId> = self.revisions.keys().cloned().collect();
+        for id in ids {
+            if !self.is_ancestor(root, id) {
+                self.revisions.remove(&id);
+            }
+        }
+        self.revisions.get_mut(&root).unwrap().parent = None;
+        true
+    }
+
+    /// True if `ancestor` is `id` or one of its ancestors.
+    fn is_ancestor(&self, ancestor: RevisionId, id: RevisionId) -> bool {
+        let mut next = Some(id);
+        while let Some(id) = next {
+            if id == ancestor {
+                return true;
+            }
+            next = self.revisions.get(&id).and_then(|r| r.parent);
+        }
+        false
+    }
+
+    /// Reports how much content is shared between revisions.
+    /// Takes time linear in the total number of chunks in all revisions.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let mut usage = MemoryUsage {
+            revisions: self.revisions.len(),
+            ..MemoryUsage::default()
+        };
+        for revision in self.revisions.values() {
+            let forest = &revision.forest.forest;
+            for id in forest.map_keys() {
+                usage.total_chunks += 1;
+                usage.total_bytes += chunk_bytes(forest, *id);
+            }
+            let unique: Vec<ChunkId> = match revision.parent {
+                Some(parent) => self.revisions[&parent]
+                    .forest
+                    .forest
+                    .changed_chunks(forest)
+                    // Removed chunks are not in this revision.
+                    .filter(|id| forest.find_nodes(*id).is_some())
+                    .collect(),
+                None => forest.map_keys().cloned().collect(),
+            };
+            usage.unique_chunks += unique.len();
+            usage.unique_bytes += unique
+                .iter()
+                .map(|id| chunk_bytes(forest, *id))
+                .sum::<usize>();
+        }
+        usage
+    }
+}
+
+/// Estimated size of a chunk.
+fn chunk_bytes(forest: &crate::indirect_nav::Forest, id: ChunkId) -> usize {
+    size_of::<ChunkId>()
+        + match forest.find_nodes(id) {
+            Some(enum_chunk::Chunk::Uniform(c)) => size_of::<enum_chunk::Chunk>() + c.data.len(),
+            Some(enum_chunk::Chunk::Indirect(c)) => {
+                size_of::<IndirectChunk>()
+                    + c.payload.as_ref().map_or(0, |p| p.len())
+                    + c.traits
+                        .values()
+                        .map(|children| children.len() * size_of::<ChunkId>())
+                        .sum::<usize>()
+            }
+            None => 0,
+        }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{changeset::Op, node_id::NodeId, test_stuff::uniform_tree, tree::NodeData};
+
+    fn payload(forest: &Forest, id: u128) -> Vec<u8> {
+        let node = forest.get_tree(NodeId(id)).unwrap();
+        node.get_payload().unwrap().into_iter().cloned().collect()
+    }
+
+    fn edited(history: &History, value: u8) -> Forest {
+        let mut forest = history.current().clone();
+        forest.set_value(NodeId(8), &[value]).unwrap();
+        forest
+    }
+
+    #[test]
+    fn undo_redo_and_branch() {
+        let (tree, _) = uniform_tree(3);
+        let mut history = History::new(Forest::from(tree), "initial");
+        let root = history.current_id();
+        let a = history.commit(edited(&history, 1), "a", None);
+        let b = history.commit(edited(&history, 2), "b", None);
+
+        assert_eq!(payload(history.undo().unwrap(), 8), vec![1]);
+        assert_eq!(payload(history.undo().unwrap(), 8), vec![5]);
+        assert!(history.undo().is_none());
+        assert_eq!(history.current_id(), root);
+        assert_eq!(payload(history.redo().unwrap(), 8), vec![1]);
+
+        // Committing after an undo branches instead of discarding `b`.
+        let c = history.commit(edited(&history, 3), "c", None);
+        assert_eq!(history.children(a), vec![b, c]);
+        assert!(history.redo().is_none());
+        history.undo();
+        assert_eq!(payload(history.redo().unwrap(), 8), vec![3]);
+
+        history.checkout(root);
+        assert_eq!(payload(history.current(), 8), vec![5]);
+        history.checkout(b);
+        history.checkout(root);
+        history.redo();
+        assert_eq!(history.redo().map(|f| payload(f, 8)), Some(vec![2]));
+        assert_eq!(history.revision(b).unwrap().label, "b");
+    }
+
+    #[test]
+    fn prune() {
+        let (tree, _) = uniform_tree(3);
+        let mut history = History::new(Forest::from(tree), "initial");
+        let root = history.current_id();
+        let a = history.commit(edited(&history, 1), "a", None);
+        let b = history.commit(edited(&history, 2), "b", None);
+        history.checkout(root);
+        let c = history.commit(edited(&history, 3), "c", None);
+
+        // `c` is not under `a`.
+        assert!(!history.prune(a));
+        history.checkout(b);
+        assert!(history.prune(a));
+        assert_eq!(history.revision_ids().collect::<Vec<_>>(), vec![a, b]);
+        assert!(history.revision(c).is_none());
+        assert_eq!(payload(history.undo().unwrap(), 8), vec![1]);
+        assert!(history.undo().is_none());
+    }
+
+    #[test]
+    fn apply_records_changeset() {
+        let (tree, _) = uniform_tree(3);
+        let mut history = History::new(Forest::from(tree), "initial");
+        let changeset = Changeset {
+            ops: vec![Op::SetPayload {
+                id: NodeId(8),
+                payload: Some(vec![7]),
+            }],
+        };
+        let id = history.apply(changeset, "set").unwrap();
+        assert_eq!(payload(history.current(), 8), vec![7]);
+        assert_eq!(
+            history
+                .revision(id)
+                .unwrap()
+                .changeset
+                .as_ref()
+                .unwrap()
+                .ops
+                .len(),
+            1
+        );
+
+        let bad = Changeset {
+            ops: vec![Op::SetPayload {
+                id: NodeId(1000),
+                payload: None,
+            }],
+        };
+        assert_eq!(
+            history.apply(bad, "bad"),
+            Err(ForestError::UnknownId(NodeId(1000)))
+        );
+        assert_eq!(history.current_id(), id);
+    }
+
+    #[test]
+    fn memory_usage() {
+        let (tree, root) = uniform_tree(3);
+        let mut history = History::new(Forest::from(tree), "initial");
+        let usage = history.memory_usage();
+        assert_eq!((usage.total_chunks, usage.unique_chunks), (2, 2));
+        assert_eq!(usage.shared_bytes(), 0);
+
+        // Editing a payload in the uniform chunk only replaces that chunk.
+        history.commit(edited(&history, 1), "a", None);
+        let usage = history.memory_usage();
+        assert_eq!(usage.revisions, 2);
+        assert_eq!((usage.total_chunks, usage.unique_chunks), (4, 3));
+        assert_eq!(usage.shared_chunks(), 1);
+        assert!(usage.shared_bytes() > 0);
+
+        // Identical revisions share everything.
+        history.commit(history.current().clone(), "b", None);
+        let previous = usage;
+        let usage = history.memory_usage();
+        assert_eq!(usage.unique_chunks, previous.unique_chunks);
+        assert_eq!(usage.unique_bytes, previous.unique_bytes);
+
+        let mut forest = history.current().clone();
+        forest.delete_subtree(NodeId(11)).unwrap();
+        history.commit(forest, "c", None);
+        assert!(history.memory_usage().unique_chunks > usage.unique_chunks);
+        assert!(history.current().forest.nav_from(root).is_some());
+    }
+}
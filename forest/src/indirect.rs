@@ -10,7 +10,6 @@
 
 use crate::{indirect_node::IndirectChunk, uniform_chunk::UniformChunk};
 
-// TODO: support undownloaded chunks blobs (find can return which blobs and at what offset the node is at)
 // TODO: support undownloaded subtrees that arn't chunks: find returns iterator of candidate trees using bloom filters
 // TODO: these types are write optimized. Consider supporting read/size optimized types (ex: using byte array instead of im's Vector)
 // TODO: maybe chunks referencing external subtrees (so they can have child references like payloads)
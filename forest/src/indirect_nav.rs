@@ -2,62 +2,384 @@
 
 use crate::{
     chunk::{Chunk, ChunkId},
+    error::ForestError,
     forest,
     indirect::enum_chunk,
     nav::{self, Resolver},
-    node_id::{HasId, NodeId},
-    tree::ParentInfo,
-    uniform_chunk::ChunkIterator,
+    node_id::{HasId, IdOffset, NodeId},
+    sync::Shared,
+    tree::{IdBase, Label, NodeNav, ParentInfo},
+    uniform_chunk::{ChunkIterator, OffsetInfoRef, UniformChunk},
 };
 
 pub type Forest = forest::Forest<enum_chunk::Chunk>;
 
-impl<'a> Resolver<enum_chunk::Node<'a>> for &'a Forest {
+/// Chunks which are (or can be loaded as) an [enum_chunk::Chunk].
+/// This allows the [Resolver] to be used with forests which don't have all their chunks loaded.
+pub trait LoadChunk {
+    fn load(&self) -> Result<&enum_chunk::Chunk, ForestError>;
+}
+
+impl LoadChunk for enum_chunk::Chunk {
+    fn load(&self) -> Result<&enum_chunk::Chunk, ForestError> {
+        Ok(self)
+    }
+}
+
+impl<'a, TChunk> Resolver<enum_chunk::Node<'a>> for &'a forest::Forest<TChunk>
+where
+    TChunk: LoadChunk + Clone + PartialEq<TChunk>,
+    for<'b> &'b TChunk: Chunk,
+{
     type Child = enum_chunk::Child<'a>;
 
     type Iter = enum_chunk::Expander<'a>;
 
+    /// Children which are not in the forest (only possible if it is corrupt) or fail to load expand to nothing.
     fn expand(&self, chunk: Self::Child) -> Self::Iter {
         match chunk {
-            enum_chunk::Child::Indirect(id) => {
-                self.find_nodes(id).unwrap().top_level_nodes(id.0).into()
-            }
+            enum_chunk::Child::Indirect(id) => match self.find_nodes(id).map(LoadChunk::load) {
+                Some(Ok(chunk)) => chunk.top_level_nodes(id.0),
+                _ => enum_chunk::Expander::Uniform(ChunkIterator::Empty),
+            },
             enum_chunk::Child::Uniform(chunk) => {
-                enum_chunk::Expander::Uniform(ChunkIterator::View(chunk))
+                enum_chunk::Expander::Uniform(ChunkIterator::Single(Some(chunk)))
             }
         }
     }
 
     fn get_parent(&self, node: &enum_chunk::Node<'a>) -> Option<ParentInfo<enum_chunk::Node<'a>>> {
+        self.try_get_parent(node).ok().flatten()
+    }
+
+    fn index_in_parent(&self, node: &enum_chunk::Node<'a>) -> Option<usize> {
+        self.try_index_in_parent(node).ok().flatten()
+    }
+
+    fn sibling(&self, node: &enum_chunk::Node<'a>, forward: bool) -> Option<enum_chunk::Node<'a>> {
+        self.try_sibling(node, forward).ok().flatten()
+    }
+
+    fn trait_len(&self, node: &enum_chunk::Node<'a>, label: Label) -> usize {
+        self.try_trait_len(node, label).unwrap_or(0)
+    }
+
+    fn trait_get(
+        &self,
+        node: &enum_chunk::Node<'a>,
+        label: Label,
+        index: usize,
+    ) -> Option<enum_chunk::Node<'a>> {
+        self.try_trait_get(node, label, index).ok().flatten()
+    }
+}
+
+impl<TChunk> forest::Forest<TChunk>
+where
+    TChunk: LoadChunk + Clone + PartialEq<TChunk>,
+    for<'b> &'b TChunk: Chunk,
+{
+    /// Gets the parent of `node`, or None if it is a root.
+    pub fn try_get_parent<'a>(
+        &'a self,
+        node: &enum_chunk::Node<'a>,
+    ) -> Result<Option<ParentInfo<enum_chunk::Node<'a>>>, ForestError> {
         match node {
-            enum_chunk::Node::Indirect(basic) => self.get_parent_from_chunk_id(ChunkId(basic.id)),
-            enum_chunk::Node::Uniform(chunk) => {
-                // TODO: Performance: maybe avoid id lookup then node look up from id below?
-                let id = chunk.get_id();
-                // Currently UniformNodes don't store a reference to the UniformChunk they are in (instead just the parts they need).
-                // Since we need to actual root schema to do the parent lookup, recover the actual chunk from the Forest:
-                let (chunk_id, chunk) = self.find_nodes_from_node(id).unwrap();
-                match chunk {
-                    enum_chunk::Chunk::Uniform(c) => {
-                        let info = c.schema.lookup_schema(chunk_id.0, id).unwrap();
-                        let parent = match info.parent.parent {
-                            Some(x) => x,
-                            None => {
-                                return self.get_parent_from_chunk_id(*chunk_id);
-                            }
-                        };
-                        Some(ParentInfo {
-                            node: enum_chunk::Node::Uniform(
-                                c.get(chunk_id.0, chunk_id.0 + parent.0).unwrap(),
-                            ),
-                            label: parent.1,
-                        })
+            enum_chunk::Node::Indirect(basic) => self.chunk_parent(ChunkId(basic.id)),
+            enum_chunk::Node::Uniform(node) => {
+                let (chunk_id, c, info) = self.uniform_info(node.get_id())?;
+                let parent = match info.parent.parent {
+                    Some(x) => x,
+                    None => {
+                        return self.chunk_parent(chunk_id);
                     }
-                    _ => panic!(),
+                };
+                let node = c
+                    .get(chunk_id.0, chunk_id.0 + parent.0)
+                    .ok_or(ForestError::SchemaMismatch(chunk_id))?;
+                Ok(Some(ParentInfo {
+                    node: enum_chunk::Node::Uniform(node),
+                    label: parent.1,
+                }))
+            }
+        }
+    }
+
+    /// Gets the index of `node` within its parent's trait, or None if it is a root.
+    /// Uses the cached [forest::Forest::child_index] and [forest::Forest::trait_offsets] of the parent's trait.
+    pub fn try_index_in_parent(
+        &self,
+        node: &enum_chunk::Node<'_>,
+    ) -> Result<Option<usize>, ForestError> {
+        match node {
+            enum_chunk::Node::Indirect(basic) => self.chunk_index(ChunkId(basic.id), 0),
+            enum_chunk::Node::Uniform(node) => {
+                let (chunk_id, _, info) = self.uniform_info(node.get_id())?;
+                match info.parent.parent {
+                    Some(_) => Ok(Some(info.parent.index)),
+                    None => self.chunk_index(chunk_id, info.parent.index),
                 }
             }
         }
     }
+
+    /// Gets the node after `node` (or before it if `forward` is false) in its parent's trait.
+    /// None if there is no such node, or if `node` is a root.
+    pub fn try_sibling<'a>(
+        &'a self,
+        node: &enum_chunk::Node<'a>,
+        forward: bool,
+    ) -> Result<Option<enum_chunk::Node<'a>>, ForestError> {
+        match node {
+            enum_chunk::Node::Indirect(basic) => self.chunk_sibling(ChunkId(basic.id), forward),
+            enum_chunk::Node::Uniform(node) => {
+                let id = node.get_id();
+                let (chunk_id, c, info) = self.uniform_info(id)?;
+                // Siblings within the chunk are adjacent in id space.
+                let stride = IdOffset(info.schema.id_stride);
+                let sibling = if forward {
+                    (info.parent.index + 1 < info.schema.node_count as usize).then(|| id + stride)
+                } else {
+                    (info.parent.index > 0).then(|| NodeId(id.0 - stride.0 as IdBase))
+                };
+                match (sibling, info.parent.parent) {
+                    (Some(sibling), _) => {
+                        Ok(c.get(chunk_id.0, sibling).map(enum_chunk::Node::Uniform))
+                    }
+                    (None, Some(_)) => Ok(None),
+                    (None, None) => self.chunk_sibling(chunk_id, forward),
+                }
+            }
+        }
+    }
+
+    /// Gets the node reached from `root` by taking child `index` of trait `label` for each step of `path`.
+    pub fn resolve_path(
+        &self,
+        root: NodeId,
+        path: &[(Label, usize)],
+    ) -> Option<enum_chunk::Node<'_>> {
+        let mut node = self.load_node(root).ok()?;
+        for (label, index) in path {
+            node = self.try_trait_get(&node, *label, *index).ok()??;
+        }
+        Some(node)
+    }
+
+    /// Gets the path from the root of the tree containing `id` to it (see [Self::resolve_path]).
+    pub fn path_of(&self, id: NodeId) -> Result<Vec<(Label, usize)>, ForestError> {
+        let mut node = self.load_node(id)?;
+        let mut path = vec![];
+        while let (Some(parent), Some(index)) = (
+            self.try_get_parent(&node)?,
+            self.try_index_in_parent(&node)?,
+        ) {
+            path.push((parent.label, index));
+            node = parent.node;
+        }
+        path.reverse();
+        Ok(path)
+    }
+
+    /// Gets the node `id`, loading its chunk if needed.
+    fn load_node(&self, id: NodeId) -> Result<enum_chunk::Node<'_>, ForestError> {
+        self.try_find_node(id)?.ok_or(ForestError::UnknownId(id))
+    }
+
+    /// Gets the node `id`, or None if it is not in the forest.
+    /// Unlike [forest::Forest::find_node], fails if the chunk containing it can not be loaded.
+    pub fn try_find_node(&self, id: NodeId) -> Result<Option<enum_chunk::Node<'_>>, ForestError> {
+        match self.find_nodes_from_node(id) {
+            Some((chunk_id, chunk)) if id < chunk_id.0 + chunk.id_extent() => {
+                Ok(chunk.load()?.get(chunk_id.0, id))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Number of nodes in trait `label` of `node`.
+    pub fn try_trait_len(
+        &self,
+        node: &enum_chunk::Node<'_>,
+        label: Label,
+    ) -> Result<usize, ForestError> {
+        match node {
+            enum_chunk::Node::Indirect(basic) => {
+                let offsets = self.offsets(ChunkId(basic.id), label)?;
+                Ok(offsets[offsets.len() - 1])
+            }
+            enum_chunk::Node::Uniform(node) => Ok(node.get_trait(label).len()),
+        }
+    }
+
+    /// Node `index` in trait `label` of `node`.
+    ///
+    /// Finds the child chunk containing it with a binary search over [forest::Forest::trait_offsets],
+    /// then jumps to the node within the chunk, so this is `O(log(n))` in the number of child chunks
+    /// (once the offsets are cached).
+    pub fn try_trait_get<'a>(
+        &'a self,
+        node: &enum_chunk::Node<'a>,
+        label: Label,
+        index: usize,
+    ) -> Result<Option<enum_chunk::Node<'a>>, ForestError> {
+        match node {
+            enum_chunk::Node::Indirect(basic) => {
+                let offsets = self.offsets(ChunkId(basic.id), label)?;
+                if index >= offsets[offsets.len() - 1] {
+                    return Ok(None);
+                }
+                // Last child starting at or before index: skips any empty children starting at the same place.
+                let i = offsets.partition_point(|offset| *offset <= index) - 1;
+                let child = basic.node.traits[&label][i];
+                let chunk = self
+                    .find_nodes(child)
+                    .ok_or(ForestError::DanglingChild {
+                        parent: ChunkId(basic.id),
+                        child,
+                    })?
+                    .load()?;
+                Ok(chunk.top_level_nodes(child.0).nth(index - offsets[i]))
+            }
+            enum_chunk::Node::Uniform(node) => Ok(node
+                .get_trait(label)
+                .nth(index)
+                .map(enum_chunk::Node::Uniform)),
+        }
+    }
+
+    /// [forest::Forest::trait_offsets] for trait `label` of the chunk `id`.
+    fn offsets(&self, id: ChunkId, label: Label) -> Result<Shared<[usize]>, ForestError> {
+        self.trait_offsets(id, label, |child| self.top_level_count(child))
+    }
+
+    /// Number of top level nodes in the chunk `id`: the count to pass to [forest::Forest::edit].
+    pub fn top_level_count(&self, id: ChunkId) -> Result<usize, ForestError> {
+        // Missing chunks expand to no nodes.
+        Ok(match self.find_nodes(id) {
+            Some(chunk) => match chunk.load()? {
+                enum_chunk::Chunk::Indirect(_) => 1,
+                enum_chunk::Chunk::Uniform(c) => c.get_count(),
+            },
+            None => 0,
+        })
+    }
+
+    /// Finds the [UniformChunk] containing `id` along with the layout information for it.
+    fn uniform_info(
+        &self,
+        id: NodeId,
+    ) -> Result<(ChunkId, &UniformChunk, OffsetInfoRef<'_>), ForestError> {
+        // Currently UniformNodes don't store a reference to the UniformChunk they are in (instead just the parts they need).
+        // Since we need to actual root schema to do the lookup, recover the actual chunk from the Forest:
+        let (chunk_id, chunk) = self
+            .find_nodes_from_node(id)
+            .ok_or(ForestError::UnknownId(id))?;
+        match chunk.load()? {
+            enum_chunk::Chunk::Uniform(c) => {
+                let info = c
+                    .schema
+                    .lookup_schema(chunk_id.0, id)
+                    .ok_or(ForestError::UnknownId(id))?;
+                Ok((*chunk_id, c, info))
+            }
+            _ => Err(ForestError::SchemaMismatch(*chunk_id)),
+        }
+    }
+
+    /// Parent of the chunk `id` and the index of `id` in the parent's trait, or None if it is a root.
+    fn chunk_position(
+        &self,
+        id: ChunkId,
+    ) -> Result<Option<(ParentInfo<ChunkId>, usize)>, ForestError> {
+        let parent = match self.get_parent_data().get(&id) {
+            Some(parent) => parent.clone(),
+            None => return Ok(None),
+        };
+        Ok(self.child_index(id)?.map(|index| (parent, index)))
+    }
+
+    /// Index of the node `offset` top level nodes into chunk `id` within the chunk's parent's trait.
+    fn chunk_index(&self, id: ChunkId, offset: usize) -> Result<Option<usize>, ForestError> {
+        let (parent, index) = match self.chunk_position(id)? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let offsets = self.offsets(parent.node, parent.label)?;
+        Ok(Some(offsets[index] + offset))
+    }
+
+    /// First node after the chunk `id` (or last node before it if `forward` is false) in its parent's trait.
+    /// Finds it with a binary search over [forest::Forest::trait_offsets], which skips any empty chunks in between.
+    fn chunk_sibling(
+        &self,
+        id: ChunkId,
+        forward: bool,
+    ) -> Result<Option<enum_chunk::Node<'_>>, ForestError> {
+        let (parent, index) = match self.chunk_position(id)? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let offsets = self.offsets(parent.node, parent.label)?;
+        // Index of the sibling node in the trait.
+        let position = if forward {
+            offsets[index + 1]
+        } else {
+            match offsets[index].checked_sub(1) {
+                Some(position) => position,
+                None => return Ok(None),
+            }
+        };
+        if position >= offsets[offsets.len() - 1] {
+            return Ok(None);
+        }
+        // Last child starting at or before position: skips any empty children starting at the same place.
+        let i = offsets.partition_point(|offset| *offset <= position) - 1;
+        let sibling = match self
+            .find_nodes(parent.node)
+            .ok_or(ForestError::UnknownId(parent.node.0))?
+            .load()?
+        {
+            enum_chunk::Chunk::Indirect(node) => node.traits[&parent.label][i],
+            enum_chunk::Chunk::Uniform(_) => return Err(ForestError::SchemaMismatch(parent.node)),
+        };
+        let chunk = self
+            .find_nodes(sibling)
+            .ok_or(ForestError::DanglingChild {
+                parent: parent.node,
+                child: sibling,
+            })?
+            .load()?;
+        Ok(match chunk {
+            enum_chunk::Chunk::Uniform(c) => {
+                let offset = c.schema.schema.id_stride * (position - offsets[i]) as u32;
+                c.get(sibling.0, sibling.0 + IdOffset(offset))
+                    .map(enum_chunk::Node::Uniform)
+            }
+            chunk => chunk.top_level_nodes(sibling.0).next(),
+        })
+    }
+
+    /// Gets the parent of the chunk `id`, or None if it is a root.
+    fn chunk_parent(
+        &self,
+        id: ChunkId,
+    ) -> Result<Option<ParentInfo<enum_chunk::Node<'_>>>, ForestError> {
+        let parent = match self.get_parent_data().get(&id) {
+            Some(parent) => parent.clone(),
+            None => return Ok(None),
+        };
+        let node = self
+            .find_nodes(parent.node)
+            .ok_or(ForestError::UnknownId(parent.node.0))?
+            .load()?
+            .get(parent.node.0, parent.node.0)
+            .ok_or(ForestError::SchemaMismatch(parent.node))?;
+        Ok(Some(ParentInfo {
+            node,
+            label: parent.label,
+        }))
+    }
 }
 
 impl<TChunk> forest::Forest<TChunk>
@@ -75,7 +397,14 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{indirect_node::IndirectChunk, tree::Def};
+    use crate::{
+        indirect_node::IndirectChunk,
+        nav::WithParent,
+        test_stuff::{big_tree, check_parents, uniform_tree, wide_tree, COLORS},
+        tree::{Def, Label, Node, NodeNav},
+        uniform_chunk::{ChunkSchema, OffsetSchema, RootChunkSchema},
+    };
+    use std::collections::HashMap;
 
     #[test]
     fn it_works() {
@@ -85,7 +414,8 @@ mod tests {
             IndirectChunk {
                 def: Def(1),
                 payload: None,
-                traits: im_rc::HashMap::default(),
+                payload_layout: None,
+                traits: im::HashMap::default(),
             }
             .into(),
         );
@@ -95,4 +425,478 @@ mod tests {
         let n = forest.find_nodes(ChunkId(NodeId(5))).unwrap();
         let _n = Chunk::get(&n, NodeId(5), NodeId(5)).unwrap();
     }
+
+    fn leaf() -> enum_chunk::Chunk {
+        IndirectChunk {
+            def: Def(1),
+            payload: None,
+            payload_layout: None,
+            traits: im::HashMap::default(),
+        }
+        .into()
+    }
+
+    /// (child, parent, label) for all chunks with parents, computed from scratch.
+    fn all_parents(forest: &Forest) -> Vec<(ChunkId, ChunkId, Label)> {
+        let mut parents = vec![];
+        for id in forest.map_keys() {
+            let chunk = forest.find_nodes(*id).unwrap();
+            for label in chunk.get_traits() {
+                parents.extend(chunk.get_trait(label).map(|child| (child, *id, label)));
+            }
+        }
+        parents.sort();
+        parents
+    }
+
+    fn parent_data(forest: &Forest) -> Vec<(ChunkId, ChunkId, Label)> {
+        let mut parents: Vec<_> = forest
+            .get_parent_data()
+            .iter()
+            .map(|(child, p)| (*child, p.node, p.label))
+            .collect();
+        parents.sort();
+        parents
+    }
+
+    #[test]
+    fn incremental_parents() {
+        let mut forest = Forest::new();
+        let mut root = IndirectChunk {
+            def: Def(1),
+            payload: None,
+            payload_layout: None,
+            traits: im::HashMap::default(),
+        };
+        let children: Vec<ChunkId> = (1..1000).map(|i| ChunkId(NodeId(i))).collect();
+        for child in children.iter() {
+            forest.insert(*child, leaf());
+        }
+        root.traits.insert(Label(2), children);
+        forest.insert(ChunkId(NodeId(0)), root.into());
+        forest.insert(ChunkId(NodeId(5000)), leaf());
+        assert_eq!(parent_data(&forest), all_parents(&forest));
+
+        let edit = |forest: &mut Forest, f: &dyn Fn(&mut IndirectChunk)| {
+            forest.update(ChunkId(NodeId(0)), |chunk| match chunk {
+                enum_chunk::Chunk::Indirect(node) => f(node),
+                _ => panic!(),
+            });
+            assert_eq!(parent_data(forest), all_parents(forest));
+        };
+        // Append
+        edit(&mut forest, &|node| {
+            node.traits
+                .get_mut(&Label(2))
+                .unwrap()
+                .push(ChunkId(NodeId(5000)))
+        });
+        // Remove from the middle
+        edit(&mut forest, &|node| {
+            node.traits.get_mut(&Label(2)).unwrap().remove(500);
+        });
+        // Move to a trait which is visited first
+        edit(&mut forest, &|node| {
+            let child = node.traits.get_mut(&Label(2)).unwrap().remove(10);
+            node.traits.insert(Label(1), vec![child]);
+        });
+        // Reorder
+        edit(&mut forest, &|node| {
+            node.traits.get_mut(&Label(2)).unwrap().swap(0, 900);
+        });
+
+        // Move to a new parent.
+        let mut other = IndirectChunk {
+            def: Def(1),
+            payload: None,
+            payload_layout: None,
+            traits: im::HashMap::default(),
+        };
+        other.traits.insert(Label(1), vec![ChunkId(NodeId(3))]);
+        forest.insert(ChunkId(NodeId(6000)), other.into());
+        edit(&mut forest, &|node| {
+            node.traits
+                .get_mut(&Label(2))
+                .unwrap()
+                .retain(|c| *c != ChunkId(NodeId(3)))
+        });
+
+        // Edits which report the children they change.
+        let root = ChunkId(NodeId(0));
+        forest.offsets(root, Label(2)).unwrap();
+        forest.edit(
+            root,
+            |chunk| match chunk {
+                enum_chunk::Chunk::Indirect(node) => {
+                    let moved = node.traits.get_mut(&Label(2)).unwrap().remove(20);
+                    node.traits.insert(Label(3), vec![moved]);
+                    let edits = vec![
+                        forest::ChildEdit {
+                            label: Label(2),
+                            index: 20,
+                            removed: vec![moved],
+                            added: vec![],
+                        },
+                        forest::ChildEdit {
+                            label: Label(3),
+                            index: 0,
+                            removed: vec![],
+                            added: vec![moved],
+                        },
+                    ];
+                    ((), edits)
+                }
+                _ => panic!(),
+            },
+            Forest::top_level_count,
+        );
+        assert_eq!(parent_data(&forest), all_parents(&forest));
+        // The cached offsets were spliced rather than dropped.
+        let mut expected = vec![0];
+        for child in forest.find_nodes(root).unwrap().get_trait(Label(2)) {
+            expected.push(expected.last().unwrap() + forest.top_level_count(child).unwrap());
+        }
+        assert_eq!(*forest.offsets(root, Label(2)).unwrap(), *expected);
+
+        forest.remove(root);
+        assert_eq!(parent_data(&forest), all_parents(&forest));
+    }
+
+    /// Root (id 0) with children: a leaf (99), a [UniformChunk] (100) of two nodes with nested children, and a leaf (200).
+    fn nested_forest() -> Forest {
+        // Two top level nodes, each with 3 children, each with 2 children.
+        let leaf_schema = Shared::new(ChunkSchema {
+            def: Def(4),
+            node_count: 2,
+            bytes_per_node: 1,
+            id_stride: 1,
+            payload_size: Some(1),
+            payload_layout: None,
+            traits: HashMap::default(),
+        });
+        let mid_schema = Shared::new(ChunkSchema {
+            def: Def(3),
+            node_count: 3,
+            bytes_per_node: 2,
+            id_stride: 3,
+            payload_size: None,
+            payload_layout: None,
+            traits: std::iter::once((
+                Label(2),
+                OffsetSchema {
+                    id_offset: IdOffset(1),
+                    byte_offset: 0,
+                    schema: leaf_schema,
+                },
+            ))
+            .collect(),
+        });
+        let schema = ChunkSchema {
+            def: Def(2),
+            node_count: 2,
+            bytes_per_node: 6,
+            id_stride: 10,
+            payload_size: None,
+            payload_layout: None,
+            traits: std::iter::once((
+                Label(1),
+                OffsetSchema {
+                    id_offset: IdOffset(1),
+                    byte_offset: 0,
+                    schema: mid_schema,
+                },
+            ))
+            .collect(),
+        };
+
+        let mut forest = Forest::new();
+        forest.insert(
+            ChunkId(NodeId(100)),
+            UniformChunk {
+                schema: Shared::new(RootChunkSchema::new(schema)),
+                data: Box::new((0..12).collect()),
+            }
+            .into(),
+        );
+        for id in [99, 200] {
+            forest.insert(ChunkId(NodeId(id)), leaf());
+        }
+        let mut root = IndirectChunk {
+            def: Def(1),
+            payload: None,
+            payload_layout: None,
+            traits: im::HashMap::default(),
+        };
+        root.traits.insert(
+            Label(1),
+            [99, 100, 200].map(|id| ChunkId(NodeId(id))).to_vec(),
+        );
+        forest.insert(ChunkId(NodeId(0)), root.into());
+        forest
+    }
+
+    #[test]
+    fn siblings() {
+        let forest = nested_forest();
+        let nav = forest.nav_from(NodeId(0)).unwrap();
+        check_parents(nav);
+
+        // Second child of the third child of the second top level node.
+        let nav = forest.nav_from(NodeId(119)).unwrap();
+        assert_eq!(nav.parent().unwrap().node.get_id(), NodeId(117));
+        assert_eq!(nav.index_in_parent(), Some(1));
+        assert!(nav.next_sibling().is_none());
+        // Second top level node.
+        let nav = forest.nav_from(NodeId(110)).unwrap();
+        assert_eq!(nav.index_in_parent(), Some(2));
+        assert_eq!(nav.next_sibling().unwrap().get_id(), NodeId(200));
+        assert_eq!(
+            forest
+                .nav_from(NodeId(200))
+                .unwrap()
+                .prev_sibling()
+                .unwrap()
+                .get_id(),
+            NodeId(110)
+        );
+        assert_eq!(forest.nav_from(NodeId(0)).unwrap().index_in_parent(), None);
+    }
+
+    /// Inserts `child` at `index` in trait [COLORS] of the root of a [wide_tree].
+    fn insert_wide_child(forest: &mut Forest, index: usize, child: ChunkId) {
+        forest.edit(
+            ChunkId(NodeId(0)),
+            |chunk| match chunk {
+                enum_chunk::Chunk::Indirect(node) => {
+                    node.traits[&COLORS].insert(index, child);
+                    let edit = forest::ChildEdit {
+                        label: COLORS,
+                        index,
+                        removed: vec![],
+                        added: vec![child],
+                    };
+                    ((), vec![edit])
+                }
+                _ => panic!(),
+            },
+            Forest::top_level_count,
+        );
+    }
+
+    #[test]
+    fn cached_child_indices() {
+        let (mut forest, root) = wide_tree(5);
+        check_parents(forest.nav_from(root).unwrap());
+
+        // Appending keeps the cached indices.
+        let leaf = forest.find_nodes(ChunkId(NodeId(1))).unwrap().clone();
+        forest.insert(ChunkId(NodeId(6)), leaf);
+        insert_wide_child(&mut forest, 5, ChunkId(NodeId(6)));
+        assert_eq!(forest.child_index(ChunkId(NodeId(6))), Ok(Some(5)));
+        check_parents(forest.nav_from(root).unwrap());
+
+        // Inserting in the middle shifts the following children.
+        // The inserted chunk is missing so expands to nothing, and is skipped by sibling steps.
+        insert_wide_child(&mut forest, 1, ChunkId(NodeId(100)));
+        assert_eq!(forest.child_index(ChunkId(NodeId(2))), Ok(Some(2)));
+        let nav = forest.nav_from(NodeId(2)).unwrap();
+        assert_eq!(nav.index_in_parent(), Some(1));
+        assert_eq!(nav.prev_sibling().unwrap().get_id(), NodeId(1));
+        assert_eq!(
+            forest
+                .nav_from(NodeId(1))
+                .unwrap()
+                .next_sibling()
+                .unwrap()
+                .get_id(),
+            NodeId(2)
+        );
+        check_parents(forest.nav_from(root).unwrap());
+        assert_eq!(forest.child_index(ChunkId(root)), Ok(None));
+    }
+
+    fn all_ids(node: impl Node + HasId, out: &mut Vec<NodeId>) {
+        out.push(node.get_id());
+        for label in node.get_traits() {
+            for child in node.get_trait(label) {
+                all_ids(child, out);
+            }
+        }
+    }
+
+    #[test]
+    fn paths() {
+        let forest = nested_forest();
+        let mut ids = vec![];
+        all_ids(forest.nav_from(NodeId(0)).unwrap(), &mut ids);
+        assert_eq!(ids.len(), 1 + 2 + 2 * (1 + 3 * (1 + 2)));
+        for id in ids {
+            let path = forest.path_of(id).unwrap();
+            let node = forest.resolve_path(NodeId(0), &path).unwrap();
+            assert_eq!(node.get_id(), id);
+        }
+        assert_eq!(
+            forest.path_of(NodeId(115)).unwrap(),
+            vec![(Label(1), 2), (Label(1), 1), (Label(2), 0)]
+        );
+        assert!(forest.resolve_path(NodeId(0), &[(Label(1), 4)]).is_none());
+        assert!(forest
+            .resolve_path(NodeId(0), &[(Label(1), 1), (Label(1), 3)])
+            .is_none());
+    }
+
+    /// Checks indexing into every trait under `nav` agrees with iterating it.
+    fn check_indexing<'a>(nav: nav::Nav<&'a Forest, enum_chunk::Node<'a>>) {
+        for label in nav.get_traits() {
+            let ids: Vec<NodeId> = nav.get_trait(label).map(|c| c.get_id()).collect();
+            assert_eq!(nav.trait_len(label), ids.len());
+            for (i, id) in ids.iter().enumerate() {
+                assert_eq!(nav.trait_get(label, i).unwrap().get_id(), *id);
+            }
+            assert!(nav.trait_get(label, ids.len()).is_none());
+            for child in nav.get_trait(label) {
+                check_indexing(child);
+            }
+        }
+    }
+
+    #[test]
+    fn indexed_traits() {
+        let (forest, root) = big_tree(100, 20, 100);
+        check_indexing(forest.nav_from(root).unwrap());
+
+        let mut forest = nested_forest();
+        check_indexing(forest.nav_from(NodeId(0)).unwrap());
+        assert_eq!(forest.nav_from(NodeId(0)).unwrap().trait_len(Label(1)), 4);
+
+        // Changing the size of a child updates its parent's offsets.
+        let chunk = match forest.find_nodes(ChunkId(NodeId(100))).unwrap() {
+            enum_chunk::Chunk::Uniform(c) => c.slice(0, 1),
+            _ => panic!(),
+        };
+        forest.insert(ChunkId(NodeId(100)), chunk.into());
+        let nav = forest.nav_from(NodeId(0)).unwrap();
+        assert_eq!(nav.trait_len(Label(1)), 3);
+        assert_eq!(nav.trait_get(Label(1), 2).unwrap().get_id(), NodeId(200));
+        check_indexing(nav);
+
+        // As does changing the parent.
+        forest.update(ChunkId(NodeId(0)), |chunk| match chunk {
+            enum_chunk::Chunk::Indirect(node) => node.traits[&Label(1)].remove(0),
+            _ => panic!(),
+        });
+        let nav = forest.nav_from(NodeId(0)).unwrap();
+        assert_eq!(nav.trait_len(Label(1)), 2);
+        assert_eq!(nav.trait_get(Label(1), 0).unwrap().get_id(), NodeId(100));
+        check_indexing(nav);
+    }
+
+    #[test]
+    fn try_insert() {
+        let (mut forest, _) = uniform_tree(3);
+        let chunk = forest.find_nodes(ChunkId(NodeId(1))).unwrap().clone();
+        // Uniform chunk at 1 uses ids 1 to 15.
+        assert_eq!(
+            forest.try_insert(ChunkId(NodeId(15)), leaf()),
+            Err(ForestError::OverlappingChunks(
+                ChunkId(NodeId(1)),
+                ChunkId(NodeId(15))
+            ))
+        );
+        assert_eq!(
+            forest.try_insert(ChunkId(NodeId(20)), chunk.clone()),
+            Ok(())
+        );
+        assert_eq!(
+            forest.try_insert(ChunkId(NodeId(17)), chunk.clone()),
+            Err(ForestError::OverlappingChunks(
+                ChunkId(NodeId(17)),
+                ChunkId(NodeId(20))
+            ))
+        );
+        // Replacing a chunk is not.
+        assert_eq!(
+            forest.try_insert(ChunkId(NodeId(1)), chunk.clone()),
+            Err(ForestError::OverlappingChunks(
+                ChunkId(NodeId(1)),
+                ChunkId(NodeId(1))
+            ))
+        );
+        assert_eq!(forest.try_insert(ChunkId(NodeId(16)), leaf()), Ok(()));
+        forest.validate().unwrap();
+
+        // Ids at the end of the id space.
+        let last = ChunkId(NodeId(IdBase::MAX));
+        let near_end = ChunkId(NodeId(IdBase::MAX - 3));
+        assert_eq!(forest.try_insert(last, leaf()), Ok(()));
+        assert_eq!(
+            forest.try_insert(ChunkId(NodeId(IdBase::MAX - 20)), chunk.clone()),
+            Ok(())
+        );
+        assert_eq!(
+            forest.try_insert(near_end, chunk.clone()),
+            Err(ForestError::OverlappingChunks(near_end, last))
+        );
+        forest.validate().unwrap();
+        forest.insert(near_end, chunk);
+        assert_eq!(
+            forest.validate(),
+            Err(ForestError::OverlappingChunks(near_end, last))
+        );
+    }
+
+    #[test]
+    fn validate() {
+        let (forest, _) = uniform_tree(3);
+        forest.validate().unwrap();
+
+        let mut overlapping = forest.clone();
+        overlapping.insert(ChunkId(NodeId(7)), leaf());
+        assert_eq!(
+            overlapping.validate(),
+            Err(ForestError::OverlappingChunks(
+                ChunkId(NodeId(1)),
+                ChunkId(NodeId(7))
+            ))
+        );
+
+        let mut dangling = forest.clone();
+        dangling.remove(ChunkId(NodeId(1)));
+        assert_eq!(
+            dangling.validate(),
+            Err(ForestError::DanglingChild {
+                parent: ChunkId(NodeId(0)),
+                child: ChunkId(NodeId(1))
+            })
+        );
+
+        let mut shared = forest;
+        let mut other = IndirectChunk {
+            def: Def(1),
+            payload: None,
+            payload_layout: None,
+            traits: im::HashMap::default(),
+        };
+        other.traits.insert(Label(1), vec![ChunkId(NodeId(1))]);
+        shared.insert(ChunkId(NodeId(100)), other.into());
+        assert_eq!(
+            shared.validate(),
+            Err(ForestError::MultipleParents(ChunkId(NodeId(1))))
+        );
+
+        // Two chunks which are each other's parent, so are not under any root.
+        let mut cycle = Forest::new();
+        for (id, child) in [(100, 200), (200, 100)] {
+            let node = IndirectChunk {
+                def: Def(1),
+                payload: None,
+                payload_layout: None,
+                traits: [(Label(1), vec![ChunkId(NodeId(child))])]
+                    .into_iter()
+                    .collect(),
+            };
+            cycle.insert(ChunkId(NodeId(id)), node.into());
+        }
+        assert_eq!(cycle.validate(), Err(ForestError::Cycle(NodeId(100))));
+    }
 }
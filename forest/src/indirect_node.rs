@@ -6,7 +6,9 @@ use std::{iter::Cloned, slice};
 
 use crate::{
     chunk::{Chunk, ChunkId},
-    node_id::{HasId, NodeId},
+    node_id::{HasId, IdOffset, NodeId},
+    payload::PayloadLayout,
+    sync::Shared,
     tree::{Def, Label, NodeData, NodeNav},
     util::{ImHashMap, ImSlice},
 };
@@ -15,14 +17,17 @@ use crate::{
 pub struct IndirectChunk {
     pub def: Def,
     // Payload is often not used, so indirect it to keep the size down.
-    pub payload: Option<Box<im_rc::Vector<u8>>>,
+    pub payload: Option<Box<im::Vector<u8>>>,
+    /// Layout of the payload, which must fit it (see [PayloadLayout::fits]).
+    /// Kept from the schema of nodes split out of a [crate::uniform_chunk::UniformChunk].
+    pub payload_layout: Option<Shared<PayloadLayout>>,
     // TODO: use im::Vector here
     pub traits: ImHashMap<Label, Vec<ChunkId>>,
 }
 
 impl<'a> NodeNav<ChunkId> for &'a IndirectChunk {
     type TTraitChildren = Cloned<slice::Iter<'a, ChunkId>>;
-    type TLabels = Cloned<im_rc::hashmap::Keys<'a, Label, Vec<ChunkId>>>;
+    type TLabels = Cloned<im::hashmap::Keys<'a, Label, Vec<ChunkId>>>;
 
     fn get_traits(&self) -> Self::TLabels {
         self.traits.keys().cloned()
@@ -40,9 +45,13 @@ impl NodeData for IndirectChunk {
         self.def
     }
 
-    fn get_payload(&self) -> Option<ImSlice> {
+    fn get_payload(&self) -> Option<ImSlice<'_>> {
         self.payload.as_ref().map(|p| p.focus())
     }
+
+    fn get_payload_layout(&self) -> Option<&PayloadLayout> {
+        self.payload_layout.as_deref()
+    }
 }
 
 impl IndirectChunk {
@@ -50,6 +59,17 @@ impl IndirectChunk {
     pub fn empty_trait() -> Cloned<slice::Iter<'static, ChunkId>> {
         Self::EMPTY.iter().cloned()
     }
+
+    /// Sets the layout of the payload to `layout`, or to None if it does not fit the payload.
+    pub fn with_layout(mut self, layout: Option<&PayloadLayout>) -> Self {
+        self.payload_layout = match (layout, &self.payload) {
+            (Some(layout), Some(payload)) if layout.fits(payload.len()) => {
+                Some(Shared::new(layout.clone()))
+            }
+            _ => None,
+        };
+        self
+    }
 }
 
 /// View of a BasicNode with an Id.
@@ -75,6 +95,10 @@ impl<'a> Chunk for &'a IndirectChunk {
     fn top_level_nodes(&self, id: NodeId) -> Self::Expander {
         std::iter::once(IndirectNode { node: self, id })
     }
+
+    fn id_extent(&self) -> IdOffset {
+        IdOffset(1)
+    }
 }
 
 impl HasId for IndirectNode<'_> {
@@ -85,7 +109,7 @@ impl HasId for IndirectNode<'_> {
 
 impl<'a> NodeNav<ChunkId> for IndirectNode<'a> {
     type TTraitChildren = Cloned<slice::Iter<'a, ChunkId>>;
-    type TLabels = Cloned<im_rc::hashmap::Keys<'a, Label, Vec<ChunkId>>>;
+    type TLabels = Cloned<im::hashmap::Keys<'a, Label, Vec<ChunkId>>>;
 
     fn get_traits(&self) -> Self::TLabels {
         self.node.get_traits()
@@ -101,7 +125,11 @@ impl NodeData for IndirectNode<'_> {
         self.node.get_def()
     }
 
-    fn get_payload(&self) -> Option<ImSlice> {
+    fn get_payload(&self) -> Option<ImSlice<'_>> {
         self.node.get_payload()
     }
+
+    fn get_payload_layout(&self) -> Option<&PayloadLayout> {
+        self.node.get_payload_layout()
+    }
 }
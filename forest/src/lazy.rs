@@ -0,0 +1,541 @@
+//! Forest which loads its chunks on demand from a [ChunkStore].
+//!
+//! [save] writes each chunk to its own blob (in the [snapshot] format), and an index listing the chunks.
+//! [open] creates a [Forest] with an unloaded placeholder for each chunk in the index.
+//! Placeholders are loaded the first time their content is accessed:
+//! by [forest::Forest::find_node], [LoadChunk::load] on the result of [forest::Forest::find_nodes_from_node],
+//! or when navigating into them with [crate::nav].
+//! Chunks which fail to load look empty to those, so the failures are recorded (see [Forest::load_errors]).
+//! The `try_` methods (like [forest::Forest::try_find_node]) return them instead.
+//!
+//! The index includes the child chunks of each chunk, so parent information can be computed without loading anything.
+//! It also includes a [RangeBloom] of the ids in each tree (each separately loadable subtree),
+//! so trees which don't contain an id can be ruled out without loading them (see [Forest::tree_may_contain]).
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    bloom::RangeBloom,
+    chunk::{Chunk, ChunkId},
+    error::ForestError,
+    forest,
+    indirect::enum_chunk,
+    indirect_nav::{self, LoadChunk},
+    indirect_node::IndirectChunk,
+    node_id::{IdOffset, NodeId},
+    snapshot::{self, put_u128, put_u32, put_u64, Input, SnapshotError},
+    sync::{Lock, OnceCell, Shared},
+    tree::{Label, NodeNav},
+    uniform_chunk::ChunkIterator,
+    util::ImHashMap,
+};
+
+pub type Forest = forest::Forest<LazyChunk>;
+
+/// Key of the blob listing the chunks written by [save].
+pub const INDEX_KEY: &str = "index";
+
+/// Storage for named blobs.
+pub trait ChunkStore {
+    fn read(&self, key: &str) -> io::Result<Vec<u8>>;
+    fn write(&self, key: &str, blob: &[u8]) -> io::Result<()>;
+}
+
+/// [ChunkStore] shared by the chunks loaded from it.
+#[cfg(not(feature = "sync"))]
+pub type SharedStore = Shared<dyn ChunkStore>;
+/// [ChunkStore] shared by the chunks loaded from it, which might be on different threads.
+#[cfg(feature = "sync")]
+pub type SharedStore = Shared<dyn ChunkStore + Send + Sync>;
+
+/// [ChunkStore] which keeps blobs in memory.
+#[derive(Default)]
+pub struct MemoryStore {
+    blobs: Lock<HashMap<String, Vec<u8>>>,
+    reads: AtomicUsize,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of blobs which have been read.
+    pub fn reads(&self) -> usize {
+        self.reads.load(Ordering::Relaxed)
+    }
+}
+
+impl ChunkStore for MemoryStore {
+    fn read(&self, key: &str) -> io::Result<Vec<u8>> {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.blobs
+            .borrow()
+            .get(key)
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, key))
+    }
+
+    fn write(&self, key: &str, blob: &[u8]) -> io::Result<()> {
+        self.blobs
+            .borrow_mut()
+            .insert(key.to_owned(), blob.to_vec());
+        Ok(())
+    }
+}
+
+/// [ChunkStore] which keeps each blob in a file in a directory.
+pub struct DirectoryStore {
+    path: PathBuf,
+}
+
+impl DirectoryStore {
+    /// Uses the directory at `path`, which must already exist.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        DirectoryStore { path: path.into() }
+    }
+}
+
+impl ChunkStore for DirectoryStore {
+    fn read(&self, key: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.path.join(key))
+    }
+
+    fn write(&self, key: &str, blob: &[u8]) -> io::Result<()> {
+        fs::write(self.path.join(key), blob)
+    }
+}
+
+fn chunk_key(id: ChunkId) -> String {
+    format!("{:032x}", id.0 .0)
+}
+
+/// Chunk in a [Forest], which might not be loaded yet.
+#[derive(Clone)]
+pub enum LazyChunk {
+    Loaded(enum_chunk::Chunk),
+    Unloaded(Shared<Placeholder>),
+}
+
+/// Unloaded placeholders are only equal to themselves, so replacing one with its content counts as a change.
+impl PartialEq for LazyChunk {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (LazyChunk::Loaded(a), LazyChunk::Loaded(b)) => a == b,
+            (LazyChunk::Unloaded(a), LazyChunk::Unloaded(b)) => Shared::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+/// Chunk in a [ChunkStore], which is loaded on first use.
+pub struct Placeholder {
+    id: ChunkId,
+    id_extent: IdOffset,
+    /// Child chunks, so parents can be found without loading.
+    traits: ImHashMap<Label, Vec<ChunkId>>,
+    /// Ids in the tree under this chunk. Only for the roots of trees.
+    tree_ids: Option<RangeBloom>,
+    store: SharedStore,
+    chunk: OnceCell<enum_chunk::Chunk>,
+    /// Error from the last load, if it failed.
+    error: Lock<Option<ForestError>>,
+}
+
+impl Placeholder {
+    pub fn is_loaded(&self) -> bool {
+        self.chunk.get().is_some()
+    }
+
+    /// Filter of the ids in the tree under this chunk, if it is the root of a tree.
+    pub fn tree_ids(&self) -> Option<&RangeBloom> {
+        self.tree_ids.as_ref()
+    }
+
+    /// Loads the chunk if it is not already loaded.
+    /// Failed loads are not cached, so they are retried next time, but the error is kept until then (see [Self::load_error]).
+    pub fn load(&self) -> Result<&enum_chunk::Chunk, ForestError> {
+        if let Some(chunk) = self.chunk.get() {
+            return Ok(chunk);
+        }
+        let result = self.fetch_checked();
+        *self.error.borrow_mut() = result.as_ref().err().cloned();
+        let chunk = result?;
+        Ok(self.chunk.get_or_init(|| chunk))
+    }
+
+    /// Error from the last attempt to load the chunk, if it failed.
+    pub fn load_error(&self) -> Option<ForestError> {
+        self.error.borrow().clone()
+    }
+
+    fn fetch_checked(&self) -> Result<enum_chunk::Chunk, ForestError> {
+        let chunk = self.fetch().map_err(|_| ForestError::LoadFailed(self.id))?;
+        // The forest was built from the index, so the chunk has to agree with it.
+        let traits = match &chunk {
+            enum_chunk::Chunk::Indirect(c) => c.traits.clone(),
+            enum_chunk::Chunk::Uniform(_) => ImHashMap::default(),
+        };
+        if (&chunk).id_extent() != self.id_extent || traits != self.traits {
+            return Err(ForestError::SchemaMismatch(self.id));
+        }
+        Ok(chunk)
+    }
+
+    fn fetch(&self) -> Result<enum_chunk::Chunk, SnapshotError> {
+        let blob = self.store.read(&chunk_key(self.id))?;
+        let mut chunks = snapshot::read_chunks(&mut blob.as_slice())?;
+        match chunks.pop() {
+            Some((id, chunk)) if id == self.id && chunks.is_empty() => Ok(chunk),
+            _ => Err(SnapshotError::Malformed(
+                "blob does not contain the expected chunk",
+            )),
+        }
+    }
+}
+
+impl LoadChunk for LazyChunk {
+    fn load(&self) -> Result<&enum_chunk::Chunk, ForestError> {
+        match self {
+            LazyChunk::Loaded(chunk) => Ok(chunk),
+            LazyChunk::Unloaded(placeholder) => placeholder.load(),
+        }
+    }
+}
+
+/// Loads the chunk when its content is needed.
+/// If loading fails, the chunk is treated as empty and the error is recorded (see [Forest::load_errors]).
+impl<'a> Chunk for &'a LazyChunk {
+    type View = enum_chunk::Node<'a>;
+    type Child = enum_chunk::Child<'a>;
+    type Expander = enum_chunk::Expander<'a>;
+
+    fn get(&self, first_id: NodeId, id: NodeId) -> Option<enum_chunk::Node<'a>> {
+        LoadChunk::load(*self).ok()?.get(first_id, id)
+    }
+
+    fn top_level_nodes(&self, id: NodeId) -> Self::Expander {
+        match LoadChunk::load(*self) {
+            Ok(chunk) => chunk.top_level_nodes(id),
+            Err(_) => enum_chunk::Expander::Uniform(ChunkIterator::Empty),
+        }
+    }
+
+    fn id_extent(&self) -> IdOffset {
+        match *self {
+            LazyChunk::Loaded(chunk) => chunk.id_extent(),
+            LazyChunk::Unloaded(placeholder) => placeholder.id_extent,
+        }
+    }
+}
+
+/// For parent info: does not load the chunk.
+impl<'a> NodeNav<ChunkId> for &'a LazyChunk {
+    type TTraitChildren = enum_chunk::ChunkTraitIterator<'a>;
+    type TLabels = enum_chunk::ChunkLabelIterator<'a>;
+
+    fn get_traits(&self) -> Self::TLabels {
+        match *self {
+            LazyChunk::Loaded(chunk) => chunk.get_traits(),
+            LazyChunk::Unloaded(placeholder) => placeholder.traits.keys().cloned().into(),
+        }
+    }
+
+    fn get_trait(&self, label: Label) -> Self::TTraitChildren {
+        match *self {
+            LazyChunk::Loaded(chunk) => chunk.get_trait(label),
+            LazyChunk::Unloaded(placeholder) => placeholder
+                .traits
+                .get(&label)
+                .map_or(IndirectChunk::empty_trait(), |x| x.iter().cloned())
+                .into(),
+        }
+    }
+}
+
+impl Forest {
+    /// Errors from the chunks whose last load failed, in chunk id order.
+    ///
+    /// Navigation which can't return errors (like [forest::Forest::find_node] and [crate::nav]) treats these chunks as empty,
+    /// so check this after using it to tell missing data from lost data.
+    pub fn load_errors(&self) -> Vec<ForestError> {
+        self.map_keys()
+            .filter_map(|id| match self.find_nodes(*id) {
+                Some(LazyChunk::Unloaded(placeholder)) => placeholder.load_error(),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Checks if `id` might be in the tree under `root` without loading anything.
+    /// Returns true if it is not known, for example because `root` is not the root of a tree in the index.
+    pub fn tree_may_contain(&self, root: ChunkId, id: NodeId) -> bool {
+        match self.find_nodes(root) {
+            Some(LazyChunk::Unloaded(placeholder)) => placeholder
+                .tree_ids()
+                .is_none_or(|filter| filter.may_contain(id)),
+            _ => true,
+        }
+    }
+}
+
+/// Writes each chunk of `forest` to its own blob in `store`, followed by the index used by [open].
+pub fn save(forest: &indirect_nav::Forest, store: &dyn ChunkStore) -> io::Result<()> {
+    let parents = forest.get_parent_data();
+    let mut index = vec![];
+    put_u32(&mut index, snapshot::VERSION);
+    put_u64(&mut index, forest.map_keys().count() as u64);
+    for id in forest.map_keys() {
+        let chunk = forest.find_nodes(*id).unwrap();
+        let mut blob = vec![];
+        snapshot::write_chunks([(*id, chunk)], &mut blob)?;
+        store.write(&chunk_key(*id), &blob)?;
+
+        put_u128(&mut index, id.0 .0);
+        put_u32(&mut index, chunk.id_extent().0);
+        let mut labels: Vec<Label> = chunk.get_traits().collect();
+        labels.sort();
+        put_u32(&mut index, labels.len() as u32);
+        for label in labels {
+            put_u128(&mut index, label.0);
+            let children: Vec<ChunkId> = chunk.get_trait(label).collect();
+            put_u32(&mut index, children.len() as u32);
+            for child in children {
+                put_u128(&mut index, child.0 .0);
+            }
+        }
+        if parents.contains_key(id) {
+            index.push(0);
+        } else {
+            index.push(1);
+            RangeBloom::for_tree(forest, *id).write(&mut index);
+        }
+    }
+    store.write(INDEX_KEY, &index)
+}
+
+/// Opens a forest written by [save], without loading any of its chunks.
+pub fn open(store: SharedStore) -> Result<Forest, SnapshotError> {
+    let blob = store.read(INDEX_KEY)?;
+    let mut input = Input { data: &blob };
+    // The index is the same in all versions: only the chunks differ.
+    input.version()?;
+
+    let mut forest = Forest::new();
+    for _ in 0..input.u64()? {
+        let id = input.chunk_id()?;
+        let id_extent = IdOffset(input.u32()?);
+        let mut traits = ImHashMap::default();
+        for _ in 0..input.u32()? {
+            let label = Label(input.u128()?);
+            let children = (0..input.u32()?)
+                .map(|_| input.u128().map(|id| ChunkId(NodeId(id))))
+                .collect::<Result<Vec<_>, _>>()?;
+            if traits.insert(label, children).is_some() {
+                return Err(SnapshotError::Malformed("duplicate trait"));
+            }
+        }
+        let tree_ids = if input.flag()? {
+            Some(RangeBloom::read(&mut input)?)
+        } else {
+            None
+        };
+        let placeholder = Placeholder {
+            id,
+            id_extent,
+            traits,
+            tree_ids,
+            store: store.clone(),
+            chunk: OnceCell::new(),
+            error: Lock::default(),
+        };
+        forest.try_insert(id, LazyChunk::Unloaded(Shared::new(placeholder)))?;
+    }
+    if !input.data.is_empty() {
+        return Err(SnapshotError::Malformed("trailing data"));
+    }
+    forest.validate()?;
+    Ok(forest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        chunker,
+        example_node::BasicNode,
+        node_id::HasId,
+        test_stuff::{check_parents, walk_all, wide_tree},
+        tree::{Def, NodeData},
+        ShapeLibrary,
+    };
+
+    /// Root with 3 uniform chunks (at 1, 13 and 25), each a node with 10 bytes under it, separated by indirect nodes.
+    fn tree() -> (indirect_nav::Forest, NodeId) {
+        let node = |def: u128, payload: u8, children: Vec<BasicNode>| BasicNode {
+            id: NodeId(0),
+            def: Def(def),
+            payload: Some(im::vector![payload]),
+            traits: [(Label(1), children)]
+                .into_iter()
+                .filter(|(_, c)| !c.is_empty())
+                .collect(),
+        };
+        let run = || (0..10).map(|i| node(3, i, vec![])).collect();
+        let tree = node(
+            1,
+            0,
+            vec![
+                node(2, 0, run()),
+                node(4, 0, vec![]),
+                node(2, 0, run()),
+                node(4, 0, vec![]),
+                node(2, 0, run()),
+            ],
+        );
+        let mut forest = indirect_nav::Forest::new();
+        let chunks = chunker::chunk(
+            &chunker::Analyzed::new(&tree),
+            NodeId(0),
+            &mut ShapeLibrary::default(),
+        );
+        for (id, chunk) in chunks {
+            forest.insert(id, chunk);
+        }
+        (forest, NodeId(0))
+    }
+
+    fn loaded(forest: &Forest) -> usize {
+        forest
+            .map_keys()
+            .filter(|id| match forest.find_nodes(**id).unwrap() {
+                LazyChunk::Loaded(_) => true,
+                LazyChunk::Unloaded(placeholder) => placeholder.is_loaded(),
+            })
+            .count()
+    }
+
+    #[test]
+    fn loads_on_demand() {
+        let (tree, root) = tree();
+        let store = Shared::new(MemoryStore::new());
+        save(&tree, store.as_ref()).unwrap();
+        let forest = open(store.clone()).unwrap();
+        assert_eq!(forest.map_keys().count(), 6);
+        assert_eq!(store.reads(), 1);
+
+        // Parents are known without loading.
+        assert_eq!(forest.get_parent_data().len(), 5);
+        assert_eq!(loaded(&forest), 0);
+
+        // Byte 5 of the second run.
+        let node = forest.find_node(NodeId(13 + 1 + 5)).unwrap();
+        assert_eq!(node.get_def(), Def(3));
+        assert_eq!(loaded(&forest), 1);
+        let (chunk_id, chunk) = forest.find_nodes_from_node(NodeId(24)).unwrap();
+        assert_eq!(*chunk_id, ChunkId(NodeId(24)));
+        assert!(matches!(
+            chunk.load().unwrap(),
+            enum_chunk::Chunk::Indirect(_)
+        ));
+        assert_eq!(loaded(&forest), 2);
+
+        let parent = forest.try_get_parent(&node).unwrap().unwrap();
+        assert_eq!(parent.node.get_id(), NodeId(13));
+        assert_eq!(loaded(&forest), 2);
+
+        let nav = forest.nav_from(root).unwrap();
+        assert_eq!(walk_all(nav.clone()), 1 + 3 * 11 + 2);
+        assert_eq!(loaded(&forest), 6);
+        assert_eq!(store.reads(), 7);
+        check_parents(nav);
+    }
+
+    #[test]
+    fn tree_filter() {
+        let (mut tree, root) = tree();
+        let other = tree.find_nodes(ChunkId(NodeId(24))).unwrap().clone();
+        tree.insert(ChunkId(NodeId(1000)), other);
+        let store = Shared::new(MemoryStore::new());
+        save(&tree, store.as_ref()).unwrap();
+        let forest = open(store.clone()).unwrap();
+
+        assert!((0..36).all(|i| forest.tree_may_contain(ChunkId(root), NodeId(i))));
+        assert!(forest.tree_may_contain(ChunkId(NodeId(1000)), NodeId(1000)));
+        assert!(!forest.tree_may_contain(ChunkId(NodeId(1000)), NodeId(5)));
+        // Not a root, so unknown.
+        assert!(forest.tree_may_contain(ChunkId(NodeId(1)), NodeId(5000)));
+        assert_eq!(loaded(&forest), 0);
+    }
+
+    #[test]
+    fn directory_store() {
+        let (tree, root) = tree();
+        let path = std::env::temp_dir().join(format!("forest-lazy-{:x}", rand::random::<u64>()));
+        fs::create_dir(&path).unwrap();
+        let store = DirectoryStore::new(&path);
+        save(&tree, &store).unwrap();
+
+        let forest = open(Shared::new(store)).unwrap();
+        let node = forest.find_node(NodeId(35)).unwrap();
+        assert_eq!(*node.get_payload().unwrap().get(0).unwrap(), 9);
+        assert_eq!(walk_all(forest.nav_from(root).unwrap()), 1 + 3 * 11 + 2);
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn opens_v1() {
+        let (tree, root) = wide_tree(3);
+        let store = Shared::new(MemoryStore::new());
+        save(&tree, store.as_ref()).unwrap();
+        // Without uniform chunks or payloads, version 1 blobs only differ in their version,
+        // and in their chunk not having a payload layout flag.
+        for (key, blob) in store.blobs.borrow_mut().iter_mut() {
+            if key == INDEX_KEY {
+                blob[0..4].copy_from_slice(&1u32.to_le_bytes());
+                continue;
+            }
+            blob[4..8].copy_from_slice(&1u32.to_le_bytes());
+            // After the magic, version, def table (with one def), label table, schema count, chunk count,
+            // and the chunk's id, type, def and payload flag.
+            let labels = u32::from_le_bytes(blob[28..32].try_into().unwrap()) as usize;
+            let flag = 32 + 16 * labels + 4 + 8 + 16 + 1 + 4 + 1;
+            assert_eq!(blob.remove(flag), 0);
+        }
+
+        let forest = open(store).unwrap();
+        assert_eq!(walk_all(forest.nav_from(root).unwrap()), 4);
+        assert!(forest.load_errors().is_empty());
+    }
+
+    #[test]
+    fn missing_chunk() {
+        let (tree, root) = tree();
+        let store = Shared::new(MemoryStore::new());
+        save(&tree, store.as_ref()).unwrap();
+        store
+            .blobs
+            .borrow_mut()
+            .remove(&chunk_key(ChunkId(NodeId(1))));
+
+        let forest = open(store).unwrap();
+        let failed = ForestError::LoadFailed(ChunkId(NodeId(1)));
+        assert_eq!(forest.try_find_node(NodeId(2)).err(), Some(failed.clone()));
+        assert!(forest.try_find_node(NodeId(5000)).unwrap().is_none());
+        let root_node = forest.try_find_node(root).unwrap().unwrap();
+        assert_eq!(
+            forest.try_trait_get(&root_node, Label(1), 0).err(),
+            Some(failed.clone())
+        );
+
+        // Navigation which can't report errors skips the chunk, but records the failure.
+        assert!(forest.find_node(NodeId(2)).is_none());
+        assert_eq!(walk_all(forest.nav_from(root).unwrap()), 1 + 2 * 11 + 2);
+        assert_eq!(forest.load_errors(), vec![failed]);
+    }
+}
@@ -1,7 +1,7 @@
 /*!
 Experimental Copy On Write Tree in the style of [Fluid's experimental tree](https://github.com/microsoft/FluidFramework/tree/main/experimental/dds/tree#tree-abstraction).
 
-This prototypes a forest using [im_rc::OrdMap] which allows with compressed sequences via [uniform_chunk],
+This prototypes a forest using [im::OrdMap] which allows with compressed sequences via [uniform_chunk],
 as well as a general architectural pattern for all of this with low coupling and a nice API in (See [nav]).
 
 This design was done with virtualization (only loading a subset of the tree on demand) in mind, however none has been implemented yet.
@@ -24,109 +24,1666 @@ or to virtualize the logical tree, and load chunks of it into the Forest.
                 - Maybe use conservative updates (skip regenerating just to do deletes sometimes)
 */
 
-use nav::Resolver;
-use node_id::HasId;
-use tree::ParentInfo;
+use std::{collections::HashSet, hash::BuildHasher};
+
+use chunk::{Chunk, ChunkId};
+use error::ForestError;
+use forest::ChildEdit;
+use indirect::enum_chunk;
+use indirect_node::IndirectChunk;
+use node_id::{HasId, IdOffset};
+use rand::Rng;
+use sync::{Lock, Shared, Weak};
+use tree::{IdBase, NodeData, NodeNav, ParentInfo};
+use uniform_chunk::{ChunkSchema, RootChunkSchema, UniformChunk};
+use util::ImHashMap;
+
+pub use node_id::NodeId;
+pub use tree::{Def, Label};
 
 extern crate derive_more;
-extern crate im_rc;
+#[cfg(feature = "sync")]
+pub extern crate im;
+#[cfg(not(feature = "sync"))]
+pub extern crate im_rc as im;
 extern crate num_integer;
 #[macro_use]
 extern crate macro_rules_attribute;
 
-mod chunk;
-mod example_node;
-mod forest;
-mod indirect;
-mod indirect_nav;
-mod indirect_node;
-mod nav;
-mod node_id;
-mod tree;
-mod uniform_chunk;
-mod util;
+pub mod bloom;
+pub mod changeset;
+pub mod chunk;
+pub mod chunker;
+pub mod diff;
+pub mod error;
+pub mod example_node;
+pub mod forest;
+pub mod history;
+pub mod indirect;
+pub mod indirect_nav;
+pub mod indirect_node;
+pub mod lazy;
+pub mod nav;
+pub mod nav_mut;
+pub mod node_id;
+pub mod payload;
+pub mod schema;
+pub mod snapshot;
+pub mod sync;
+pub mod tree;
+pub mod uniform_chunk;
+pub mod util;
 
 #[macro_use]
 pub mod enum_node;
 pub mod test_stuff;
 
 /// A forest (collection of trees) that that can optionally compression sections using [uniform_chunk]s.
+#[derive(Clone)]
 pub struct Forest {
     forest: indirect_nav::Forest,
-    shapes: std::rc::Rc<ShapeLibrary>,
+    /// Shared between clones of the forest, so snapshots can share schema too.
+    /// Shapes which are no longer used by any clone are dropped (see [ShapeLibrary::prune]).
+    shapes: Shared<Lock<ShapeLibrary>>,
 }
 
 /// Unique identifier for a particular tree shape.
-struct ShapeId(u128);
+/// Content hash of its [ChunkSchema].
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct ShapeId(u128);
 
-struct ShapeLibrary {
-    // TODO: could use something like  weak_table::WeakValueHashMap if we don't want this to grow forever.
-    map: std::collections::HashMap<ShapeId, uniform_chunk::RootChunkSchema>,
+impl ShapeId {
+    pub fn new(schema: &ChunkSchema) -> Self {
+        let hash = |seed| ahash::RandomState::with_seeds(seed, 1, 2, 3).hash_one(schema) as u128;
+        ShapeId(hash(0) << 64 | hash(1))
+    }
 }
 
-// Idea:
-// Add getter for forest/resolver (generic type) to nav/tree (specifically indirect nav).
-// Use this to add owning wrapper (that adds no fields) to allow editing by calling back into the forest (nav would have a mut forest in this case? Ref cell? COW ref cel?).
-// Maybe do copy on write instead?
+/// Interns schema, so that all [UniformChunk]s with the same shape share one [RootChunkSchema].
+/// This is required for [UniformChunk]'s equality (which compares schema by reference) to be accurate.
+///
+/// Shapes are held weakly, so ones no longer used by any chunk are dropped.
+/// Their entries are removed by [Self::prune], which runs whenever the number of entries doubles.
+#[derive(Default)]
+pub struct ShapeLibrary {
+    map: std::collections::HashMap<ShapeId, Weak<RootChunkSchema>>,
+    /// Number of entries at which adding another runs [Self::prune].
+    prune_at: usize,
+}
+
+impl ShapeLibrary {
+    /// Fewest entries [ShapeLibrary::prune] leaves room for.
+    const MIN_PRUNE_AT: usize = 64;
+
+    /// Gets the shared [RootChunkSchema] for `schema`, creating it if needed.
+    pub fn intern(&mut self, schema: ChunkSchema) -> Shared<RootChunkSchema> {
+        let id = ShapeId::new(&schema);
+        match self.get(id) {
+            Some(existing) if existing.schema == schema => existing,
+            // Hash collision: leave this schema un-deduplicated.
+            Some(_) => Shared::new(RootChunkSchema::new(schema)),
+            None => self.insert(id, Shared::new(RootChunkSchema::new(schema))),
+        }
+    }
+
+    /// Gets the shared equivalent of `schema`, adding `schema` to the library if there is none.
+    pub fn intern_root(&mut self, schema: Shared<RootChunkSchema>) -> Shared<RootChunkSchema> {
+        let id = ShapeId::new(&schema.schema);
+        match self.get(id) {
+            Some(existing) if existing.schema == schema.schema => existing,
+            Some(_) => schema,
+            None => self.insert(id, schema),
+        }
+    }
+
+    /// Removes the entries of shapes which are no longer used.
+    pub fn prune(&mut self) {
+        self.map.retain(|_, shape| shape.strong_count() > 0);
+        self.prune_at = (self.map.len() * 2).max(Self::MIN_PRUNE_AT);
+    }
+
+    /// Number of distinct shapes in use.
+    pub fn len(&self) -> usize {
+        self.map
+            .values()
+            .filter(|shape| shape.strong_count() > 0)
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Gets the shape with this id, if it is still in use.
+    fn get(&self, id: ShapeId) -> Option<Shared<RootChunkSchema>> {
+        self.map.get(&id).and_then(Weak::upgrade)
+    }
+
+    fn insert(&mut self, id: ShapeId, schema: Shared<RootChunkSchema>) -> Shared<RootChunkSchema> {
+        if self.map.len() >= self.prune_at {
+            self.prune();
+        }
+        self.map.insert(id, Shared::downgrade(&schema));
+        schema
+    }
+}
 
 impl Forest {
-    pub fn get_tree(&self, id: node_id::NodeId) -> Option<indirect::enum_chunk::Node> {
+    pub fn new() -> Self {
+        Forest {
+            forest: indirect_nav::Forest::new(),
+            shapes: Shared::default(),
+        }
+    }
+
+    pub fn get_tree(&self, id: node_id::NodeId) -> Option<indirect::enum_chunk::Node<'_>> {
         self.forest.find_node(id)
     }
 
-    pub fn insert_or_replace_node(&mut self, node: impl tree::Node<node_id::NodeId> + HasId) {
-        todo!()
-        // Split chunks, rechunk etc.
+    /// Inserts `node`, replacing the existing node with the same id if there is one.
+    ///
+    /// Children are referenced by id and must already be in the forest.
+    /// They are detached from their current parents (if any) and parented under `node`.
+    /// Children of a replaced node which are not children of `node` are left in the forest as roots.
+    ///
+    /// If `node` or its children are within a [UniformChunk], the chunk is split as needed.
+    /// Fails with [ForestError::Cycle] if a child is `node` or one of its ancestors,
+    /// and with [ForestError::MultipleParents] if a child is listed more than once.
+    pub fn insert_or_replace_node(
+        &mut self,
+        node: impl tree::Node<node_id::NodeId> + HasId,
+    ) -> Result<(), ForestError> {
+        let mut seen = HashSet::new();
+        for label in node.get_traits() {
+            for child in node.get_trait(label) {
+                if !seen.insert(child) {
+                    return Err(ForestError::MultipleParents(ChunkId(child)));
+                }
+            }
+        }
+
+        self.transaction(|forest| {
+            let id = node.get_id();
+            let exists = forest.forest.find_node(id).is_some();
+            if exists {
+                forest.make_indirect(id)?;
+            } else if let Some((chunk_id, enum_chunk::Chunk::Uniform(chunk))) =
+                forest.forest.find_nodes_from_node(id)
+            {
+                if id < chunk_id.0 + chunk.id_extent() {
+                    return Err(ForestError::OverlappingChunks(*chunk_id, ChunkId(id)));
+                }
+            }
+
+            let mut traits = ImHashMap::default();
+            for label in node.get_traits() {
+                let mut children = vec![];
+                for child in node.get_trait(label) {
+                    if exists {
+                        forest.check_not_ancestor(child, id)?;
+                    } else if child == id {
+                        return Err(ForestError::Cycle(id));
+                    }
+                    forest.split_out(child)?;
+                    forest.replace_in_parent(ChunkId(child), &[])?;
+                    children.push(ChunkId(child));
+                }
+                if !children.is_empty() {
+                    traits.insert(label, children);
+                }
+            }
+
+            let payload = node
+                .get_payload()
+                .map(|p| Box::new(p.into_iter().cloned().collect()));
+            forest.forest.insert(
+                ChunkId(id),
+                IndirectChunk {
+                    def: node.get_def(),
+                    payload,
+                    payload_layout: None,
+                    traits,
+                }
+                .with_layout(node.get_payload_layout())
+                .into(),
+            );
+            Ok(())
+        })
     }
 
-    pub fn delete_node(&mut self, id: node_id::NodeId) {
-        todo!()
-        // Split chunks, rechunk etc.
-        // Return success or error has parent
+    /// Deletes a node which has no children, detaching it from its parent.
+    pub fn delete_node(&mut self, id: node_id::NodeId) -> Result<(), ForestError> {
+        let node = self.get_tree(id).ok_or(ForestError::UnknownId(id))?;
+        if node
+            .get_traits()
+            .any(|label| node.get_trait(label).next().is_some())
+        {
+            return Err(ForestError::HasChildren(id));
+        }
+        self.delete_subtree(id)
     }
 
+    /// Gets the parent of a node, or None if the node is a root.
     pub fn get_parent(
         &self,
         id: node_id::NodeId,
-    ) -> Option<Option<ParentInfo<indirect::enum_chunk::Node>>> {
-        let info = (&(self.forest)).get_parent(self.get_tree(id).as_ref()?);
-        Some(info)
-        // todo!("return better value for missing node vs root")
+    ) -> Result<Option<ParentInfo<indirect::enum_chunk::Node<'_>>>, ForestError> {
+        let node = self.get_tree(id).ok_or(ForestError::UnknownId(id))?;
+        self.forest.try_get_parent(&node)
+    }
+
+    /// Gets the node reached from `root` by taking child `index` of trait `label` for each step of `path`.
+    pub fn resolve_path(
+        &self,
+        root: node_id::NodeId,
+        path: &[(Label, usize)],
+    ) -> Option<indirect::enum_chunk::Node<'_>> {
+        self.forest.resolve_path(root, path)
+    }
+
+    /// Gets the path from the root of the tree containing `id` to it (see [Self::resolve_path]).
+    pub fn path_of(&self, id: node_id::NodeId) -> Result<Vec<(Label, usize)>, ForestError> {
+        self.forest.path_of(id)
     }
 }
 
 /// Non-minimal functionality
 impl Forest {
-    /// Inserts a tree, allocating it's ids arbitrarily
+    /// Writes a binary snapshot of the forest (see [snapshot]).
+    pub fn write_snapshot(&self, out: &mut impl std::io::Write) -> std::io::Result<()> {
+        snapshot::write(&self.forest, out)
+    }
+
+    /// Loads a forest from a snapshot written by [Forest::write_snapshot].
+    pub fn read_snapshot(
+        input: &mut impl std::io::Read,
+    ) -> Result<Forest, snapshot::SnapshotError> {
+        Ok(snapshot::read(input)?.into())
+    }
+
+    /// Inserts a tree, allocating it's ids arbitrarily (the ids in `tree` are ignored).
+    ///
+    /// Ids are allocated sequentially, so runs of identically shaped subtrees can be stored in [UniformChunk]s.
+    /// Returns the id of the root.
     pub fn insert_tree(&mut self, tree: example_node::BasicNode) -> node_id::NodeId {
-        todo!()
-        // heuristically chunk
+        let tree = chunker::Analyzed::new(&tree);
+        let root = self.allocate_ids(tree.size);
+        let chunks = chunker::chunk(&tree, root, &mut self.shapes.borrow_mut());
+        for (id, chunk) in chunks {
+            self.forest.insert(id, chunk);
+        }
+        root
     }
 
     /// Insert a tree, keeping chunks as is.
-    pub fn insert_chunked() {
-        todo!()
-        // dedup shapes
+    /// Schema of [UniformChunk]s are deduplicated with existing ones.
+    ///
+    /// Fails without modifying the forest if any chunk does not match its schema, or overlaps another chunk.
+    pub fn insert_chunked(
+        &mut self,
+        chunks: impl IntoIterator<Item = (ChunkId, enum_chunk::Chunk)>,
+    ) -> Result<(), ForestError> {
+        self.transaction(|forest| {
+            let mut shapes = forest.shapes.borrow_mut();
+            for (id, mut chunk) in chunks {
+                if let enum_chunk::Chunk::Uniform(c) = &mut chunk {
+                    let schema = &c.schema.schema;
+                    if c.data.len() != (schema.node_count * schema.bytes_per_node) as usize {
+                        return Err(ForestError::SchemaMismatch(id));
+                    }
+                    c.schema = shapes.intern_root(c.schema.clone());
+                }
+                forest.forest.try_insert(id, chunk)?;
+            }
+            Ok(())
+        })
     }
 
-    pub fn set_value(&mut self, id: node_id::NodeId, value: &[u8]) {
-        todo!()
+    /// Sets the payload of a node.
+    ///
+    /// Nodes in a [UniformChunk] are updated in place (copy on write, `O(log(n))` in the size of the chunk)
+    /// if `value` has the payload size of their schema.
+    /// Otherwise the node is split out of the chunk into an [IndirectChunk],
+    /// which keeps the node's [payload::PayloadLayout] if it fits `value`.
+    pub fn set_value(&mut self, id: node_id::NodeId, value: &[u8]) -> Result<(), ForestError> {
+        let (chunk_id, chunk) = self
+            .forest
+            .find_nodes_from_node(id)
+            .ok_or(ForestError::UnknownId(id))?;
+        let chunk_id = *chunk_id;
+        if let enum_chunk::Chunk::Uniform(c) = chunk {
+            let info = c
+                .schema
+                .lookup_schema(chunk_id.0, id)
+                .ok_or(ForestError::UnknownId(id))?;
+            if info.schema.payload_size.map(usize::from) == Some(value.len()) {
+                let byte_offset = info.byte_offset as usize;
+                self.forest.edit(
+                    chunk_id,
+                    |chunk| {
+                        if let enum_chunk::Chunk::Uniform(c) = chunk {
+                            for (i, byte) in value.iter().enumerate() {
+                                c.data.set(byte_offset + i, *byte);
+                            }
+                        }
+                        ((), vec![])
+                    },
+                    indirect_nav::Forest::top_level_count,
+                );
+                return Ok(());
+            }
+        } else if chunk_id.0 != id {
+            return Err(ForestError::UnknownId(id));
+        }
+
+        self.transaction(|forest| {
+            forest.make_indirect(id)?;
+            forest
+                .forest
+                .edit(
+                    ChunkId(id),
+                    |chunk| match chunk {
+                        enum_chunk::Chunk::Indirect(node) => {
+                            node.payload = Some(Box::new(value.iter().cloned().collect()));
+                            if !node
+                                .get_payload_layout()
+                                .is_some_and(|l| l.fits(value.len()))
+                            {
+                                node.payload_layout = None;
+                            }
+                            (Ok(()), vec![])
+                        }
+                        _ => (Err(ForestError::SchemaMismatch(ChunkId(id))), vec![]),
+                    },
+                    indirect_nav::Forest::top_level_count,
+                )
+                .unwrap_or(Err(ForestError::SchemaMismatch(ChunkId(id))))
+        })
     }
 
-    pub fn replace_node_chunked(&mut self, id: node_id::NodeId) {
-        todo!()
+    /// Sets the payload of a node to `value` (see [payload::Payload] and [Forest::set_value]).
+    pub fn set_payload_from<T: payload::Payload>(
+        &mut self,
+        id: node_id::NodeId,
+        value: &T,
+    ) -> Result<(), ForestError> {
+        self.set_value(id, &value.to_bytes())
+    }
+
+    /// Sets the field `name` of a node's payload, using the node's [payload::PayloadLayout].
+    pub fn set_payload_field(
+        &mut self,
+        id: node_id::NodeId,
+        name: &str,
+        value: &payload::Value,
+    ) -> Result<(), ForestError> {
+        let node = self.get_tree(id).ok_or(ForestError::UnknownId(id))?;
+        let invalid = |error| ForestError::InvalidPayload(id, error);
+        let field = node
+            .get_payload_layout()
+            .and_then(|layout| layout.field(name))
+            .ok_or_else(|| invalid(payload::PayloadError::UnknownField(name.to_owned())))?;
+        let mut bytes = payload::to_vec(
+            node.get_payload()
+                .ok_or(invalid(payload::PayloadError::Missing))?,
+        );
+        field.encode(value, &mut bytes).map_err(invalid)?;
+        self.set_value(id, &bytes)
+    }
+
+    /// Inserts `tree` (allocating its ids like [Self::insert_tree]) as child `index` of trait `label` of `parent`.
+    /// Returns the id of its root.
+    pub fn insert_child(
+        &mut self,
+        parent: node_id::NodeId,
+        label: Label,
+        index: usize,
+        tree: example_node::BasicNode,
+    ) -> Result<node_id::NodeId, ForestError> {
+        self.transaction(|forest| {
+            let root = forest.insert_tree(tree);
+            forest.attach(parent, label, index, root)?;
+            Ok(root)
+        })
+    }
+
+    /// Moves the subtree under `id` to be child `index` of trait `label` of `new_parent`.
+    /// `index` is the position after `id` has been removed from its current parent (if any).
+    ///
+    /// Nodes keep their ids. Chunks are split as needed.
+    /// Fails with [ForestError::Cycle] if `new_parent` is `id` or under it.
+    pub fn move_subtree(
+        &mut self,
+        id: node_id::NodeId,
+        new_parent: node_id::NodeId,
+        label: Label,
+        index: usize,
+    ) -> Result<(), ForestError> {
+        self.get_tree(id).ok_or(ForestError::UnknownId(id))?;
+        self.check_not_ancestor(id, new_parent)?;
+
+        self.transaction(|forest| {
+            forest.split_out(id)?;
+            forest.replace_in_parent(ChunkId(id), &[])?;
+            forest.attach(new_parent, label, index, id)
+        })
+    }
+
+    /// Fails with [ForestError::Cycle] if `id` is `node` or one of its ancestors.
+    fn check_not_ancestor(
+        &self,
+        id: node_id::NodeId,
+        node: node_id::NodeId,
+    ) -> Result<(), ForestError> {
+        let mut ancestor = Some(self.get_tree(node).ok_or(ForestError::UnknownId(node))?);
+        while let Some(node) = ancestor {
+            if node.get_id() == id {
+                return Err(ForestError::Cycle(id));
+            }
+            ancestor = self.forest.try_get_parent(&node)?.map(|p| p.node);
+        }
+        Ok(())
     }
 
-    pub fn update_chunk(
+    /// Adds `child`, which must be the only top level node in its chunk and have no parent,
+    /// as child `index` of trait `label` of `parent`.
+    fn attach(
+        &mut self,
+        parent: node_id::NodeId,
+        label: Label,
+        index: usize,
+        child: node_id::NodeId,
+    ) -> Result<(), ForestError> {
+        let len = self.trait_len(parent, label)?;
+        // The node the child is inserted before, if any.
+        let next = match index.cmp(&len) {
+            std::cmp::Ordering::Less => Some(self.trait_child(parent, label, index)?),
+            std::cmp::Ordering::Equal => None,
+            std::cmp::Ordering::Greater => {
+                return Err(ForestError::IndexOutOfRange {
+                    parent,
+                    label,
+                    index,
+                })
+            }
+        };
+
+        self.make_indirect(parent)?;
+        if let Some(next) = next {
+            self.split_out(next)?;
+        }
+        let mismatch = ForestError::SchemaMismatch(ChunkId(parent));
+        self.forest
+            .edit(
+                ChunkId(parent),
+                |chunk| match chunk {
+                    enum_chunk::Chunk::Indirect(node) => {
+                        let children = node.traits.entry(label).or_default();
+                        let position = match next {
+                            Some(next) => match children.iter().position(|c| *c == ChunkId(next)) {
+                                Some(position) => position,
+                                None => return (Err(mismatch), vec![]),
+                            },
+                            None => children.len(),
+                        };
+                        children.insert(position, ChunkId(child));
+                        let edit = ChildEdit {
+                            label,
+                            index: position,
+                            removed: vec![],
+                            added: vec![ChunkId(child)],
+                        };
+                        (Ok(()), vec![edit])
+                    }
+                    _ => (Err(mismatch), vec![]),
+                },
+                indirect_nav::Forest::top_level_count,
+            )
+            .unwrap_or(Err(ForestError::SchemaMismatch(ChunkId(parent))))
+    }
+
+    /// Deletes child `index` of trait `label` of `parent`, and all of its descendants.
+    pub fn remove_child(
+        &mut self,
+        parent: node_id::NodeId,
+        label: Label,
+        index: usize,
+    ) -> Result<(), ForestError> {
+        let child = self.trait_child(parent, label, index)?;
+        self.delete_subtree(child)
+    }
+
+    /// Number of children in trait `label` of `parent`.
+    fn trait_len(&self, parent: node_id::NodeId, label: Label) -> Result<usize, ForestError> {
+        let node = self
+            .get_tree(parent)
+            .ok_or(ForestError::UnknownId(parent))?;
+        self.forest.try_trait_len(&node, label)
+    }
+
+    /// Id of child `index` of trait `label` of `parent`.
+    fn trait_child(
+        &self,
+        parent: node_id::NodeId,
+        label: Label,
+        index: usize,
+    ) -> Result<node_id::NodeId, ForestError> {
+        let node = self
+            .get_tree(parent)
+            .ok_or(ForestError::UnknownId(parent))?;
+        let child = self.forest.try_trait_get(&node, label, index)?;
+        child
+            .map(|c| c.get_id())
+            .ok_or(ForestError::IndexOutOfRange {
+                parent,
+                label,
+                index,
+            })
+    }
+
+    /// Applies the ops in `changeset` in order, returning a changeset which undoes them.
+    ///
+    /// Atomic: if any op fails, the forest is left unchanged.
+    pub fn apply(
+        &mut self,
+        changeset: &changeset::Changeset,
+    ) -> Result<changeset::Changeset, ForestError> {
+        self.transaction(|forest| {
+            let mut ops = vec![];
+            for op in changeset.ops.iter() {
+                ops.push(forest.apply_op(op)?);
+            }
+            ops.reverse();
+            Ok(changeset::Changeset { ops })
+        })
+    }
+
+    /// Applies `op`, returning its inverse.
+    fn apply_op(&mut self, op: &changeset::Op) -> Result<changeset::Op, ForestError> {
+        use changeset::{Op, Place};
+        match op {
+            Op::Insert { place, trees } => {
+                for (i, tree) in trees.iter().enumerate() {
+                    let index = place
+                        .index
+                        .checked_add(i)
+                        .ok_or(ForestError::IndexOutOfRange {
+                            parent: place.parent,
+                            label: place.label,
+                            index: place.index,
+                        })?;
+                    tree.validate()?;
+                    self.insert_chunked(tree.chunks.iter().cloned())?;
+                    self.attach(place.parent, place.label, index, tree.root)?;
+                }
+                Ok(Op::DeleteRange {
+                    place: place.clone(),
+                    count: trees.len(),
+                })
+            }
+            Op::DeleteRange { place, count } => {
+                let len = self.trait_len(place.parent, place.label)?;
+                let end = place
+                    .index
+                    .checked_add(*count)
+                    .filter(|end| *end <= len)
+                    .ok_or(ForestError::IndexOutOfRange {
+                        parent: place.parent,
+                        label: place.label,
+                        index: place.index.max(len),
+                    })?;
+                let ids = (place.index..end)
+                    .map(|index| self.trait_child(place.parent, place.label, index))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let mut trees = vec![];
+                for id in ids {
+                    self.split_out(id)?;
+                    trees.push(self.subtree(id)?);
+                    self.delete_subtree(id)?;
+                }
+                Ok(Op::Insert {
+                    place: place.clone(),
+                    trees,
+                })
+            }
+            Op::SetPayload { id, payload } => {
+                let node = self.get_tree(*id).ok_or(ForestError::UnknownId(*id))?;
+                let old = node.get_payload().map(|p| p.into_iter().cloned().collect());
+                self.set_payload(*id, payload.as_deref())?;
+                Ok(Op::SetPayload {
+                    id: *id,
+                    payload: old,
+                })
+            }
+            Op::Move { id, to } => {
+                let node = self.get_tree(*id).ok_or(ForestError::UnknownId(*id))?;
+                let from = match (
+                    self.forest.try_get_parent(&node)?,
+                    self.forest.try_index_in_parent(&node)?,
+                ) {
+                    (Some(parent), Some(index)) => Some(Place {
+                        parent: parent.node.get_id(),
+                        label: parent.label,
+                        index,
+                    }),
+                    _ => None,
+                };
+                match to {
+                    Some(place) => {
+                        self.move_subtree(*id, place.parent, place.label, place.index)?
+                    }
+                    None => {
+                        self.split_out(*id)?;
+                        self.replace_in_parent(ChunkId(*id), &[])?;
+                    }
+                }
+                Ok(Op::Move { id: *id, to: from })
+            }
+        }
+    }
+
+    /// Copies the chunks of the subtree under `id`, which must be the only top level node in its chunk.
+    fn subtree(&self, id: node_id::NodeId) -> Result<changeset::Subtree, ForestError> {
+        let mut chunks = vec![];
+        let mut pending = vec![ChunkId(id)];
+        while let Some(chunk_id) = pending.pop() {
+            let chunk = self
+                .forest
+                .find_nodes(chunk_id)
+                .ok_or(ForestError::UnknownId(chunk_id.0))?;
+            for label in chunk.get_traits() {
+                pending.extend(chunk.get_trait(label));
+            }
+            chunks.push((chunk_id, chunk.clone()));
+        }
+        Ok(changeset::Subtree { root: id, chunks })
+    }
+
+    /// Sets or removes the payload of a node.
+    fn set_payload(
+        &mut self,
+        id: node_id::NodeId,
+        payload: Option<&[u8]>,
+    ) -> Result<(), ForestError> {
+        match payload {
+            Some(value) => self.set_value(id, value),
+            None => {
+                let node = self.get_tree(id).ok_or(ForestError::UnknownId(id))?;
+                if node.get_payload().is_none() {
+                    return Ok(());
+                }
+                self.make_indirect(id)?;
+                self.forest.edit(
+                    ChunkId(id),
+                    |chunk| {
+                        if let enum_chunk::Chunk::Indirect(node) = chunk {
+                            node.payload = None;
+                        }
+                        ((), vec![])
+                    },
+                    indirect_nav::Forest::top_level_count,
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// Node level changes from `old` to `new` (see [diff]).
+    pub fn diff(old: &Forest, new: &Forest) -> Vec<diff::Change> {
+        diff::diff(&old.forest, &new.forest)
+    }
+
+    /// Checks every tree in the forest against `schema`, returning all the violations.
+    pub fn validate_against(&self, schema: &schema::Schema) -> Vec<schema::Violation> {
+        let mut violations = vec![];
+        for id in self.forest.map_keys() {
+            if self.forest.get_parent_data().contains_key(id) {
+                continue;
+            }
+            let chunk = self.forest.find_nodes(*id).unwrap();
+            for root in chunk.top_level_nodes(id.0) {
+                let nav = self.forest.nav_from(root.get_id()).unwrap();
+                schema.validate(nav, &mut violations);
+            }
+        }
+        violations
+    }
+
+    /// Editing cursor at `id`, or None if `id` is not in the forest.
+    pub fn nav_mut(&mut self, id: node_id::NodeId) -> Option<nav_mut::NavMut<'_>> {
+        nav_mut::NavMut::new(self, id)
+    }
+
+    /// Rechunks the subtree under `id` with the default [chunker::RechunkPolicy] (see [Forest::rechunk]).
+    pub fn replace_node_chunked(&mut self, id: node_id::NodeId) -> Result<(), ForestError> {
+        self.rechunk(id, &chunker::RechunkPolicy::default())
+    }
+
+    /// Rebuilds the chunks of the subtree under `id`, keeping all ids.
+    ///
+    /// Runs of identically shaped siblings with sequential ids are stored as [UniformChunk]s
+    /// (including runs which were in separate chunks), and everything else as [IndirectChunk]s.
+    /// `id` becomes the first node of its own chunk.
+    /// Payload layouts are kept for the [Def]s which have them.
+    ///
+    /// Like all edits this is copy on write, so clones of the forest are unaffected.
+    pub fn rechunk(
+        &mut self,
+        id: node_id::NodeId,
+        policy: &chunker::RechunkPolicy,
+    ) -> Result<(), ForestError> {
+        let mut layouts = std::collections::HashMap::new();
+        let tree = chunker::copy_tree(
+            &self.forest.nav_from(id).ok_or(ForestError::UnknownId(id))?,
+            &mut layouts,
+        );
+        let tree = chunker::Analyzed::new(&tree);
+        self.transaction(|forest| {
+            forest.split_out(id)?;
+            forest.remove_chunks(id)?;
+            let chunks = chunker::rechunk(&tree, policy, &layouts, &mut forest.shapes.borrow_mut());
+            for (chunk_id, chunk) in chunks {
+                forest.forest.insert(chunk_id, chunk);
+            }
+            Ok(())
+        })
+    }
+
+    /// Modifies the chunk `id` (if present) with `f`.
+    pub fn update_chunk<R>(
         &mut self,
         id: chunk::ChunkId,
-    ) -> im_rc::ordmap::Entry<chunk::ChunkId, indirect::enum_chunk::Chunk> {
-        self.forest.entry(id)
+        f: impl FnOnce(&mut indirect::enum_chunk::Chunk) -> R,
+    ) -> Option<R> {
+        self.forest.update(id, f)
+    }
+
+    /// Deletes a node and all of its descendants, detaching it from its parent.
+    pub fn delete_subtree(&mut self, id: node_id::NodeId) -> Result<(), ForestError> {
+        if self.forest.find_node(id).is_none() {
+            return Err(ForestError::UnknownId(id));
+        }
+        self.transaction(|forest| {
+            forest.split_out(id)?;
+            forest.replace_in_parent(ChunkId(id), &[])?;
+            forest.remove_chunks(id)
+        })
     }
+}
+
+impl Default for Forest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Deduplicates the schema of the chunks in `forest`.
+impl From<indirect_nav::Forest> for Forest {
+    fn from(forest: indirect_nav::Forest) -> Self {
+        let mut result = Forest::new();
+        result.insert_interned(
+            forest
+                .map_keys()
+                .map(|id| (*id, forest.find_nodes(*id).unwrap().clone())),
+        );
+        result
+    }
+}
+
+/// Chunk splitting
+impl Forest {
+    /// Runs `edit` on a copy of the forest, keeping the result only if it succeeds.
+    /// This is cheap since the forest is copy on write.
+    /// On failure, the shapes only the copy used are removed from the [ShapeLibrary].
+    fn transaction<T>(
+        &mut self,
+        edit: impl FnOnce(&mut Forest) -> Result<T, ForestError>,
+    ) -> Result<T, ForestError> {
+        let mut forest = self.clone();
+        match edit(&mut forest) {
+            Ok(result) => {
+                *self = forest;
+                Ok(result)
+            }
+            Err(error) => {
+                drop(forest);
+                self.shapes.borrow_mut().prune();
+                Err(error)
+            }
+        }
+    }
+
+    /// Inserts chunks, deduplicating the schema of [UniformChunk]s.
+    fn insert_interned(&mut self, chunks: impl IntoIterator<Item = (ChunkId, enum_chunk::Chunk)>) {
+        let mut shapes = self.shapes.borrow_mut();
+        for (id, mut chunk) in chunks {
+            if let enum_chunk::Chunk::Uniform(c) = &mut chunk {
+                c.schema = shapes.intern_root(c.schema.clone());
+            }
+            self.forest.insert(id, chunk);
+        }
+    }
+
+    /// Finds `count` sequential ids which are not used or reserved by any chunk, and returns the first.
+    fn allocate_ids(&self, count: IdBase) -> NodeId {
+        let mut rng = rand::thread_rng();
+        loop {
+            let first = NodeId(rng.gen_range(0..IdBase::MAX - count));
+            let last = NodeId(first.0 + count - 1);
+            let free = match self.forest.find_nodes_from_node(last) {
+                Some((chunk_id, chunk)) => {
+                    let extent = match chunk {
+                        enum_chunk::Chunk::Indirect(_) => 1,
+                        enum_chunk::Chunk::Uniform(c) => c.id_extent().0 as IdBase,
+                    };
+                    chunk_id.0 .0 + extent <= first.0
+                }
+                None => true,
+            };
+            if free {
+                return first;
+            }
+        }
+    }
+
+    /// Splits chunks as needed to make `id` the only top level node in its chunk.
+    fn split_out(&mut self, id: NodeId) -> Result<(), ForestError> {
+        loop {
+            let (chunk_id, chunk) = match self.forest.find_nodes_from_node(id) {
+                Some((chunk_id, enum_chunk::Chunk::Uniform(chunk))) => (*chunk_id, chunk.clone()),
+                Some((chunk_id, enum_chunk::Chunk::Indirect(_))) if chunk_id.0 == id => {
+                    return Ok(())
+                }
+                _ => return Err(ForestError::UnknownId(id)),
+            };
+            chunk
+                .schema
+                .lookup_schema(chunk_id.0, id)
+                .ok_or(ForestError::UnknownId(id))?;
+
+            let stride = chunk.schema.schema.id_stride;
+            let index = ((id - chunk_id.0).0 / stride) as usize;
+            let count = chunk.get_count();
+            let node_id = chunk_id.0 + IdOffset(index as u32 * stride);
+            if count > 1 {
+                let (before, rest) = chunk.split_at(index);
+                let (node, after) = rest.split_at(1);
+                let mut pieces: Vec<(ChunkId, enum_chunk::Chunk)> = vec![];
+                if index > 0 {
+                    pieces.push((chunk_id, before.into()));
+                }
+                pieces.push((ChunkId(node_id), node.into()));
+                if index + 1 < count {
+                    pieces.push((ChunkId(node_id + IdOffset(stride)), after.into()));
+                }
+                self.replace_chunk(chunk_id, pieces)?;
+            }
+
+            if node_id == id {
+                return Ok(());
+            }
+            // id is nested within node_id: expose its containing trait as its own chunk and continue from there.
+            self.explode(node_id)?;
+        }
+    }
+
+    /// Removes the chunk `id` (which must be the only top level node in it) and all chunks under it,
+    /// without updating its parent.
+    fn remove_chunks(&mut self, id: NodeId) -> Result<(), ForestError> {
+        let mut pending = vec![(ChunkId(id), ChunkId(id))];
+        while let Some((parent, chunk_id)) = pending.pop() {
+            let chunk = self
+                .forest
+                .remove(chunk_id)
+                .ok_or(ForestError::DanglingChild {
+                    parent,
+                    child: chunk_id,
+                })?;
+            for label in (&chunk).get_traits() {
+                pending.extend((&chunk).get_trait(label).map(|child| (chunk_id, child)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Splits chunks as needed to store `id` as an [IndirectChunk].
+    fn make_indirect(&mut self, id: NodeId) -> Result<(), ForestError> {
+        self.split_out(id)?;
+        if let Some(enum_chunk::Chunk::Uniform(_)) = self.forest.find_nodes(ChunkId(id)) {
+            self.explode(id)?;
+        }
+        Ok(())
+    }
+
+    /// Replaces a [UniformChunk] containing a single top level node with an [IndirectChunk],
+    /// moving the content of each of its traits into its own [UniformChunk].
+    fn explode(&mut self, id: NodeId) -> Result<(), ForestError> {
+        let chunk = match self.forest.find_nodes(ChunkId(id)) {
+            Some(enum_chunk::Chunk::Uniform(chunk)) => chunk.clone(),
+            _ => return Err(ForestError::SchemaMismatch(ChunkId(id))),
+        };
+        let schema = &chunk.schema.schema;
+        debug_assert_eq!(schema.node_count, 1);
+
+        let payload = schema
+            .payload_size
+            .map(|size| Box::new((*chunk.data).clone().slice(0..size as usize)));
+        let mut traits = ImHashMap::default();
+        for (label, offset_schema) in schema.traits.iter() {
+            let sub_schema = &offset_schema.schema;
+            if sub_schema.node_count == 0 {
+                continue;
+            }
+            let start = offset_schema.byte_offset as usize;
+            let end = start + (sub_schema.node_count * sub_schema.bytes_per_node) as usize;
+            let child = ChunkId(id + offset_schema.id_offset);
+            self.forest.insert(
+                child,
+                UniformChunk {
+                    data: Box::new((*chunk.data).clone().slice(start..end)),
+                    schema: self.shapes.borrow_mut().intern((**sub_schema).clone()),
+                }
+                .into(),
+            );
+            traits.insert(*label, vec![child]);
+        }
+
+        self.forest.insert(
+            ChunkId(id),
+            IndirectChunk {
+                def: schema.def,
+                payload,
+                payload_layout: schema.payload_layout.clone().map(Shared::new),
+                traits,
+            }
+            .into(),
+        );
+        Ok(())
+    }
+
+    /// Replaces the chunk at `old` with `pieces`, which are inserted in its place in its parent's trait.
+    fn replace_chunk(
+        &mut self,
+        old: ChunkId,
+        pieces: Vec<(ChunkId, enum_chunk::Chunk)>,
+    ) -> Result<(), ForestError> {
+        let ids: Vec<ChunkId> = pieces.iter().map(|(id, _)| *id).collect();
+        self.replace_in_parent(old, &ids)?;
+        self.forest.remove(old);
+        self.insert_interned(pieces);
+        Ok(())
+    }
+
+    /// Replaces `old` with `new` in the trait of its parent chunk (if it has one).
+    /// Removes the trait if this leaves it empty.
+    fn replace_in_parent(&mut self, old: ChunkId, new: &[ChunkId]) -> Result<(), ForestError> {
+        let parent = match self.forest.get_parent_data().get(&old) {
+            Some(parent) => parent.clone(),
+            None => return Ok(()),
+        };
+        let mismatch = ForestError::SchemaMismatch(parent.node);
+        self.forest
+            .edit(
+                parent.node,
+                |chunk| match chunk {
+                    enum_chunk::Chunk::Indirect(node) => {
+                        let children = match node.traits.get_mut(&parent.label) {
+                            Some(children) => children,
+                            None => return (Err(mismatch), vec![]),
+                        };
+                        let index = match children.iter().position(|c| *c == old) {
+                            Some(index) => index,
+                            None => return (Err(mismatch), vec![]),
+                        };
+                        children.splice(index..=index, new.iter().cloned());
+                        if children.is_empty() {
+                            node.traits.remove(&parent.label);
+                        }
+                        let edit = ChildEdit {
+                            label: parent.label,
+                            index,
+                            removed: vec![old],
+                            added: new.to_vec(),
+                        };
+                        (Ok(()), vec![edit])
+                    }
+                    _ => (Err(mismatch), vec![]),
+                },
+                indirect_nav::Forest::top_level_count,
+            )
+            .unwrap_or(Err(ForestError::SchemaMismatch(parent.node)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_stuff::{
+        check_parents, point_tree, uniform_tree, walk_all, IdNode, CHANNELS, COLORS,
+    };
+    use crate::tree::NodeData;
+    use std::collections::HashMap;
+
+    fn leaf(id: u128) -> IdNode {
+        IdNode {
+            id: NodeId(id),
+            def: Def(100),
+            payload: Some(im::vector![9]),
+            traits: HashMap::new(),
+        }
+    }
+
+    fn chunk_ids(forest: &Forest) -> Vec<u128> {
+        forest.forest.map_keys().map(|k| k.0 .0).collect()
+    }
+
+    #[test]
+    fn replace_top_level_in_chunk() {
+        let (tree, root) = uniform_tree(3);
+        let mut forest = Forest::from(tree);
+        forest.insert_or_replace_node(leaf(6)).unwrap();
+
+        assert_eq!(chunk_ids(&forest), vec![0, 1, 6, 7, 8, 9, 10, 11]);
+        let node = forest.get_tree(NodeId(6)).unwrap();
+        assert_eq!(node.get_def(), Def(100));
+        assert_eq!(*node.get_payload().unwrap().get(0).unwrap(), 9);
+        // Replaced node's channels are left as roots.
+        assert!(forest.get_parent(NodeId(7)).unwrap().is_none());
+
+        let nav = forest.forest.nav_from(root).unwrap();
+        assert_eq!(walk_all(nav.clone()), 1 + 5 + 1 + 5);
+        check_parents(nav);
+        forest.forest.validate().unwrap();
+    }
+
+    #[test]
+    fn paths() {
+        let (tree, root) = uniform_tree(3);
+        let forest = Forest::from(tree);
+        let path = forest.path_of(NodeId(13)).unwrap();
+        assert_eq!(path, vec![(COLORS, 2), (CHANNELS[1], 0)]);
+        let node = forest.resolve_path(root, &path).unwrap();
+        assert_eq!(node.get_id(), NodeId(13));
+        assert!(forest.path_of(root).unwrap().is_empty());
+        assert!(forest.resolve_path(root, &[(COLORS, 3)]).is_none());
+        assert_eq!(
+            forest.path_of(NodeId(16)),
+            Err(ForestError::UnknownId(NodeId(16)))
+        );
+    }
+
+    #[test]
+    fn replace_nested_in_chunk() {
+        let (tree, root) = uniform_tree(3);
+        let mut forest = Forest::from(tree);
+        forest.insert_or_replace_node(leaf(8)).unwrap();
+
+        assert_eq!(chunk_ids(&forest), vec![0, 1, 6, 7, 8, 9, 10, 11]);
+        assert_eq!(forest.get_tree(NodeId(8)).unwrap().get_def(), Def(100));
+        // Sibling channel keeps its data.
+        let sibling = forest.get_tree(NodeId(9)).unwrap();
+        assert_eq!(*sibling.get_payload().unwrap().get(0).unwrap(), 6);
+
+        let nav = forest.forest.nav_from(root).unwrap();
+        assert_eq!(walk_all(nav.clone()), 16);
+        check_parents(nav);
+        forest.forest.validate().unwrap();
+    }
+
+    #[test]
+    fn reparent_children() {
+        let (tree, root) = uniform_tree(3);
+        let mut forest = Forest::from(tree);
+        forest.insert_or_replace_node(leaf(100)).unwrap();
+        forest
+            .insert_or_replace_node(IdNode {
+                id: root,
+                def: Def(1),
+                payload: None,
+                traits: [
+                    (COLORS, vec![NodeId(11), NodeId(100)]),
+                    (CHANNELS[0], vec![NodeId(3)]),
+                ]
+                .into_iter()
+                .collect(),
+            })
+            .unwrap();
+
+        let nav = forest.forest.nav_from(root).unwrap();
+        assert_eq!(walk_all(nav.clone()), 1 + 5 + 1 + 1);
+        check_parents(nav);
+        forest.forest.validate().unwrap();
+        // The rest of the old chunk is left as roots.
+        assert!(forest.get_parent(NodeId(1)).unwrap().is_none());
+        assert!(forest.get_parent(NodeId(6)).unwrap().is_none());
+        let parent = forest.get_parent(NodeId(3)).unwrap().unwrap();
+        assert_eq!(parent.node.get_id(), root);
+        assert_eq!(parent.label, CHANNELS[0]);
+    }
+
+    #[test]
+    fn insert_or_replace_rejects_cycles() {
+        let (tree, root) = uniform_tree(3);
+        let mut forest = Forest::from(tree);
+        forest.insert_or_replace_node(leaf(100)).unwrap();
+        let before = chunk_ids(&forest);
+        let parent_of = |id: u128, child: u128| IdNode {
+            id: NodeId(id),
+            def: Def(1),
+            payload: None,
+            traits: [(COLORS, vec![NodeId(child)])].into_iter().collect(),
+        };
+        assert_eq!(
+            forest.insert_or_replace_node(parent_of(100, 100)),
+            Err(ForestError::Cycle(NodeId(100)))
+        );
+        assert_eq!(
+            forest.insert_or_replace_node(parent_of(7, 0)),
+            Err(ForestError::Cycle(root))
+        );
+        assert_eq!(chunk_ids(&forest), before);
+        assert!(forest.get_parent(NodeId(100)).unwrap().is_none());
+    }
+
+    #[test]
+    fn insert_or_replace_rejects_duplicate_children() {
+        let (tree, root) = uniform_tree(3);
+        let mut forest = Forest::from(tree);
+        forest.insert_or_replace_node(leaf(100)).unwrap();
+        let before = chunk_ids(&forest);
+        let parent = |traits: Vec<(Label, Vec<NodeId>)>| IdNode {
+            id: NodeId(200),
+            def: Def(1),
+            payload: None,
+            traits: traits.into_iter().collect(),
+        };
+        let duplicate = Err(ForestError::MultipleParents(ChunkId(NodeId(100))));
+        assert_eq!(
+            forest.insert_or_replace_node(parent(vec![(COLORS, vec![NodeId(100), NodeId(100)])])),
+            duplicate
+        );
+        assert_eq!(
+            forest.insert_or_replace_node(parent(vec![
+                (COLORS, vec![NodeId(100)]),
+                (CHANNELS[0], vec![NodeId(6), NodeId(100)]),
+            ])),
+            duplicate
+        );
+        assert_eq!(chunk_ids(&forest), before);
+        assert!(forest.get_tree(NodeId(200)).is_none());
+        assert_eq!(
+            forest.get_parent(NodeId(6)).unwrap().unwrap().node.get_id(),
+            root
+        );
+        forest.forest.validate().unwrap();
+    }
+
+    #[test]
+    fn delete_from_middle_of_chunk() {
+        let (tree, root) = uniform_tree(3);
+        let mut forest = Forest::from(tree);
+        forest.delete_subtree(NodeId(6)).unwrap();
+
+        assert_eq!(chunk_ids(&forest), vec![0, 1, 11]);
+        assert!(forest.get_tree(NodeId(8)).is_none());
+        let nav = forest.forest.nav_from(root).unwrap();
+        assert_eq!(walk_all(nav.clone()), 1 + 5 + 5);
+        check_parents(nav);
+        forest.forest.validate().unwrap();
+    }
+
+    #[test]
+    fn delete_node() {
+        let (tree, root) = uniform_tree(3);
+        let mut forest = Forest::from(tree);
+        assert_eq!(
+            forest.delete_node(NodeId(6)),
+            Err(ForestError::HasChildren(NodeId(6)))
+        );
+        forest.delete_node(NodeId(8)).unwrap();
+        assert_eq!(
+            forest.delete_node(NodeId(8)),
+            Err(ForestError::UnknownId(NodeId(8)))
+        );
+
+        let nav = forest.forest.nav_from(root).unwrap();
+        assert_eq!(walk_all(nav.clone()), 15);
+        check_parents(nav);
+        forest.forest.validate().unwrap();
+    }
+
+    #[test]
+    fn delete_whole_tree() {
+        let (tree, root) = uniform_tree(3);
+        let mut forest = Forest::from(tree);
+        forest.delete_subtree(root).unwrap();
+        assert!(chunk_ids(&forest).is_empty());
+        assert!(forest.forest.get_parent_data().is_empty());
+    }
+
+    fn trait_ids(forest: &Forest, id: u128, label: Label) -> Vec<u128> {
+        let nav = forest.forest.nav_from(NodeId(id)).unwrap();
+        nav.get_trait(label).map(|child| child.get_id().0).collect()
+    }
+
+    #[test]
+    fn move_subtree() {
+        let (tree, root) = uniform_tree(3);
+        let mut forest = Forest::from(tree);
+        // Channel from the middle of the chunk to the front of the root's trait.
+        forest.move_subtree(NodeId(8), root, COLORS, 0).unwrap();
+        assert_eq!(trait_ids(&forest, 0, COLORS), vec![8, 1, 6, 11]);
+        assert!(trait_ids(&forest, 6, CHANNELS[1]).is_empty());
+        assert_eq!(payload(&forest, 8), vec![5]);
+        assert_eq!(
+            forest.get_parent(NodeId(8)).unwrap().unwrap().node.get_id(),
+            root
+        );
+
+        // Reorder within a trait: index is after removal.
+        forest.move_subtree(NodeId(1), root, COLORS, 3).unwrap();
+        assert_eq!(trait_ids(&forest, 0, COLORS), vec![8, 6, 11, 1]);
+
+        // Under a node in a chunk.
+        forest
+            .move_subtree(NodeId(6), NodeId(12), Label(5), 0)
+            .unwrap();
+        assert_eq!(trait_ids(&forest, 0, COLORS), vec![8, 11, 1]);
+        assert_eq!(trait_ids(&forest, 12, Label(5)), vec![6]);
+        assert_eq!(payload(&forest, 9), vec![6]);
+
+        let nav = forest.forest.nav_from(root).unwrap();
+        assert_eq!(walk_all(nav.clone()), 1 + 3 * 5);
+        check_parents(nav);
+        forest.forest.validate().unwrap();
+    }
+
+    #[test]
+    fn move_subtree_rejects_cycles() {
+        let (tree, root) = uniform_tree(3);
+        let mut forest = Forest::from(tree);
+        let before = chunk_ids(&forest);
+        assert_eq!(
+            forest.move_subtree(root, NodeId(7), COLORS, 0),
+            Err(ForestError::Cycle(root))
+        );
+        assert_eq!(
+            forest.move_subtree(NodeId(6), NodeId(6), COLORS, 0),
+            Err(ForestError::Cycle(NodeId(6)))
+        );
+        assert!(matches!(
+            forest.move_subtree(NodeId(6), root, COLORS, 4),
+            Err(ForestError::IndexOutOfRange { .. })
+        ));
+        assert_eq!(chunk_ids(&forest), before);
+    }
+
+    #[test]
+    fn insert_tree() {
+        let byte = |i: u8| example_node::BasicNode {
+            id: NodeId(0),
+            def: Def(2),
+            payload: Some(im::vector![i]),
+            traits: HashMap::new(),
+        };
+        let tree = example_node::BasicNode {
+            id: NodeId(0),
+            def: Def(1),
+            payload: None,
+            traits: [(Label(1), (0..100).map(byte).collect())]
+                .into_iter()
+                .collect(),
+        };
+        let mut forest = Forest::new();
+        let root = forest.insert_tree(tree);
+        let other = forest.insert_tree(byte(1));
+        assert_ne!(root, other);
+
+        assert_eq!(chunk_ids(&forest).len(), 2);
+        assert_eq!(walk_all(forest.forest.nav_from(root).unwrap()), 101);
+        let child = forest.get_tree(NodeId(root.0 + 51)).unwrap();
+        assert_eq!(*child.get_payload().unwrap().get(0).unwrap(), 50);
+    }
+
+    fn uniform(forest: &Forest, id: u128) -> &UniformChunk {
+        match forest.forest.find_nodes(ChunkId(NodeId(id))) {
+            Some(enum_chunk::Chunk::Uniform(c)) => c,
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn insert_chunked_dedups_shapes() {
+        let chunk = || {
+            uniform_tree(3)
+                .0
+                .find_nodes(ChunkId(NodeId(1)))
+                .unwrap()
+                .clone()
+        };
+        let (a, b) = (chunk(), chunk());
+        assert!(a != b);
+
+        let mut forest = Forest::new();
+        forest
+            .insert_chunked([(ChunkId(NodeId(1)), a), (ChunkId(NodeId(100)), b)])
+            .unwrap();
+        assert!(Shared::ptr_eq(
+            &uniform(&forest, 1).schema,
+            &uniform(&forest, 100).schema
+        ));
+        assert!(uniform(&forest, 1) == uniform(&forest, 100));
+        assert_eq!(forest.shapes.borrow().len(), 1);
+    }
+
+    #[test]
+    fn insert_chunked_checks_data_length() {
+        let mut chunk = uniform(&Forest::from(uniform_tree(3).0), 1).clone();
+        chunk.data.pop_back();
+        let mut forest = Forest::new();
+        assert_eq!(
+            forest.insert_chunked([(ChunkId(NodeId(1)), chunk.into())]),
+            Err(ForestError::SchemaMismatch(ChunkId(NodeId(1))))
+        );
+        assert!(chunk_ids(&forest).is_empty());
+    }
+
+    #[test]
+    fn insert_chunked_checks_overlap() {
+        let chunk = uniform(&Forest::from(uniform_tree(3).0), 1).clone();
+        let mut forest = Forest::new();
+        forest.insert_or_replace_node(leaf(10)).unwrap();
+        assert_eq!(
+            forest.insert_chunked([
+                (ChunkId(NodeId(100)), chunk.clone().into()),
+                (ChunkId(NodeId(1)), chunk.into())
+            ]),
+            Err(ForestError::OverlappingChunks(
+                ChunkId(NodeId(1)),
+                ChunkId(NodeId(10))
+            ))
+        );
+        assert_eq!(chunk_ids(&forest), vec![10]);
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let (tree, root) = uniform_tree(3);
+        let mut forest = Forest::from(tree);
+        forest.set_value(NodeId(7), &[1, 2]).unwrap();
+        let mut data = vec![];
+        forest.write_snapshot(&mut data).unwrap();
+
+        let loaded = Forest::read_snapshot(&mut data.as_slice()).unwrap();
+        assert_eq!(chunk_ids(&loaded), chunk_ids(&forest));
+        assert_eq!(payload(&loaded, 7), vec![1, 2]);
+        let nav = loaded.forest.nav_from(root).unwrap();
+        assert_eq!(walk_all(nav.clone()), 16);
+        check_parents(nav);
+    }
+
+    #[test]
+    fn errors_leave_forest_unchanged() {
+        let (tree, _) = uniform_tree(3);
+        let mut forest = Forest::from(tree);
+        assert_eq!(
+            forest.get_parent(NodeId(100)).err(),
+            Some(ForestError::UnknownId(NodeId(100)))
+        );
+        // Fails after node 8 has already been split out of its chunk.
+        let mut node = leaf(100);
+        node.traits.insert(COLORS, vec![NodeId(8), NodeId(200)]);
+        assert_eq!(
+            forest.insert_or_replace_node(node),
+            Err(ForestError::UnknownId(NodeId(200)))
+        );
+        assert_eq!(chunk_ids(&forest), vec![0, 1]);
+        assert!(forest.get_tree(NodeId(100)).is_none());
+        assert_eq!(
+            forest.get_parent(NodeId(8)).unwrap().unwrap().node.get_id(),
+            NodeId(6)
+        );
+    }
+
+    #[test]
+    fn dangling_child() {
+        let (mut tree, root) = uniform_tree(3);
+        tree.remove(ChunkId(NodeId(1)));
+        let mut forest = Forest::from(tree);
+        // Navigation skips the missing chunk.
+        assert_eq!(walk_all(forest.forest.nav_from(root).unwrap()), 1);
+        assert_eq!(
+            forest.delete_subtree(root),
+            Err(ForestError::DanglingChild {
+                parent: ChunkId(root),
+                child: ChunkId(NodeId(1))
+            })
+        );
+        assert!(forest.get_tree(root).is_some());
+    }
+
+    #[test]
+    fn split_chunks_share_shapes() {
+        let (tree, _) = uniform_tree(5);
+        let mut forest = Forest::from(tree);
+        forest.delete_subtree(NodeId(6)).unwrap();
+        forest.delete_subtree(NodeId(16)).unwrap();
+
+        assert_eq!(chunk_ids(&forest), vec![0, 1, 11, 21]);
+        assert!(Shared::ptr_eq(
+            &uniform(&forest, 1).schema,
+            &uniform(&forest, 11).schema
+        ));
+        // Only single nodes are left: the original shape and the 3 nodes left after the first delete are dropped.
+        assert_eq!(forest.shapes.borrow().len(), 1);
+    }
+
+    #[test]
+    fn unused_shapes_are_dropped() {
+        let (tree, _) = uniform_tree(5);
+        let mut forest = Forest::from(tree);
+        let snapshot = forest.clone();
+        forest.delete_subtree(NodeId(6)).unwrap();
+        // The snapshot still uses the original shape.
+        assert_eq!(forest.shapes.borrow().len(), 3);
+        drop(snapshot);
+        assert_eq!(forest.shapes.borrow().len(), 2);
+
+        // Splitting out 16 interns new shapes before the edit fails.
+        let (tree, _) = uniform_tree(5);
+        let mut forest = Forest::from(tree);
+        let parent = IdNode {
+            id: NodeId(100),
+            def: Def(1),
+            payload: None,
+            traits: [(COLORS, vec![NodeId(16), NodeId(100)])]
+                .into_iter()
+                .collect(),
+        };
+        assert!(forest.insert_or_replace_node(parent).is_err());
+        assert_eq!(forest.shapes.borrow().map.len(), 1);
+
+        let mut shapes = ShapeLibrary::default();
+        let mut schema = uniform(&forest, 1).schema.schema.clone();
+        for node_count in 1..1000 {
+            schema.node_count = node_count;
+            shapes.intern(schema.clone());
+        }
+        assert!(shapes.is_empty());
+        assert!(shapes.map.len() <= ShapeLibrary::MIN_PRUNE_AT);
+    }
+
+    fn payload(forest: &Forest, id: u128) -> Vec<u8> {
+        let node = forest.get_tree(NodeId(id)).unwrap();
+        node.get_payload().unwrap().into_iter().cloned().collect()
+    }
+
+    #[test]
+    fn set_value_in_chunk() {
+        let (tree, _) = uniform_tree(3);
+        let mut forest = Forest::from(tree);
+        let snapshot = forest.clone();
+        forest.set_value(NodeId(8), &[200]).unwrap();
+
+        assert_eq!(chunk_ids(&forest), vec![0, 1]);
+        assert_eq!(payload(&forest, 8), vec![200]);
+        assert_eq!(payload(&forest, 9), vec![6]);
+        assert_eq!(payload(&snapshot, 8), vec![5]);
+    }
+
+    #[test]
+    fn set_value_resize() {
+        let (tree, root) = uniform_tree(3);
+        let mut forest = Forest::from(tree);
+        forest.set_value(NodeId(8), &[1, 2]).unwrap();
+        forest.set_value(root, &[3]).unwrap();
+
+        assert_eq!(chunk_ids(&forest), vec![0, 1, 6, 7, 8, 9, 10, 11]);
+        assert_eq!(payload(&forest, 8), vec![1, 2]);
+        assert_eq!(payload(&forest, 0), vec![3]);
+        check_parents(forest.forest.nav_from(root).unwrap());
+        assert_eq!(
+            forest.set_value(NodeId(1000), &[]),
+            Err(ForestError::UnknownId(NodeId(1000)))
+        );
+    }
+
+    fn check_rechunked(forest: &Forest, original: &Forest, root: NodeId) {
+        forest.forest.validate().unwrap();
+        let nav = forest.forest.nav_from(root).unwrap();
+        assert_eq!(walk_all(nav.clone()), 16);
+        check_parents(nav);
+        assert_eq!(Forest::diff(original, forest), vec![]);
+    }
+
+    #[test]
+    fn rechunk_folds_runs() {
+        let (tree, root) = uniform_tree(3);
+        let mut forest = Forest::from(tree);
+        forest.set_value(NodeId(8), &[1, 2]).unwrap();
+        forest.set_value(NodeId(8), &[5]).unwrap();
+        let split = forest.clone();
+        assert_eq!(chunk_ids(&forest).len(), 8);
+
+        // All ids are sequential, so everything fits in one chunk.
+        forest
+            .rechunk(root, &chunker::RechunkPolicy::default())
+            .unwrap();
+        assert_eq!(chunk_ids(&forest), vec![0]);
+        check_rechunked(&forest, &split, root);
+        // Copy on write: the clone keeps its chunks.
+        assert_eq!(chunk_ids(&split).len(), 8);
+
+        // Rechunking part of a chunk splits it out.
+        forest.replace_node_chunked(NodeId(6)).unwrap();
+        assert_eq!(chunk_ids(&forest), vec![0, 1, 6, 11]);
+        assert!(matches!(
+            forest.forest.find_nodes(ChunkId(NodeId(6))),
+            Some(enum_chunk::Chunk::Uniform(_))
+        ));
+        check_rechunked(&forest, &split, root);
+    }
+
+    #[test]
+    fn rechunk_keeps_ids() {
+        let (tree, root) = uniform_tree(3);
+        let mut forest = Forest::from(tree);
+        forest.move_subtree(NodeId(6), root, COLORS, 0).unwrap();
+        let moved = forest.clone();
+        forest
+            .rechunk(root, &chunker::RechunkPolicy::default())
+            .unwrap();
+        // The colors are out of id order, so each is its own chunk.
+        assert_eq!(chunk_ids(&forest), vec![0, 1, 6, 11]);
+        assert_eq!(trait_ids(&forest, 0, COLORS), vec![6, 1, 11]);
+        check_rechunked(&forest, &moved, root);
+
+        assert_eq!(
+            forest.rechunk(NodeId(1000), &chunker::RechunkPolicy::default()),
+            Err(ForestError::UnknownId(NodeId(1000)))
+        );
+    }
+
+    #[test]
+    fn rechunk_policy() {
+        let (tree, root) = uniform_tree(3);
+        let original = Forest::from(tree);
+
+        let mut forest = original.clone();
+        let policy = chunker::RechunkPolicy {
+            min_nodes: 2,
+            max_nodes: 10,
+        };
+        forest.rechunk(root, &policy).unwrap();
+        assert_eq!(chunk_ids(&forest), vec![0, 1, 11]);
+        assert_eq!(uniform(&forest, 1).get_count(), 2);
+        check_rechunked(&forest, &original, root);
+
+        let policy = chunker::RechunkPolicy {
+            min_nodes: 100,
+            ..Default::default()
+        };
+        forest.rechunk(root, &policy).unwrap();
+        assert_eq!(chunk_ids(&forest).len(), 16);
+        check_rechunked(&forest, &original, root);
+    }
+
+    #[test]
+    fn split_out_keeps_layouts() {
+        use payload::{PayloadData, Value};
+
+        let (tree, root) = point_tree(3);
+        let mut forest = Forest::from(tree);
+        // Giving point 2 a child makes it an IndirectChunk.
+        forest
+            .move_subtree(NodeId(3), NodeId(2), COLORS, 0)
+            .unwrap();
+        assert!(matches!(
+            forest.forest.find_nodes(ChunkId(NodeId(2))),
+            Some(enum_chunk::Chunk::Indirect(_))
+        ));
+        for (id, y) in [(2, -1.0), (3, -2.0)] {
+            let node = forest.get_tree(NodeId(id)).unwrap();
+            assert_eq!(node.get_payload_field("y"), Ok(Value::Float(y)));
+        }
+        forest
+            .set_payload_field(NodeId(2), "x", &Value::Float(5.0))
+            .unwrap();
+        let node = forest.get_tree(NodeId(2)).unwrap();
+        assert_eq!(node.get_payload_field("x"), Ok(Value::Float(5.0)));
+
+        // Layouts are kept while they fit the payload.
+        let point = [7.0f32.to_le_bytes(), 8.0f32.to_le_bytes(), [0; 4]].concat();
+        forest.set_value(NodeId(1), &point).unwrap();
+        let node = forest.get_tree(NodeId(1)).unwrap();
+        assert_eq!(node.get_payload_field("y"), Ok(Value::Float(8.0)));
+        forest.set_value(NodeId(1), &[0; 4]).unwrap();
+        let node = forest.get_tree(NodeId(1)).unwrap();
+        assert!(node.get_payload_layout().is_none());
+        forest.forest.validate().unwrap();
+        check_parents(forest.forest.nav_from(root).unwrap());
+    }
+
+    #[test]
+    fn rechunk_keeps_layouts() {
+        use payload::{PayloadData, Value};
+
+        let (tree, root) = point_tree(3);
+        let mut forest = Forest::from(tree);
+        // Resizing a payload so its layout no longer fits loses the layout.
+        forest.set_value(NodeId(2), &[0]).unwrap();
+        let point = [7.0f32.to_le_bytes(), 8.0f32.to_le_bytes()].concat();
+        forest.set_value(NodeId(2), &point).unwrap();
+        let node = forest.get_tree(NodeId(2)).unwrap();
+        assert!(node.get_payload_layout().is_none());
 
-    pub fn delete_subtree(&mut self, id: node_id::NodeId) {
-        todo!()
-        // Return success or error has parent
-        // Split chunks, rechunk etc.
+        forest
+            .rechunk(root, &chunker::RechunkPolicy::default())
+            .unwrap();
+        assert_eq!(chunk_ids(&forest), vec![0]);
+        let node = forest.get_tree(NodeId(2)).unwrap();
+        assert_eq!(node.get_payload_field("y"), Ok(Value::Float(8.0)));
+        let node = forest.get_tree(NodeId(3)).unwrap();
+        assert_eq!(node.get_payload_field("x"), Ok(Value::Float(2.0)));
     }
 }
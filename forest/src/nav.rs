@@ -11,10 +11,22 @@ pub trait Resolver<Node>: Copy {
     type Iter: Iterator<Item = Node>;
     fn expand(&self, chunk: Self::Child) -> Self::Iter;
     fn get_parent(&self, node: &Node) -> Option<ParentInfo<Node>>;
+    /// Index of `node` within its parent's trait, or None if it is a root.
+    fn index_in_parent(&self, node: &Node) -> Option<usize>;
+    /// The node after `node` (or before it if `forward` is false) in its parent's trait.
+    fn sibling(&self, node: &Node, forward: bool) -> Option<Node>;
+    /// Number of nodes in trait `label` of `node`.
+    fn trait_len(&self, node: &Node, label: Label) -> usize;
+    /// Node `index` in trait `label` of `node`.
+    fn trait_get(&self, node: &Node, label: Label, index: usize) -> Option<Node>;
 }
 
 pub trait WithParent: Sized {
     fn parent(&self) -> Option<ParentInfo<Self>>;
+    /// Index of this node within its parent's trait, or None if it is a root.
+    fn index_in_parent(&self) -> Option<usize>;
+    fn next_sibling(&self) -> Option<Self>;
+    fn prev_sibling(&self) -> Option<Self>;
 }
 
 #[derive(Clone)]
@@ -29,6 +41,21 @@ impl<R, TNode> Nav<R, TNode> {
     }
 }
 
+/// Random access into traits, without iterating the nodes before the one requested.
+impl<R: Resolver<TNode>, TNode> Nav<R, TNode> {
+    /// Number of nodes in trait `label`.
+    pub fn trait_len(&self, label: Label) -> usize {
+        self.resolver.trait_len(&self.view, label)
+    }
+
+    /// Node `index` in trait `label`.
+    pub fn trait_get(&self, label: Label, index: usize) -> Option<Self> {
+        self.resolver
+            .trait_get(&self.view, label, index)
+            .map(|view| Self::new(self.resolver, view))
+    }
+}
+
 impl<R, TNode> WithParent for Nav<R, TNode>
 where
     R: Resolver<TNode>,
@@ -40,6 +67,22 @@ where
             label: p.label,
         })
     }
+
+    fn index_in_parent(&self) -> Option<usize> {
+        self.resolver.index_in_parent(&self.view)
+    }
+
+    fn next_sibling(&self) -> Option<Self> {
+        self.resolver
+            .sibling(&self.view, true)
+            .map(|view| Self::new(self.resolver, view))
+    }
+
+    fn prev_sibling(&self) -> Option<Self> {
+        self.resolver
+            .sibling(&self.view, false)
+            .map(|view| Self::new(self.resolver, view))
+    }
 }
 
 impl<R, TNode: HasId> HasId for Nav<R, TNode> {
@@ -88,9 +131,13 @@ where
         self.view.get_def()
     }
 
-    fn get_payload(&self) -> Option<crate::util::ImSlice> {
+    fn get_payload(&self) -> Option<crate::util::ImSlice<'_>> {
         self.view.get_payload()
     }
+
+    fn get_payload_layout(&self) -> Option<&crate::payload::PayloadLayout> {
+        self.view.get_payload_layout()
+    }
 }
 
 impl<R, TNode> Iterator for TraitNav<R, TNode>
@@ -101,29 +148,21 @@ where
     type Item = Nav<R, TNode>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(ref mut chunks) = self.pending {
-            match chunks.next() {
-                Some(chunk) => {
-                    return Some(Nav {
-                        resolver: self.resolver,
-                        view: chunk,
-                    });
+        loop {
+            if let Some(ref mut chunks) = self.pending {
+                match chunks.next() {
+                    Some(chunk) => {
+                        return Some(Nav {
+                            resolver: self.resolver,
+                            view: chunk,
+                        });
+                    }
+                    None => self.pending = None,
                 }
-                None => self.pending = None,
             }
-        }
 
-        match self.view.next() {
-            Some(id) => {
-                let mut iter = self.resolver.expand(id);
-                let result = iter.next().unwrap(); // Chunk must not be empty
-                self.pending = Some(iter);
-                Some(Nav {
-                    view: result,
-                    resolver: self.resolver,
-                })
-            }
-            None => None,
+            // Expansions can be empty (for example if the child is missing), so keep going until a node is found.
+            self.pending = Some(self.resolver.expand(self.view.next()?));
         }
     }
 }
@@ -0,0 +1,207 @@
+//! Editing cursor over a [Forest].
+//!
+//! Edits go through the forest, which is copy on write,
+//! so clones of the forest taken before an edit (snapshots) are unaffected by it.
+
+use crate::{
+    error::ForestError,
+    example_node::BasicNode,
+    indirect::enum_chunk,
+    indirect_nav,
+    nav::{Nav, WithParent},
+    node_id::{HasId, NodeId},
+    tree::Label,
+    Forest,
+};
+
+pub type ForestNav<'a> = Nav<&'a indirect_nav::Forest, enum_chunk::Node<'a>>;
+
+/// Cursor at a node of a [Forest], which can edit the forest and move around it.
+pub struct NavMut<'a> {
+    forest: &'a mut Forest,
+    id: NodeId,
+}
+
+impl<'a> NavMut<'a> {
+    /// Cursor at `id`, or None if `id` is not in `forest`.
+    pub fn new(forest: &'a mut Forest, id: NodeId) -> Option<Self> {
+        forest.get_tree(id)?;
+        Some(NavMut { forest, id })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn forest(&self) -> &Forest {
+        self.forest
+    }
+
+    /// Read only view of the current node.
+    pub fn nav(&self) -> ForestNav<'_> {
+        self.forest
+            .forest
+            .nav_from(self.id)
+            .expect("cursor is at a node in the forest")
+    }
+
+    pub fn set_payload(&mut self, value: &[u8]) -> Result<(), ForestError> {
+        self.forest.set_value(self.id, value)
+    }
+
+    /// Inserts `tree` as child `index` of trait `label`, returning the id of its root.
+    /// See [Forest::insert_child].
+    pub fn insert_child(
+        &mut self,
+        label: Label,
+        index: usize,
+        tree: BasicNode,
+    ) -> Result<NodeId, ForestError> {
+        self.forest.insert_child(self.id, label, index, tree)
+    }
+
+    /// Deletes child `index` of trait `label` and its descendants.
+    pub fn remove_child(&mut self, label: Label, index: usize) -> Result<(), ForestError> {
+        self.forest.remove_child(self.id, label, index)
+    }
+
+    /// Moves to the parent. Returns false (without moving) if this is a root.
+    pub fn move_to_parent(&mut self) -> bool {
+        self.move_with(|nav| nav.parent().map(|p| p.node))
+    }
+
+    /// Moves to child `index` of trait `label`. Returns false (without moving) if there is no such child.
+    pub fn move_to_child(&mut self, label: Label, index: usize) -> bool {
+        self.move_with(|nav| nav.trait_get(label, index))
+    }
+
+    /// Moves to the next sibling. Returns false (without moving) if there is none.
+    pub fn move_to_next_sibling(&mut self) -> bool {
+        self.move_with(|nav| nav.next_sibling())
+    }
+
+    /// Moves to the previous sibling. Returns false (without moving) if there is none.
+    pub fn move_to_prev_sibling(&mut self) -> bool {
+        self.move_with(|nav| nav.prev_sibling())
+    }
+
+    fn move_with(
+        &mut self,
+        f: impl for<'b> FnOnce(ForestNav<'b>) -> Option<ForestNav<'b>>,
+    ) -> bool {
+        match f(self.nav()).map(|nav| nav.get_id()) {
+            Some(id) => {
+                self.id = id;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_stuff::{check_parents, uniform_tree, CHANNELS, COLORS},
+        tree::{Def, NodeData, NodeNav},
+    };
+    use std::collections::HashMap;
+
+    fn leaf(value: u8) -> BasicNode {
+        BasicNode {
+            id: NodeId(0),
+            def: Def(100),
+            payload: Some(im::vector![value]),
+            traits: HashMap::new(),
+        }
+    }
+
+    fn snapshot(forest: &Forest) -> Vec<u8> {
+        let mut data = vec![];
+        forest.write_snapshot(&mut data).unwrap();
+        data
+    }
+
+    fn children(cursor: &NavMut) -> Vec<NodeId> {
+        cursor.nav().get_trait(COLORS).map(|c| c.get_id()).collect()
+    }
+
+    #[test]
+    fn moves() {
+        let (tree, root) = uniform_tree(3);
+        let mut forest = Forest::from(tree);
+        let mut cursor = forest.nav_mut(root).unwrap();
+        assert!(!cursor.move_to_parent());
+        assert!(cursor.move_to_child(COLORS, 1));
+        assert_eq!(cursor.id(), NodeId(6));
+        assert!(cursor.move_to_child(CHANNELS[2], 0));
+        assert_eq!(cursor.id(), NodeId(9));
+        assert!(!cursor.move_to_next_sibling());
+        assert!(cursor.move_to_parent());
+        assert!(cursor.move_to_next_sibling());
+        assert_eq!(cursor.id(), NodeId(11));
+        assert!(!cursor.move_to_next_sibling());
+        assert!(cursor.move_to_prev_sibling());
+        assert!(cursor.move_to_prev_sibling());
+        assert_eq!(cursor.id(), NodeId(1));
+        assert!(!cursor.move_to_prev_sibling());
+        assert!(!cursor.move_to_child(COLORS, 0));
+        assert!(forest.nav_mut(NodeId(16)).is_none());
+    }
+
+    #[test]
+    fn edits() {
+        let (tree, root) = uniform_tree(3);
+        let mut forest = Forest::from(tree);
+        let before = forest.clone();
+        let before_data = snapshot(&before);
+
+        let mut cursor = forest.nav_mut(NodeId(7)).unwrap();
+        cursor.set_payload(&[42]).unwrap();
+        assert_eq!(cursor.nav().get_payload().unwrap().get(0), Some(&42));
+        assert!(cursor.move_to_parent());
+        assert!(cursor.move_to_parent());
+        assert_eq!(cursor.id(), root);
+
+        let inserted = cursor.insert_child(COLORS, 1, leaf(5)).unwrap();
+        assert_eq!(
+            children(&cursor),
+            vec![NodeId(1), inserted, NodeId(6), NodeId(11)]
+        );
+        let appended = cursor.insert_child(COLORS, 4, leaf(6)).unwrap();
+        cursor.remove_child(COLORS, 0).unwrap();
+        assert_eq!(
+            children(&cursor),
+            vec![inserted, NodeId(6), NodeId(11), appended]
+        );
+        assert_eq!(
+            cursor.insert_child(COLORS, 5, leaf(7)),
+            Err(ForestError::IndexOutOfRange {
+                parent: root,
+                label: COLORS,
+                index: 5
+            })
+        );
+        assert!(matches!(
+            cursor.remove_child(COLORS, 4),
+            Err(ForestError::IndexOutOfRange { .. })
+        ));
+        assert!(cursor.move_to_child(COLORS, 3));
+        assert_eq!(cursor.id(), appended);
+
+        check_parents(forest.forest.nav_from(root).unwrap());
+        forest.forest.validate().unwrap();
+        // Clones from before the edits are unchanged.
+        assert_eq!(snapshot(&before), before_data);
+        assert_eq!(
+            before
+                .get_tree(NodeId(7))
+                .unwrap()
+                .get_payload()
+                .unwrap()
+                .get(0),
+            Some(&4)
+        );
+    }
+}
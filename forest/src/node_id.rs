@@ -5,7 +5,7 @@ use crate::tree::IdBase;
 #[derive(Ord, PartialOrd, Eq, PartialEq, Copy, Clone, Hash, Debug)]
 pub struct NodeId(pub IdBase);
 
-#[derive(Ord, PartialOrd, Eq, PartialEq, Copy, Clone)]
+#[derive(Ord, PartialOrd, Eq, PartialEq, Copy, Clone, Hash, Debug)]
 pub struct IdOffset(pub u32);
 
 impl Add<IdOffset> for NodeId {
@@ -0,0 +1,555 @@
+//! Typed payloads, encoded as bytes in [NodeData::get_payload].
+//!
+//! [Payload] is implemented for:
+//! - `bool`: one byte, 0 or 1.
+//! - Fixed width integers and floats: little endian, or big endian when wrapped in [BigEndian].
+//! - `String`: UTF-8.
+//! - [NodeId]: 16 bytes, big endian (the byte order of UUIDs).
+//!
+//! [PayloadData::get_payload_as] reads any node's payload as one of these,
+//! and [crate::Forest::set_payload_from] writes them.
+//! Payloads can also be structs of fields, described by a [PayloadLayout] in their chunk's schema
+//! (see [PayloadData::get_payload_field] and [crate::Forest::set_payload_field]).
+//! Nodes split out of a [crate::uniform_chunk::UniformChunk] keep their layout
+//! (as [crate::indirect_node::IndirectChunk::payload_layout]) until their payload is resized so it no longer fits.
+//! For payloads typed by their node's [crate::tree::Def] instead, see [PayloadType]
+//! (which can be given per [crate::tree::Def] in a [crate::schema::Schema]).
+
+use std::fmt;
+
+use crate::{node_id::NodeId, tree::NodeData, util::ImSlice};
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum PayloadError {
+    Missing,
+    Length {
+        expected: usize,
+        actual: usize,
+    },
+    InvalidBool(u8),
+    InvalidUtf8,
+    /// A [Value] which does not fit in the [PayloadType], or a [PayloadType] with an unsupported size.
+    WrongType,
+    /// The node's [PayloadLayout] has no field with this name (or it has no layout).
+    UnknownField(String),
+}
+
+impl fmt::Display for PayloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PayloadError::Missing => write!(f, "node has no payload"),
+            PayloadError::Length { expected, actual } => {
+                write!(f, "payload has {} bytes, expected {}", actual, expected)
+            }
+            PayloadError::InvalidBool(byte) => write!(f, "{} is not a valid bool", byte),
+            PayloadError::InvalidUtf8 => write!(f, "payload is not valid UTF-8"),
+            PayloadError::WrongType => write!(f, "value does not match the payload type"),
+            PayloadError::UnknownField(name) => write!(f, "payload has no field {:?}", name),
+        }
+    }
+}
+
+impl std::error::Error for PayloadError {}
+
+/// A value which can be stored as a payload.
+pub trait Payload: Sized {
+    fn encode(&self, out: &mut Vec<u8>);
+    fn decode(bytes: &[u8]) -> Result<Self, PayloadError>;
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![];
+        self.encode(&mut out);
+        out
+    }
+}
+
+/// Typed access to the payload of any node.
+pub trait PayloadData: NodeData {
+    fn get_payload_as<T: Payload>(&self) -> Result<T, PayloadError> {
+        let payload = self.get_payload().ok_or(PayloadError::Missing)?;
+        T::decode(&to_vec(payload))
+    }
+
+    /// Decodes the field `name` of the payload, using the node's [PayloadLayout].
+    fn get_payload_field(&self, name: &str) -> Result<Value, PayloadError> {
+        let field = self
+            .get_payload_layout()
+            .and_then(|layout| layout.field(name))
+            .ok_or_else(|| PayloadError::UnknownField(name.to_owned()))?;
+        let payload = self.get_payload().ok_or(PayloadError::Missing)?;
+        field.decode(&to_vec(payload))
+    }
+}
+
+impl<N: NodeData + ?Sized> PayloadData for N {}
+
+pub(crate) fn to_vec(payload: ImSlice) -> Vec<u8> {
+    payload.into_iter().cloned().collect()
+}
+
+fn fixed<const N: usize>(bytes: &[u8]) -> Result<[u8; N], PayloadError> {
+    bytes.try_into().map_err(|_| PayloadError::Length {
+        expected: N,
+        actual: bytes.len(),
+    })
+}
+
+/// Stores the wrapped number big endian instead of little endian.
+#[derive(Clone, Copy, PartialEq, PartialOrd, Default, Debug)]
+pub struct BigEndian<T>(pub T);
+
+macro_rules! numbers {
+    ($($t:ty),*) => {$(
+        impl Payload for $t {
+            fn encode(&self, out: &mut Vec<u8>) {
+                out.extend(self.to_le_bytes());
+            }
+
+            fn decode(bytes: &[u8]) -> Result<Self, PayloadError> {
+                fixed(bytes).map(<$t>::from_le_bytes)
+            }
+        }
+
+        impl Payload for BigEndian<$t> {
+            fn encode(&self, out: &mut Vec<u8>) {
+                out.extend(self.0.to_be_bytes());
+            }
+
+            fn decode(bytes: &[u8]) -> Result<Self, PayloadError> {
+                fixed(bytes).map(<$t>::from_be_bytes).map(BigEndian)
+            }
+        }
+    )*};
+}
+
+numbers!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, f32, f64);
+
+impl Payload for bool {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, PayloadError> {
+        match fixed::<1>(bytes)? {
+            [0] => Ok(false),
+            [1] => Ok(true),
+            [byte] => Err(PayloadError::InvalidBool(byte)),
+        }
+    }
+}
+
+impl Payload for String {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend(self.as_bytes());
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, PayloadError> {
+        String::from_utf8(bytes.to_vec()).map_err(|_| PayloadError::InvalidUtf8)
+    }
+}
+
+impl Payload for NodeId {
+    fn encode(&self, out: &mut Vec<u8>) {
+        BigEndian(self.0).encode(out)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, PayloadError> {
+        BigEndian::<u128>::decode(bytes).map(|id| NodeId(id.0))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+/// Type of a payload, for when it is only known at runtime (ex: from the node's [crate::tree::Def]).
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum PayloadType {
+    Bool,
+    /// Unsigned integer with the given number of bytes (1, 2, 4, 8 or 16).
+    UInt(usize, Endian),
+    /// Signed integer with the given number of bytes (1, 2, 4, 8 or 16).
+    Int(usize, Endian),
+    /// Float with the given number of bytes (4 or 8).
+    Float(usize, Endian),
+    String,
+    NodeId,
+}
+
+/// A payload decoded using a [PayloadType].
+#[derive(Clone, PartialEq, Debug)]
+pub enum Value {
+    Bool(bool),
+    UInt(u128),
+    Int(i128),
+    Float(f64),
+    String(String),
+    NodeId(NodeId),
+}
+
+/// Decodes `bytes` as `$t` (with `$endian`), converting to `$wide`.
+macro_rules! decode_as {
+    ($bytes:expr, $endian:expr, $wide:ty, $($t:ty),*) => {{
+        let bytes = $bytes;
+        match bytes.len() {
+            $(
+                n if n == std::mem::size_of::<$t>() => match $endian {
+                    Endian::Little => <$t>::decode(bytes)? as $wide,
+                    Endian::Big => BigEndian::<$t>::decode(bytes)?.0 as $wide,
+                },
+            )*
+            _ => return Err(PayloadError::WrongType),
+        }
+    }};
+}
+
+/// Encodes `$value` as `$t` (with `$endian`), where `$t` has `$size` bytes.
+macro_rules! encode_as {
+    ($value:expr, $size:expr, $endian:expr, $out:expr, $($t:ty),*) => {
+        match $size {
+            $(
+                n if n == std::mem::size_of::<$t>() => {
+                    let value = <$t>::try_from($value).map_err(|_| PayloadError::WrongType)?;
+                    match $endian {
+                        Endian::Little => value.encode($out),
+                        Endian::Big => BigEndian(value).encode($out),
+                    }
+                }
+            )*
+            _ => return Err(PayloadError::WrongType),
+        }
+    };
+}
+
+impl PayloadType {
+    /// Number of bytes payloads of this type have, or None if it varies.
+    /// Unsupported sizes are returned as is, see [PayloadType::is_supported].
+    pub fn size(&self) -> Option<usize> {
+        match self {
+            PayloadType::Bool => Some(1),
+            PayloadType::UInt(size, _)
+            | PayloadType::Int(size, _)
+            | PayloadType::Float(size, _) => Some(*size),
+            PayloadType::String => None,
+            PayloadType::NodeId => Some(16),
+        }
+    }
+
+    /// False for number types with sizes which no Rust number has.
+    pub fn is_supported(&self) -> bool {
+        match self {
+            PayloadType::UInt(size, _) | PayloadType::Int(size, _) => {
+                [1, 2, 4, 8, 16].contains(size)
+            }
+            PayloadType::Float(size, _) => [4, 8].contains(size),
+            _ => true,
+        }
+    }
+
+    pub fn decode(&self, bytes: &[u8]) -> Result<Value, PayloadError> {
+        if let Some(expected) = self.size() {
+            if bytes.len() != expected {
+                return Err(PayloadError::Length {
+                    expected,
+                    actual: bytes.len(),
+                });
+            }
+        }
+        Ok(match self {
+            PayloadType::Bool => Value::Bool(bool::decode(bytes)?),
+            PayloadType::UInt(_, endian) => {
+                Value::UInt(decode_as!(bytes, endian, u128, u8, u16, u32, u64, u128))
+            }
+            PayloadType::Int(_, endian) => {
+                Value::Int(decode_as!(bytes, endian, i128, i8, i16, i32, i64, i128))
+            }
+            PayloadType::Float(_, endian) => Value::Float(decode_as!(bytes, endian, f64, f32, f64)),
+            PayloadType::String => Value::String(String::decode(bytes)?),
+            PayloadType::NodeId => Value::NodeId(NodeId::decode(bytes)?),
+        })
+    }
+
+    /// Encodes `value`, failing if it is the wrong kind of value or out of range for this type.
+    /// Floats are rounded to fit.
+    pub fn encode(&self, value: &Value, out: &mut Vec<u8>) -> Result<(), PayloadError> {
+        match (self, value) {
+            (PayloadType::Bool, Value::Bool(v)) => v.encode(out),
+            (PayloadType::UInt(size, endian), Value::UInt(v)) => {
+                encode_as!(*v, *size, endian, out, u8, u16, u32, u64, u128)
+            }
+            (PayloadType::Int(size, endian), Value::Int(v)) => {
+                encode_as!(*v, *size, endian, out, i8, i16, i32, i64, i128)
+            }
+            (PayloadType::Float(4, Endian::Little), Value::Float(v)) => (*v as f32).encode(out),
+            (PayloadType::Float(4, Endian::Big), Value::Float(v)) => {
+                BigEndian(*v as f32).encode(out)
+            }
+            (PayloadType::Float(8, Endian::Little), Value::Float(v)) => v.encode(out),
+            (PayloadType::Float(8, Endian::Big), Value::Float(v)) => BigEndian(*v).encode(out),
+            (PayloadType::String, Value::String(v)) => v.encode(out),
+            (PayloadType::NodeId, Value::NodeId(v)) => v.encode(out),
+            _ => return Err(PayloadError::WrongType),
+        }
+        Ok(())
+    }
+}
+
+/// Layout of a fixed size payload as a struct of named fields.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct PayloadLayout {
+    pub fields: Vec<Field>,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Field {
+    pub name: String,
+    /// Byte offset within the payload.
+    pub offset: u16,
+    /// Must have a fixed size.
+    pub payload_type: PayloadType,
+}
+
+impl PayloadLayout {
+    pub fn field(&self, name: &str) -> Option<&Field> {
+        self.fields.iter().find(|field| field.name == name)
+    }
+
+    /// True if the fields have distinct names and supported fixed sizes, and fit in payloads of `payload_size` bytes.
+    /// Fields may overlap.
+    pub fn fits(&self, payload_size: usize) -> bool {
+        self.fields.iter().enumerate().all(|(i, field)| {
+            field.payload_type.is_supported()
+                && field
+                    .payload_type
+                    .size()
+                    .is_some_and(|size| field.offset as usize + size <= payload_size)
+                && self.fields[..i].iter().all(|f| f.name != field.name)
+        })
+    }
+
+    /// Decodes all the fields of `payload`, in order.
+    pub fn decode(&self, payload: &[u8]) -> Result<Vec<(&str, Value)>, PayloadError> {
+        self.fields
+            .iter()
+            .map(|field| Ok((field.name.as_str(), field.decode(payload)?)))
+            .collect()
+    }
+}
+
+impl Field {
+    /// Bytes of the field within `payload`.
+    fn range(&self, payload_len: usize) -> Result<std::ops::Range<usize>, PayloadError> {
+        let size = self.payload_type.size().ok_or(PayloadError::WrongType)?;
+        let range = self.offset as usize..self.offset as usize + size;
+        if range.end > payload_len {
+            return Err(PayloadError::Length {
+                expected: range.end,
+                actual: payload_len,
+            });
+        }
+        Ok(range)
+    }
+
+    /// Decodes this field of `payload`.
+    pub fn decode(&self, payload: &[u8]) -> Result<Value, PayloadError> {
+        self.payload_type
+            .decode(&payload[self.range(payload.len())?])
+    }
+
+    /// Overwrites this field of `payload` with `value`.
+    pub fn encode(&self, value: &Value, payload: &mut [u8]) -> Result<(), PayloadError> {
+        let range = self.range(payload.len())?;
+        let mut bytes = vec![];
+        self.payload_type.encode(value, &mut bytes)?;
+        payload[range].copy_from_slice(&bytes);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        error::ForestError,
+        test_stuff::{point_tree, uniform_tree},
+        Forest,
+    };
+
+    fn round_trip<T: Payload + PartialEq + fmt::Debug>(value: T, bytes: &[u8]) {
+        assert_eq!(value.to_bytes(), bytes);
+        assert_eq!(T::decode(bytes).unwrap(), value);
+    }
+
+    #[test]
+    fn encodings() {
+        round_trip(true, &[1]);
+        round_trip(0x0102u16, &[2, 1]);
+        round_trip(BigEndian(0x0102u16), &[1, 2]);
+        round_trip(-2i32, &[254, 255, 255, 255]);
+        round_trip(BigEndian(1.5f32), &1.5f32.to_be_bytes());
+        round_trip(-0.25f64, &(-0.25f64).to_le_bytes());
+        round_trip("héllo".to_owned(), "héllo".as_bytes());
+        let mut id = [0; 16];
+        id[15] = 7;
+        round_trip(NodeId(7), &id);
+
+        assert_eq!(bool::decode(&[2]), Err(PayloadError::InvalidBool(2)));
+        assert_eq!(String::decode(&[0xff]), Err(PayloadError::InvalidUtf8));
+        assert_eq!(
+            u32::decode(&[1, 2]),
+            Err(PayloadError::Length {
+                expected: 4,
+                actual: 2
+            })
+        );
+    }
+
+    #[test]
+    fn forest_payloads() {
+        let (tree, root) = uniform_tree(3);
+        let mut forest = Forest::from(tree);
+        // Node 8 is in a uniform chunk, with one byte payload.
+        assert_eq!(
+            forest.get_tree(NodeId(8)).unwrap().get_payload_as(),
+            Ok(5u8)
+        );
+        forest.set_payload_from(NodeId(8), &true).unwrap();
+        assert_eq!(
+            forest.get_tree(NodeId(8)).unwrap().get_payload_as(),
+            Ok(true)
+        );
+        // Resizing splits it out of the chunk.
+        forest
+            .set_payload_from(NodeId(8), &"text".to_owned())
+            .unwrap();
+        let node = forest.get_tree(NodeId(8)).unwrap();
+        assert_eq!(node.get_payload_as(), Ok("text".to_owned()));
+        assert_eq!(
+            node.get_payload_as::<u16>(),
+            Err(PayloadError::Length {
+                expected: 2,
+                actual: 4
+            })
+        );
+
+        // The root is an indirect chunk with no payload.
+        let node = forest.get_tree(root).unwrap();
+        assert_eq!(node.get_payload_as::<u8>(), Err(PayloadError::Missing));
+        forest.set_payload_from(root, &BigEndian(9u64)).unwrap();
+        let node = forest.get_tree(root).unwrap();
+        assert_eq!(node.get_payload_as(), Ok(BigEndian(9u64)));
+        assert_eq!(node.get_payload_as(), Ok(9u64 << 56));
+    }
+
+    #[test]
+    fn payload_types() {
+        let cases = [
+            (PayloadType::Bool, Value::Bool(false)),
+            (PayloadType::UInt(2, Endian::Big), Value::UInt(300)),
+            (PayloadType::Int(8, Endian::Little), Value::Int(-5)),
+            (PayloadType::Float(4, Endian::Big), Value::Float(0.5)),
+            (PayloadType::Float(8, Endian::Little), Value::Float(0.1)),
+            (PayloadType::String, Value::String("x".to_owned())),
+            (PayloadType::NodeId, Value::NodeId(NodeId(u128::MAX))),
+        ];
+        for (payload_type, value) in cases {
+            let mut bytes = vec![];
+            payload_type.encode(&value, &mut bytes).unwrap();
+            assert_eq!(payload_type.size().unwrap_or(bytes.len()), bytes.len());
+            assert_eq!(payload_type.decode(&bytes), Ok(value));
+        }
+
+        assert_eq!(
+            PayloadType::UInt(2, Endian::Big).decode(&[1, 2]),
+            Ok(Value::UInt(0x0102))
+        );
+        let mut out = vec![];
+        assert_eq!(
+            PayloadType::UInt(1, Endian::Little).encode(&Value::UInt(256), &mut out),
+            Err(PayloadError::WrongType)
+        );
+        assert_eq!(
+            PayloadType::Bool.encode(&Value::Int(1), &mut out),
+            Err(PayloadError::WrongType)
+        );
+        assert_eq!(
+            PayloadType::UInt(3, Endian::Little).decode(&[1, 2, 3]),
+            Err(PayloadError::WrongType)
+        );
+        assert_eq!(
+            PayloadType::Int(4, Endian::Little).decode(&[1]),
+            Err(PayloadError::Length {
+                expected: 4,
+                actual: 1
+            })
+        );
+    }
+
+    #[test]
+    fn layouts() {
+        let (tree, root) = point_tree(3);
+        let mut forest = Forest::from(tree);
+        let node = forest.get_tree(NodeId(3)).unwrap();
+        let layout = node.get_payload_layout().unwrap();
+        assert_eq!(
+            layout.decode(&to_vec(node.get_payload().unwrap())),
+            Ok(vec![("x", Value::Float(2.0)), ("y", Value::Float(-2.0))])
+        );
+        assert_eq!(node.get_payload_field("y"), Ok(Value::Float(-2.0)));
+        assert_eq!(
+            node.get_payload_field("z"),
+            Err(PayloadError::UnknownField("z".to_owned()))
+        );
+        let nav = forest.forest.nav_from(NodeId(3)).unwrap();
+        assert_eq!(nav.get_payload_field("x"), Ok(Value::Float(2.0)));
+
+        forest
+            .set_payload_field(NodeId(3), "x", &Value::Float(0.5))
+            .unwrap();
+        let node = forest.get_tree(NodeId(3)).unwrap();
+        assert_eq!(node.get_payload_field("x"), Ok(Value::Float(0.5)));
+        assert_eq!(node.get_payload_field("y"), Ok(Value::Float(-2.0)));
+        // Still in the chunk, so it keeps its layout.
+        assert_eq!(forest.forest.map_keys().count(), 2);
+
+        assert_eq!(
+            forest.set_payload_field(NodeId(3), "x", &Value::Int(1)),
+            Err(ForestError::InvalidPayload(
+                NodeId(3),
+                PayloadError::WrongType
+            ))
+        );
+        assert_eq!(
+            forest.set_payload_field(root, "x", &Value::Float(1.0)),
+            Err(ForestError::InvalidPayload(
+                root,
+                PayloadError::UnknownField("x".to_owned())
+            ))
+        );
+    }
+
+    #[test]
+    fn layout_fits() {
+        let field = |name: &str, offset, payload_type| Field {
+            name: name.to_owned(),
+            offset,
+            payload_type,
+        };
+        let mut layout = PayloadLayout {
+            fields: vec![
+                field("a", 0, PayloadType::UInt(2, Endian::Big)),
+                field("b", 2, PayloadType::Bool),
+            ],
+        };
+        assert!(layout.fits(3));
+        assert!(!layout.fits(2));
+        layout.fields.push(field("a", 0, PayloadType::Bool));
+        assert!(!layout.fits(3));
+        layout.fields[2] = field("c", 0, PayloadType::String);
+        assert!(!layout.fits(3));
+        layout.fields[2] = field("c", 0, PayloadType::Int(3, Endian::Big));
+        assert!(!layout.fits(3));
+    }
+}
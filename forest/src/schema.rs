@@ -0,0 +1,369 @@
+//! Schema giving meaning to [Def]s: which traits and payload nodes of each [Def] may have.
+//!
+//! Use [crate::Forest::validate_against] to check a forest against a [Schema].
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    ops::RangeInclusive,
+};
+
+use crate::{
+    node_id::{HasId, NodeId},
+    payload::{self, PayloadError, PayloadType, Value},
+    tree::{Def, Label, Node, NodeData},
+};
+
+/// Allowed content for each [Def]. Nodes with [Def]s not in `defs` are violations.
+#[derive(Clone, Default, Debug)]
+pub struct Schema {
+    pub defs: HashMap<Def, NodeSchema>,
+}
+
+/// Allowed content of nodes of one [Def].
+#[derive(Clone, Default, Debug)]
+pub struct NodeSchema {
+    /// Allowed traits. Traits not listed here must be empty.
+    pub traits: HashMap<Label, TraitSchema>,
+    pub payload: PayloadSchema,
+}
+
+#[derive(Clone, Debug)]
+pub struct TraitSchema {
+    /// Allowed number of children.
+    pub count: RangeInclusive<usize>,
+    /// Allowed [Def]s of children, or None to allow any.
+    pub defs: Option<HashSet<Def>>,
+}
+
+impl TraitSchema {
+    /// Any number of children of the given [Def]s.
+    pub fn sequence(defs: impl IntoIterator<Item = Def>) -> Self {
+        TraitSchema {
+            count: 0..=usize::MAX,
+            defs: Some(defs.into_iter().collect()),
+        }
+    }
+
+    /// Exactly one child of the given [Def]s.
+    pub fn value(defs: impl IntoIterator<Item = Def>) -> Self {
+        TraitSchema {
+            count: 1..=1,
+            ..Self::sequence(defs)
+        }
+    }
+
+    /// Zero or one child of the given [Def]s.
+    pub fn optional(defs: impl IntoIterator<Item = Def>) -> Self {
+        TraitSchema {
+            count: 0..=1,
+            ..Self::sequence(defs)
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Default, Debug)]
+pub enum PayloadSchema {
+    /// No payload allowed.
+    #[default]
+    None,
+    /// Optional payload of any size.
+    Any,
+    /// Payload required, with a length (in bytes) in the range.
+    Bytes(RangeInclusive<usize>),
+    /// Payload required, which must decode as the type.
+    Typed(PayloadType),
+}
+
+/// A way a node does not match a [Schema].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Violation {
+    pub node: NodeId,
+    pub kind: ViolationKind,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ViolationKind {
+    /// The node's [Def] is not in the schema. Its content is not checked.
+    UnknownDef(Def),
+    /// A non-empty trait which the node's [Def] does not allow.
+    UnexpectedTrait(Label),
+    ChildCount {
+        label: Label,
+        count: usize,
+    },
+    ChildDef {
+        label: Label,
+        child: NodeId,
+        def: Def,
+    },
+    UnexpectedPayload,
+    MissingPayload,
+    PayloadSize(usize),
+    InvalidPayload(PayloadError),
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "node {:?}: ", self.node)?;
+        match &self.kind {
+            ViolationKind::UnknownDef(def) => write!(f, "{:?} is not in the schema", def),
+            ViolationKind::UnexpectedTrait(label) => write!(f, "trait {:?} is not allowed", label),
+            ViolationKind::ChildCount { label, count } => {
+                write!(f, "{} children is not allowed in trait {:?}", count, label)
+            }
+            ViolationKind::ChildDef { label, child, def } => write!(
+                f,
+                "child {:?} in trait {:?} has {:?}, which is not allowed there",
+                child, label, def
+            ),
+            ViolationKind::UnexpectedPayload => write!(f, "payload is not allowed"),
+            ViolationKind::MissingPayload => write!(f, "payload is required"),
+            ViolationKind::PayloadSize(len) => write!(f, "payload of {} bytes is not allowed", len),
+            ViolationKind::InvalidPayload(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl Schema {
+    /// Decodes the payload of `node` using the [PayloadType] of its [Def].
+    /// None if the schema does not give its [Def] a [PayloadType].
+    pub fn decode_payload(&self, node: &impl NodeData) -> Option<Result<Value, PayloadError>> {
+        match self.defs.get(&node.get_def())?.payload {
+            PayloadSchema::Typed(payload_type) => Some(match node.get_payload() {
+                Some(payload) => payload_type.decode(&payload::to_vec(payload)),
+                None => Err(PayloadError::Missing),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Checks `root` and everything under it, adding all violations to `out` (in depth first pre-order).
+    pub fn validate<N: Node<N> + HasId>(&self, root: N, out: &mut Vec<Violation>) {
+        let mut stack = vec![root];
+        while let Some(node) = stack.pop() {
+            let id = node.get_id();
+            let mut violation = |kind| out.push(Violation { node: id, kind });
+            let schema = match self.defs.get(&node.get_def()) {
+                Some(schema) => Some(schema),
+                None => {
+                    violation(ViolationKind::UnknownDef(node.get_def()));
+                    None
+                }
+            };
+
+            if let Some(schema) = schema {
+                let len = node.get_payload().map(|p| p.len());
+                match (&schema.payload, len) {
+                    (PayloadSchema::None, Some(_)) => violation(ViolationKind::UnexpectedPayload),
+                    (PayloadSchema::Bytes(_) | PayloadSchema::Typed(_), None) => {
+                        violation(ViolationKind::MissingPayload)
+                    }
+                    (PayloadSchema::Typed(_), Some(_)) => {
+                        if let Some(Err(error)) = self.decode_payload(&node) {
+                            violation(ViolationKind::InvalidPayload(error))
+                        }
+                    }
+                    (PayloadSchema::Bytes(range), Some(len)) if !range.contains(&len) => {
+                        violation(ViolationKind::PayloadSize(len))
+                    }
+                    _ => {}
+                }
+
+                // Required traits can be missing from the node.
+                let mut labels: Vec<Label> = node
+                    .get_traits()
+                    .chain(schema.traits.keys().cloned())
+                    .collect();
+                labels.sort();
+                labels.dedup();
+                for label in labels {
+                    let count = node.get_trait(label).count();
+                    match schema.traits.get(&label) {
+                        None if count > 0 => violation(ViolationKind::UnexpectedTrait(label)),
+                        None => {}
+                        Some(schema) => {
+                            if !schema.count.contains(&count) {
+                                violation(ViolationKind::ChildCount { label, count });
+                            }
+                            for child in node.get_trait(label) {
+                                let def = child.get_def();
+                                if !schema.defs.as_ref().is_none_or(|d| d.contains(&def)) {
+                                    violation(ViolationKind::ChildDef {
+                                        label,
+                                        child: child.get_id(),
+                                        def,
+                                    });
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+
+            let children: Vec<N> = {
+                let mut labels: Vec<Label> = node.get_traits().collect();
+                labels.sort();
+                labels
+                    .into_iter()
+                    .flat_map(|label| node.get_trait(label))
+                    .collect()
+            };
+            stack.extend(children.into_iter().rev());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_stuff::{uniform_tree, IdNode, CHANNELS, COLORS},
+        Forest,
+    };
+
+    fn schema() -> Schema {
+        let channels = CHANNELS
+            .iter()
+            .map(|label| (*label, TraitSchema::value([Def(3)])))
+            .collect();
+        Schema {
+            defs: [
+                (
+                    Def(1),
+                    NodeSchema {
+                        traits: [(COLORS, TraitSchema::sequence([Def(2)]))].into(),
+                        payload: PayloadSchema::None,
+                    },
+                ),
+                (
+                    Def(2),
+                    NodeSchema {
+                        traits: channels,
+                        payload: PayloadSchema::None,
+                    },
+                ),
+                (
+                    Def(3),
+                    NodeSchema {
+                        traits: HashMap::new(),
+                        payload: PayloadSchema::Bytes(1..=1),
+                    },
+                ),
+            ]
+            .into(),
+        }
+    }
+
+    fn at(node: u128, kind: ViolationKind) -> Violation {
+        Violation {
+            node: NodeId(node),
+            kind,
+        }
+    }
+
+    #[test]
+    fn valid() {
+        let (tree, _) = uniform_tree(3);
+        assert_eq!(Forest::from(tree).validate_against(&schema()), vec![]);
+    }
+
+    #[test]
+    fn typed_payloads() {
+        let (tree, _) = uniform_tree(3);
+        let mut forest = Forest::from(tree);
+        let mut schema = schema();
+        schema.defs.get_mut(&Def(3)).unwrap().payload = PayloadSchema::Typed(PayloadType::Bool);
+
+        let node = forest.get_tree(NodeId(3)).unwrap();
+        assert_eq!(schema.decode_payload(&node), Some(Ok(Value::Bool(true))));
+        let root = forest.get_tree(NodeId(0)).unwrap();
+        assert_eq!(schema.decode_payload(&root), None);
+
+        // Payloads are 0 to 11, so all but nodes 2 (0) and 3 (1) are invalid.
+        forest.set_value(NodeId(4), &[]).unwrap();
+        let violations = forest.validate_against(&schema);
+        assert_eq!(violations.len(), 10);
+        assert_eq!(
+            violations[0],
+            at(
+                4,
+                ViolationKind::InvalidPayload(PayloadError::Length {
+                    expected: 1,
+                    actual: 0
+                })
+            )
+        );
+        assert_eq!(
+            violations[1],
+            at(
+                5,
+                ViolationKind::InvalidPayload(PayloadError::InvalidBool(3))
+            )
+        );
+    }
+
+    #[test]
+    fn violations() {
+        let (tree, _) = uniform_tree(3);
+        let mut forest = Forest::from(tree);
+        forest.set_value(NodeId(8), &[1, 2]).unwrap();
+        forest
+            .insert_or_replace_node(IdNode {
+                id: NodeId(1000),
+                def: Def(100),
+                payload: None,
+                traits: HashMap::new(),
+            })
+            .unwrap();
+
+        let mut schema = schema();
+        let root = schema.defs.get_mut(&Def(1)).unwrap();
+        root.traits.insert(COLORS, TraitSchema::value([Def(2)]));
+        root.traits.insert(Label(99), TraitSchema::value([]));
+        let color = schema.defs.get_mut(&Def(2)).unwrap();
+        color.traits.remove(&CHANNELS[3]);
+        color.traits.get_mut(&CHANNELS[0]).unwrap().defs = Some([Def(4)].into());
+
+        let wrong_channel = |node, child| {
+            [
+                at(
+                    node,
+                    ViolationKind::ChildDef {
+                        label: CHANNELS[0],
+                        child: NodeId(child),
+                        def: Def(3),
+                    },
+                ),
+                at(node, ViolationKind::UnexpectedTrait(CHANNELS[3])),
+            ]
+        };
+        let mut expected = vec![
+            at(
+                0,
+                ViolationKind::ChildCount {
+                    label: COLORS,
+                    count: 3,
+                },
+            ),
+            at(
+                0,
+                ViolationKind::ChildCount {
+                    label: Label(99),
+                    count: 0,
+                },
+            ),
+        ];
+        expected.extend(wrong_channel(1, 2));
+        expected.extend(wrong_channel(6, 7));
+        expected.push(at(8, ViolationKind::PayloadSize(2)));
+        expected.extend(wrong_channel(11, 12));
+        expected.push(at(1000, ViolationKind::UnknownDef(Def(100))));
+        let violations = forest.validate_against(&schema);
+        assert_eq!(violations, expected);
+        assert_eq!(
+            violations[0].to_string(),
+            "node NodeId(0): 3 children is not allowed in trait Label(1)"
+        );
+    }
+}
@@ -0,0 +1,938 @@
+//! Binary snapshots of a [Forest].
+//!
+//! The format is (all integers little endian):
+//! - [MAGIC], then the format [VERSION] as a u32 (versions back to [MIN_VERSION] can be read).
+//! - Def table, then Label table: a u32 count, then that many u128s.
+//! - Schema table: a u32 count, then that many [ChunkSchema]s. Schema only refer to earlier schema (for their traits).
+//! - Chunks: a u64 count, then that many chunks in id order.
+//!
+//! [Def]s, [Label]s and [ChunkSchema]s are deduplicated and referred to by their u32 index in their table.
+//! [UniformChunk] data is stored as a raw blob of bytes.
+//!
+//! Loading checks the snapshot is well formed (including that the chunks form a valid forest),
+//! so untrusted snapshots can be loaded.
+//! Schemas must keep the ids and bytes of their nodes within u32 offsets, their `id_stride` within [MAX_ID_STRIDE],
+//! and their nesting within [MAX_SCHEMA_DEPTH]. Traits in schemas must not be empty.
+//! This bounds the memory and recursion used loading them, since each node in a schema then has its own id.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::Hash,
+    io::{self, Read, Write},
+};
+
+use crate::{
+    chunk::ChunkId,
+    error::ForestError,
+    indirect::enum_chunk,
+    indirect_nav::Forest,
+    indirect_node::IndirectChunk,
+    node_id::{IdOffset, NodeId},
+    payload::{Endian, Field, PayloadLayout, PayloadType},
+    sync::Shared,
+    tree::{Def, Label},
+    uniform_chunk::{
+        ChunkSchema, OffsetSchema, RootChunkSchema, UniformChunk, MAX_ID_STRIDE, MAX_SCHEMA_DEPTH,
+    },
+    util::ImHashMap,
+};
+
+pub const MAGIC: [u8; 4] = *b"FRST";
+/// Version 2 added [ChunkSchema::payload_layout], and version 3 [IndirectChunk::payload_layout].
+pub const VERSION: u32 = 3;
+/// Oldest version which can still be read: snapshots are always written as [VERSION].
+pub const MIN_VERSION: u32 = 1;
+
+const INDIRECT: u8 = 0;
+const UNIFORM: u8 = 1;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    /// The input does not start with [MAGIC].
+    NotASnapshot,
+    UnsupportedVersion(u32),
+    /// The snapshot is truncated or contains invalid data.
+    Malformed(&'static str),
+    /// The chunks in the snapshot do not form a valid forest.
+    Forest(ForestError),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "io error reading snapshot: {}", e),
+            SnapshotError::NotASnapshot => write!(f, "not a snapshot"),
+            SnapshotError::UnsupportedVersion(v) => {
+                write!(f, "unsupported snapshot version {}", v)
+            }
+            SnapshotError::Malformed(what) => write!(f, "malformed snapshot: {}", what),
+            SnapshotError::Forest(e) => write!(f, "invalid forest in snapshot: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+impl From<ForestError> for SnapshotError {
+    fn from(e: ForestError) -> Self {
+        SnapshotError::Forest(e)
+    }
+}
+
+/// Writes a snapshot of `forest` to `out`.
+pub fn write(forest: &Forest, out: &mut impl Write) -> io::Result<()> {
+    write_chunks(
+        forest
+            .map_keys()
+            .map(|id| (*id, forest.find_nodes(*id).unwrap())),
+        out,
+    )
+}
+
+/// Reads a snapshot written by [write].
+/// Chunks with the same schema share a single [RootChunkSchema].
+pub fn read(input: &mut impl Read) -> Result<Forest, SnapshotError> {
+    let mut forest = Forest::new();
+    for (id, chunk) in read_chunks(input)? {
+        forest.try_insert(id, chunk)?;
+    }
+    forest.validate()?;
+    Ok(forest)
+}
+
+/// Writes a snapshot containing `chunks`, which do not need to form a complete forest.
+pub fn write_chunks<'a>(
+    chunks: impl IntoIterator<Item = (ChunkId, &'a enum_chunk::Chunk)>,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let mut writer = Writer::default();
+    let mut count = 0;
+    let mut body = vec![];
+    for (id, chunk) in chunks {
+        count += 1;
+        put_u128(&mut body, id.0 .0);
+        match chunk {
+            enum_chunk::Chunk::Indirect(c) => writer.indirect(&mut body, c),
+            enum_chunk::Chunk::Uniform(c) => writer.uniform(&mut body, c),
+        }
+    }
+
+    let mut header = MAGIC.to_vec();
+    put_u32(&mut header, VERSION);
+    for table in [&writer.defs.items, &writer.labels.items] {
+        put_u32(&mut header, table.len() as u32);
+        for item in table.iter() {
+            put_u128(&mut header, *item);
+        }
+    }
+    put_u32(&mut header, writer.schema_count);
+    out.write_all(&header)?;
+    out.write_all(&writer.schemas)?;
+    let mut count_bytes = vec![];
+    put_u64(&mut count_bytes, count);
+    out.write_all(&count_bytes)?;
+    out.write_all(&body)
+}
+
+/// Reads the chunks from a snapshot written by [write] or [write_chunks].
+/// Unlike [read], this does not check that the chunks form a valid forest.
+pub fn read_chunks(
+    input: &mut impl Read,
+) -> Result<Vec<(ChunkId, enum_chunk::Chunk)>, SnapshotError> {
+    let mut data = vec![];
+    input.read_to_end(&mut data)?;
+    let mut input = Input { data: &data };
+
+    if input.take(MAGIC.len())? != MAGIC {
+        return Err(SnapshotError::NotASnapshot);
+    }
+    let version = input.version()?;
+
+    let defs: Vec<Def> = (0..input.u32()?)
+        .map(|_| input.u128().map(Def))
+        .collect::<Result<_, _>>()?;
+    let labels: Vec<Label> = (0..input.u32()?)
+        .map(|_| input.u128().map(Label))
+        .collect::<Result<_, _>>()?;
+    let mut schemas: Vec<(Shared<ChunkSchema>, u32)> = vec![];
+    for _ in 0..input.u32()? {
+        let schema = input.schema(version, &defs, &labels, &schemas)?;
+        schemas.push(schema);
+    }
+
+    let mut roots: Vec<Option<Shared<RootChunkSchema>>> = vec![None; schemas.len()];
+    let mut chunks = vec![];
+    for _ in 0..input.u64()? {
+        let id = input.chunk_id()?;
+        let chunk = match input.u8()? {
+            INDIRECT => input.indirect(version, &defs, &labels)?.into(),
+            UNIFORM => {
+                let index = input.index(schemas.len())?;
+                let root = roots[index]
+                    .get_or_insert_with(|| {
+                        Shared::new(RootChunkSchema::new((*schemas[index].0).clone()))
+                    })
+                    .clone();
+                let size = root.schema.node_count as u64 * root.schema.bytes_per_node as u64;
+                if input.u64()? != size {
+                    return Err(SnapshotError::Forest(ForestError::SchemaMismatch(id)));
+                }
+                let data = input.take(size as usize)?;
+                UniformChunk {
+                    data: Box::new(data.iter().cloned().collect()),
+                    schema: root,
+                }
+                .into()
+            }
+            _ => return Err(SnapshotError::Malformed("unknown chunk type")),
+        };
+        chunks.push((id, chunk));
+    }
+    if !input.data.is_empty() {
+        return Err(SnapshotError::Malformed("trailing data"));
+    }
+    Ok(chunks)
+}
+
+/// Deduplicated values, in the order they were first added.
+struct Table<T> {
+    items: Vec<T>,
+    index: HashMap<T, u32>,
+}
+
+impl<T> Default for Table<T> {
+    fn default() -> Self {
+        Table {
+            items: vec![],
+            index: HashMap::default(),
+        }
+    }
+}
+
+impl<T: Hash + Eq + Clone> Table<T> {
+    fn add(&mut self, item: &T) -> u32 {
+        if let Some(index) = self.index.get(item) {
+            return *index;
+        }
+        let index = self.items.len() as u32;
+        self.items.push(item.clone());
+        self.index.insert(item.clone(), index);
+        index
+    }
+}
+
+#[derive(Default)]
+struct Writer {
+    defs: Table<u128>,
+    labels: Table<u128>,
+    schema_index: HashMap<ChunkSchema, u32>,
+    /// Index of already written [RootChunkSchema]s, to avoid hashing their schema for every chunk.
+    root_index: HashMap<*const RootChunkSchema, u32>,
+    schema_count: u32,
+    schemas: Vec<u8>,
+}
+
+impl Writer {
+    fn indirect(&mut self, out: &mut Vec<u8>, chunk: &IndirectChunk) {
+        out.push(INDIRECT);
+        put_u32(out, self.defs.add(&chunk.def.0));
+        match &chunk.payload {
+            Some(payload) => {
+                out.push(1);
+                put_u32(out, payload.len() as u32);
+                out.extend(payload.iter());
+            }
+            None => out.push(0),
+        }
+        match &chunk.payload_layout {
+            Some(layout) => {
+                out.push(1);
+                put_layout(out, layout);
+            }
+            None => out.push(0),
+        }
+        let mut traits: Vec<_> = chunk.traits.iter().collect();
+        traits.sort_by_key(|(label, _)| **label);
+        put_u32(out, traits.len() as u32);
+        for (label, children) in traits {
+            put_u32(out, self.labels.add(&label.0));
+            put_u32(out, children.len() as u32);
+            for child in children {
+                put_u128(out, child.0 .0);
+            }
+        }
+    }
+
+    fn uniform(&mut self, out: &mut Vec<u8>, chunk: &UniformChunk) {
+        out.push(UNIFORM);
+        let index = match self.root_index.get(&Shared::as_ptr(&chunk.schema)) {
+            Some(index) => *index,
+            None => {
+                let index = self.schema(&chunk.schema.schema);
+                self.root_index.insert(Shared::as_ptr(&chunk.schema), index);
+                index
+            }
+        };
+        put_u32(out, index);
+        put_u64(out, chunk.data.len() as u64);
+        out.extend(chunk.data.iter());
+    }
+
+    /// Adds `schema` (and the schema of its traits) to the schema table, returning its index.
+    fn schema(&mut self, schema: &ChunkSchema) -> u32 {
+        if let Some(index) = self.schema_index.get(schema) {
+            return *index;
+        }
+        let mut traits: Vec<_> = schema.traits.iter().collect();
+        traits.sort_by_key(|(label, _)| **label);
+        let traits: Vec<_> = traits
+            .into_iter()
+            .map(|(label, offset)| {
+                (
+                    self.labels.add(&label.0),
+                    offset,
+                    self.schema(&offset.schema),
+                )
+            })
+            .collect();
+
+        let out = &mut self.schemas;
+        put_u32(out, self.defs.add(&schema.def.0));
+        put_u32(out, schema.node_count);
+        put_u32(out, schema.bytes_per_node);
+        put_u32(out, schema.id_stride);
+        match schema.payload_size {
+            Some(size) => {
+                out.push(1);
+                out.extend(size.to_le_bytes());
+            }
+            None => out.push(0),
+        }
+        match &schema.payload_layout {
+            Some(layout) => {
+                out.push(1);
+                put_layout(out, layout);
+            }
+            None => out.push(0),
+        }
+        put_u32(out, traits.len() as u32);
+        for (label, offset, index) in traits {
+            put_u32(out, label);
+            put_u32(out, offset.id_offset.0);
+            put_u32(out, offset.byte_offset);
+            put_u32(out, index);
+        }
+
+        let index = self.schema_count;
+        self.schema_count += 1;
+        self.schema_index.insert(schema.clone(), index);
+        index
+    }
+}
+
+pub(crate) fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend(value.to_le_bytes());
+}
+
+pub(crate) fn put_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend(value.to_le_bytes());
+}
+
+pub(crate) fn put_u128(out: &mut Vec<u8>, value: u128) {
+    out.extend(value.to_le_bytes());
+}
+
+/// Unread part of a snapshot.
+pub(crate) struct Input<'a> {
+    pub data: &'a [u8],
+}
+
+impl<'a> Input<'a> {
+    pub(crate) fn take(&mut self, count: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.data.len() < count {
+            return Err(SnapshotError::Malformed("unexpected end of snapshot"));
+        }
+        let (result, rest) = self.data.split_at(count);
+        self.data = rest;
+        Ok(result)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16, SnapshotError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u128(&mut self) -> Result<u128, SnapshotError> {
+        Ok(u128::from_le_bytes(self.take(16)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn chunk_id(&mut self) -> Result<ChunkId, SnapshotError> {
+        let id = self.u128()?;
+        // Leave room to compute the end of the chunk's id range without overflow.
+        if id.checked_add(u32::MAX as u128 + 1).is_none() {
+            return Err(SnapshotError::Malformed("chunk id out of range"));
+        }
+        Ok(ChunkId(NodeId(id)))
+    }
+
+    /// Reads an index into a table with `len` items.
+    pub(crate) fn index(&mut self, len: usize) -> Result<usize, SnapshotError> {
+        let index = self.u32()? as usize;
+        if index < len {
+            Ok(index)
+        } else {
+            Err(SnapshotError::Malformed("index out of range"))
+        }
+    }
+
+    /// Reads a format version, checking it is one which can be read.
+    pub(crate) fn version(&mut self) -> Result<u32, SnapshotError> {
+        let version = self.u32()?;
+        if !(MIN_VERSION..=VERSION).contains(&version) {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        Ok(version)
+    }
+
+    pub(crate) fn flag(&mut self) -> Result<bool, SnapshotError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SnapshotError::Malformed("invalid flag")),
+        }
+    }
+
+    fn schema(
+        &mut self,
+        version: u32,
+        defs: &[Def],
+        labels: &[Label],
+        schemas: &[(Shared<ChunkSchema>, u32)],
+    ) -> Result<(Shared<ChunkSchema>, u32), SnapshotError> {
+        let def = defs[self.index(defs.len())?];
+        let node_count = self.u32()?;
+        let bytes_per_node = self.u32()?;
+        let id_stride = self.u32()?;
+        let payload_size = if self.flag()? {
+            Some(self.u16()?)
+        } else {
+            None
+        };
+        // Version 1 has no layout flag.
+        let payload_layout = if version >= 2 && self.flag()? {
+            Some(self.layout()?)
+        } else {
+            None
+        };
+        let mut traits = std::collections::HashMap::default();
+        // Number of levels of nodes in the schema.
+        let mut depth = 1;
+        for _ in 0..self.u32()? {
+            let label = labels[self.index(labels.len())?];
+            let id_offset = IdOffset(self.u32()?);
+            let byte_offset = self.u32()?;
+            let (schema, trait_depth) = schemas[self.index(schemas.len())?].clone();
+            depth = depth.max(trait_depth + 1);
+            let offset = OffsetSchema {
+                id_offset,
+                byte_offset,
+                schema,
+            };
+            if traits.insert(label, offset).is_some() {
+                return Err(SnapshotError::Malformed("duplicate trait"));
+            }
+        }
+        let schema = ChunkSchema {
+            def,
+            node_count,
+            bytes_per_node,
+            id_stride,
+            payload_size,
+            payload_layout,
+            traits,
+        };
+        if depth > MAX_SCHEMA_DEPTH {
+            return Err(SnapshotError::Malformed("schema nested too deeply"));
+        }
+        check_layout(&schema)?;
+        Ok((Shared::new(schema), depth))
+    }
+
+    fn layout(&mut self) -> Result<PayloadLayout, SnapshotError> {
+        let fields = (0..self.u32()?)
+            .map(|_| {
+                let len = self.u32()? as usize;
+                let name = String::from_utf8(self.take(len)?.to_vec())
+                    .map_err(|_| SnapshotError::Malformed("invalid field name"))?;
+                let offset = self.u16()?;
+                let payload_type = match self.u8()? {
+                    0 => PayloadType::Bool,
+                    1 => PayloadType::UInt(self.u8()? as usize, self.endian()?),
+                    2 => PayloadType::Int(self.u8()? as usize, self.endian()?),
+                    3 => PayloadType::Float(self.u8()? as usize, self.endian()?),
+                    4 => PayloadType::String,
+                    5 => PayloadType::NodeId,
+                    _ => return Err(SnapshotError::Malformed("unknown payload type")),
+                };
+                Ok(Field {
+                    name,
+                    offset,
+                    payload_type,
+                })
+            })
+            .collect::<Result<_, SnapshotError>>()?;
+        Ok(PayloadLayout { fields })
+    }
+
+    fn endian(&mut self) -> Result<Endian, SnapshotError> {
+        Ok(if self.flag()? {
+            Endian::Big
+        } else {
+            Endian::Little
+        })
+    }
+
+    fn indirect(
+        &mut self,
+        version: u32,
+        defs: &[Def],
+        labels: &[Label],
+    ) -> Result<IndirectChunk, SnapshotError> {
+        let def = defs[self.index(defs.len())?];
+        let payload = if self.flag()? {
+            let len = self.u32()? as usize;
+            Some(Box::new(
+                self.take(len)?.iter().cloned().collect::<im::Vector<u8>>(),
+            ))
+        } else {
+            None
+        };
+        // Versions before 3 have no layout flag.
+        let payload_layout = if version >= 3 && self.flag()? {
+            let layout = self.layout()?;
+            match &payload {
+                Some(payload) if layout.fits(payload.len()) => Some(Shared::new(layout)),
+                _ => return Err(SnapshotError::Malformed("invalid payload layout")),
+            }
+        } else {
+            None
+        };
+        let mut traits = ImHashMap::default();
+        for _ in 0..self.u32()? {
+            let label = labels[self.index(labels.len())?];
+            let children = (0..self.u32()?)
+                .map(|_| self.u128().map(|id| ChunkId(NodeId(id))))
+                .collect::<Result<Vec<_>, _>>()?;
+            if traits.insert(label, children).is_some() {
+                return Err(SnapshotError::Malformed("duplicate trait"));
+            }
+        }
+        Ok(IndirectChunk {
+            def,
+            payload,
+            payload_layout,
+            traits,
+        })
+    }
+}
+
+fn put_layout(out: &mut Vec<u8>, layout: &PayloadLayout) {
+    put_u32(out, layout.fields.len() as u32);
+    for field in layout.fields.iter() {
+        put_u32(out, field.name.len() as u32);
+        out.extend(field.name.as_bytes());
+        out.extend(field.offset.to_le_bytes());
+        let number = |out: &mut Vec<u8>, tag: u8, size: usize, endian: Endian| {
+            out.extend([tag, size as u8, (endian == Endian::Big) as u8]);
+        };
+        match field.payload_type {
+            PayloadType::Bool => out.push(0),
+            PayloadType::UInt(size, endian) => number(out, 1, size, endian),
+            PayloadType::Int(size, endian) => number(out, 2, size, endian),
+            PayloadType::Float(size, endian) => number(out, 3, size, endian),
+            PayloadType::String => out.push(4),
+            PayloadType::NodeId => out.push(5),
+        }
+    }
+}
+
+/// Checks that the traits of `schema` are not empty and fit within (and don't overlap in) its ids and bytes,
+/// and its payload layout fits in its payload.
+/// The schema of the traits are assumed to already be checked.
+///
+/// [RootChunkSchema::new] requires this.
+fn check_layout(schema: &ChunkSchema) -> Result<(), SnapshotError> {
+    let malformed = Err(SnapshotError::Malformed("invalid schema layout"));
+    if schema.id_stride == 0
+        || schema.id_stride > MAX_ID_STRIDE
+        || schema.payload_size.unwrap_or(0) as u32 > schema.bytes_per_node
+        || schema.node_count as u64 * schema.id_stride as u64 > u32::MAX as u64
+        || schema.node_count as u64 * schema.bytes_per_node as u64 > u32::MAX as u64
+    {
+        return malformed;
+    }
+    if let Some(layout) = &schema.payload_layout {
+        match schema.payload_size {
+            Some(size) if layout.fits(size as usize) => {}
+            _ => return malformed,
+        }
+    }
+
+    let mut traits: Vec<&OffsetSchema> = schema.traits.values().collect();
+    traits.sort_by_key(|t| t.id_offset);
+    let mut next_id = 1;
+    for t in traits.iter() {
+        // Empty traits use no ids, so would allow schemas far larger than their `id_stride`.
+        if t.schema.node_count == 0 {
+            return malformed;
+        }
+        let end = t.id_offset.0 as u64 + t.schema.node_count as u64 * t.schema.id_stride as u64;
+        if (t.id_offset.0 as u64) < next_id || end > schema.id_stride as u64 {
+            return malformed;
+        }
+        next_id = end;
+    }
+
+    traits.sort_by_key(|t| t.byte_offset);
+    let mut next_byte = schema.payload_size.unwrap_or(0) as u64;
+    for t in traits.iter() {
+        let end =
+            t.byte_offset as u64 + t.schema.node_count as u64 * t.schema.bytes_per_node as u64;
+        if (t.byte_offset as u64) < next_byte || end > schema.bytes_per_node as u64 {
+            return malformed;
+        }
+        next_byte = end;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        chunker,
+        example_node::BasicNode,
+        test_stuff::{check_parents, point_tree, uniform_tree, walk_all},
+        tree::NodeData,
+        ShapeLibrary,
+    };
+
+    fn round_trip(forest: &Forest) -> Forest {
+        let mut data = vec![];
+        write(forest, &mut data).unwrap();
+        read(&mut data.as_slice()).unwrap()
+    }
+
+    fn uniform(forest: &Forest, id: u128) -> &UniformChunk {
+        match forest.find_nodes(ChunkId(NodeId(id))) {
+            Some(enum_chunk::Chunk::Uniform(c)) => c,
+            _ => panic!(),
+        }
+    }
+
+    fn assert_same(a: &Forest, b: &Forest) {
+        assert!(a.map_keys().eq(b.map_keys()));
+        for id in a.map_keys() {
+            match (a.find_nodes(*id).unwrap(), b.find_nodes(*id).unwrap()) {
+                (enum_chunk::Chunk::Indirect(a), enum_chunk::Chunk::Indirect(b)) => {
+                    assert!(a == b)
+                }
+                (enum_chunk::Chunk::Uniform(a), enum_chunk::Chunk::Uniform(b)) => {
+                    assert!(a.schema.schema == b.schema.schema);
+                    assert_eq!(a.data, b.data);
+                }
+                _ => panic!("chunk types differ"),
+            }
+        }
+    }
+
+    #[test]
+    fn uniform_tree_round_trip() {
+        let (forest, root) = uniform_tree(3);
+        let loaded = round_trip(&forest);
+        assert_same(&forest, &loaded);
+        let nav = loaded.nav_from(root).unwrap();
+        assert_eq!(walk_all(nav.clone()), 16);
+        check_parents(nav);
+    }
+
+    #[test]
+    fn payload_layouts() {
+        let (mut forest, _) = point_tree(3);
+        let layout = uniform(&forest, 1).schema.schema.payload_layout.clone();
+        let point = |payload: &[u8]| {
+            IndirectChunk {
+                def: Def(4),
+                payload: Some(Box::new(payload.iter().cloned().collect())),
+                payload_layout: layout.clone().map(Shared::new),
+                traits: ImHashMap::default(),
+            }
+            .into()
+        };
+        forest.insert(ChunkId(NodeId(100)), point(&[0; 8]));
+        let loaded = round_trip(&forest);
+        assert_same(&forest, &loaded);
+        assert!(uniform(&loaded, 1).schema.schema.payload_layout.is_some());
+        let node = loaded.find_node(NodeId(100)).unwrap();
+        assert!(node.get_payload_layout().is_some());
+
+        forest.insert(ChunkId(NodeId(100)), point(&[0; 6]));
+        let mut data = vec![];
+        write(&forest, &mut data).unwrap();
+        assert!(matches!(
+            read(&mut data.as_slice()),
+            Err(SnapshotError::Malformed(_))
+        ));
+
+        // Layouts must fit in the payload.
+        let mut schema = uniform(&forest, 1).schema.schema.clone();
+        schema.payload_size = Some(6);
+        assert!(check_layout(&schema).is_err());
+        schema.payload_size = None;
+        assert!(check_layout(&schema).is_err());
+    }
+
+    #[test]
+    fn rejects_oversized_schema() {
+        let schema = |node_count: u32, id_stride: u32| ChunkSchema {
+            def: Def(1),
+            node_count,
+            bytes_per_node: 0,
+            id_stride,
+            payload_size: None,
+            payload_layout: None,
+            traits: std::collections::HashMap::default(),
+        };
+        assert!(check_layout(&schema(u32::MAX, 1)).is_ok());
+        assert!(check_layout(&schema(1, MAX_ID_STRIDE)).is_ok());
+        assert!(check_layout(&schema(1, MAX_ID_STRIDE + 1)).is_err());
+
+        // Two empty chunks whose ids would overflow a u32.
+        let chunk: enum_chunk::Chunk = UniformChunk {
+            data: Box::default(),
+            schema: Shared::new(RootChunkSchema::new(schema(u32::MAX, 2))),
+        }
+        .into();
+        let mut data = vec![];
+        write_chunks(
+            [(ChunkId(NodeId(0)), &chunk), (ChunkId(NodeId(1)), &chunk)],
+            &mut data,
+        )
+        .unwrap();
+        assert!(matches!(
+            read(&mut data.as_slice()),
+            Err(SnapshotError::Malformed(_))
+        ));
+    }
+
+    /// Version 1 snapshot of a [UniformChunk] at id 7 of two nodes with one byte payloads, as written before layouts were added.
+    fn v1_snapshot() -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        put_u32(&mut data, 1);
+        // Def table, then Label table.
+        put_u32(&mut data, 1);
+        put_u128(&mut data, 5);
+        put_u32(&mut data, 0);
+        // Schema: def, node_count, bytes_per_node, id_stride, a payload of 1 byte, no layout flag and no traits.
+        put_u32(&mut data, 1);
+        for value in [0, 2, 1, 1] {
+            put_u32(&mut data, value);
+        }
+        data.extend([1, 1, 0]);
+        put_u32(&mut data, 0);
+        put_u64(&mut data, 1);
+        put_u128(&mut data, 7);
+        data.push(UNIFORM);
+        put_u32(&mut data, 0);
+        put_u64(&mut data, 2);
+        data.extend([10, 20]);
+        data
+    }
+
+    #[test]
+    fn reads_v1() {
+        let data = v1_snapshot();
+        let loaded = read(&mut data.as_slice()).unwrap();
+        let chunk = uniform(&loaded, 7);
+        assert_eq!(chunk.schema.schema.def, Def(5));
+        assert_eq!(chunk.schema.schema.payload_size, Some(1));
+        assert!(chunk.schema.schema.payload_layout.is_none());
+        assert_eq!(*chunk.data, im::vector![10, 20]);
+
+        for version in [0, VERSION + 1] {
+            let mut data = v1_snapshot();
+            data[4..8].copy_from_slice(&version.to_le_bytes());
+            assert!(matches!(
+                read(&mut data.as_slice()),
+                Err(SnapshotError::UnsupportedVersion(v)) if v == version
+            ));
+        }
+    }
+
+    /// Schema in a [schema_table]: its `node_count`, `id_stride` and traits (as their `id_offset` and index of their schema).
+    type TableSchema = (u32, u32, Vec<(u32, u32)>);
+
+    /// Snapshot with no chunks, with a schema table of nodes without payloads.
+    fn schema_table(schemas: &[TableSchema]) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        put_u32(&mut data, VERSION);
+        // One Def and two Labels.
+        put_u32(&mut data, 1);
+        put_u128(&mut data, 1);
+        put_u32(&mut data, 2);
+        put_u128(&mut data, 1);
+        put_u128(&mut data, 2);
+        put_u32(&mut data, schemas.len() as u32);
+        for (node_count, id_stride, traits) in schemas {
+            for value in [0, *node_count, 0, *id_stride] {
+                put_u32(&mut data, value);
+            }
+            data.extend([0, 0]);
+            put_u32(&mut data, traits.len() as u32);
+            for (label, (id_offset, index)) in traits.iter().enumerate() {
+                for value in [label as u32, *id_offset, 0, *index] {
+                    put_u32(&mut data, value);
+                }
+            }
+        }
+        put_u64(&mut data, 0);
+        data
+    }
+
+    #[test]
+    fn rejects_large_schemas() {
+        let malformed =
+            |data: Vec<u8>| matches!(read(&mut data.as_slice()), Err(SnapshotError::Malformed(_)));
+        let chain = |depth: u32| -> Vec<TableSchema> {
+            (0..depth)
+                .map(|i| match i {
+                    0 => (1, 1, vec![]),
+                    _ => (1, i + 1, vec![(1, i - 1)]),
+                })
+                .collect()
+        };
+        assert!(read(&mut schema_table(&chain(MAX_SCHEMA_DEPTH)).as_slice()).is_ok());
+        assert!(malformed(schema_table(&chain(MAX_SCHEMA_DEPTH + 1))));
+
+        // Each schema has the previous one twice as empty traits, so would double in size at each level.
+        let mut doubling = vec![(0, 1, vec![])];
+        for i in 0..64 {
+            doubling.push((0, 1, vec![(1, i), (1, i)]));
+        }
+        assert!(malformed(schema_table(&doubling)));
+        assert!(malformed(schema_table(&doubling[..2])));
+    }
+
+    #[test]
+    fn shares_schema() {
+        let node = |def: u128, payload: Option<u8>, children: Vec<BasicNode>| BasicNode {
+            id: NodeId(0),
+            def: Def(def),
+            payload: payload.map(|p| im::vector![p]),
+            traits: [(Label(1), children)]
+                .into_iter()
+                .filter(|(_, c)| !c.is_empty())
+                .collect(),
+        };
+        let run = || (0..10).map(|i| node(3, Some(i), vec![])).collect();
+        // Two runs of bytes, separated by a node with a different shape so they are in separate chunks.
+        let tree = node(
+            1,
+            None,
+            vec![
+                node(2, None, run()),
+                node(2, Some(1), vec![]),
+                node(2, None, run()),
+            ],
+        );
+        let mut forest = Forest::new();
+        for (id, chunk) in chunker::chunk(
+            &chunker::Analyzed::new(&tree),
+            NodeId(0),
+            &mut ShapeLibrary::default(),
+        ) {
+            forest.insert(id, chunk);
+        }
+        assert_eq!(forest.map_keys().count(), 4);
+
+        let loaded = round_trip(&forest);
+        assert_same(&forest, &loaded);
+        assert!(Shared::ptr_eq(
+            &uniform(&loaded, 1).schema,
+            &uniform(&loaded, 13).schema
+        ));
+    }
+
+    #[test]
+    fn dedups_tables() {
+        let size = |forest: &Forest| {
+            let mut data = vec![];
+            write(forest, &mut data).unwrap();
+            data.len()
+        };
+        let (mut forest, _) = uniform_tree(3);
+        let before = size(&forest);
+        let chunk = forest.find_nodes(ChunkId(NodeId(1))).unwrap().clone();
+        forest.insert(ChunkId(NodeId(100)), chunk);
+        // Just the id, type, schema index, length and data.
+        let data = uniform(&forest, 1).data.len();
+        assert_eq!(size(&forest), before + 16 + 1 + 4 + 8 + data);
+    }
+
+    #[test]
+    fn rejects_invalid() {
+        let (forest, _) = uniform_tree(3);
+        let mut data = vec![];
+        write(&forest, &mut data).unwrap();
+
+        let read = |data: &[u8]| read(&mut &data[..]).err().unwrap();
+        assert!(matches!(
+            read(&data[..data.len() - 1]),
+            SnapshotError::Malformed(_)
+        ));
+        assert!(matches!(read(b"nope"), SnapshotError::NotASnapshot));
+        let mut future = data.clone();
+        future[4..8].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            read(&future),
+            SnapshotError::UnsupportedVersion(v) if v == VERSION + 1
+        ));
+
+        let mut overlapping = forest;
+        overlapping.insert(
+            ChunkId(NodeId(3)),
+            IndirectChunk {
+                def: Def(1),
+                payload: None,
+                payload_layout: None,
+                traits: ImHashMap::default(),
+            }
+            .into(),
+        );
+        let mut data = vec![];
+        write(&overlapping, &mut data).unwrap();
+        assert!(matches!(
+            read(&data),
+            SnapshotError::Forest(ForestError::OverlappingChunks(_, _))
+        ));
+    }
+}
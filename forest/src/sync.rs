@@ -0,0 +1,74 @@
+//! Shared pointers and interior mutability used by forests.
+//!
+//! By default these are single threaded, like the `im_rc` collections.
+//! With the `sync` feature they are thread safe instead (and [crate::im] is the `Arc` based `im` crate),
+//! so forests are `Send + Sync` and snapshots can be shared between threads.
+
+#[cfg(feature = "sync")]
+pub use std::sync::{Arc as Shared, OnceLock as OnceCell, Weak};
+#[cfg(not(feature = "sync"))]
+pub use std::{
+    cell::OnceCell,
+    rc::{Rc as Shared, Weak},
+};
+
+#[cfg(not(feature = "sync"))]
+type Inner<T> = std::cell::RefCell<T>;
+#[cfg(feature = "sync")]
+type Inner<T> = std::sync::RwLock<T>;
+
+/// A [std::cell::RefCell], or with the `sync` feature, a [std::sync::RwLock].
+///
+/// Mutable borrows must not overlap other borrows: the `RefCell` would panic, and the lock would deadlock.
+#[derive(Default)]
+pub struct Lock<T>(Inner<T>);
+
+impl<T> Lock<T> {
+    pub fn new(value: T) -> Self {
+        Lock(Inner::new(value))
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        #[cfg(not(feature = "sync"))]
+        return self.0.get_mut();
+        #[cfg(feature = "sync")]
+        return self
+            .0
+            .get_mut()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+    }
+}
+
+#[cfg(not(feature = "sync"))]
+impl<T> Lock<T> {
+    pub fn borrow(&self) -> std::cell::Ref<'_, T> {
+        self.0.borrow()
+    }
+
+    pub fn borrow_mut(&self) -> std::cell::RefMut<'_, T> {
+        self.0.borrow_mut()
+    }
+}
+
+/// Panics while holding the lock can't leave the protected data half updated
+/// (only caches and interning tables are stored in these), so poisoning is ignored.
+#[cfg(feature = "sync")]
+impl<T> Lock<T> {
+    pub fn borrow(&self) -> std::sync::RwLockReadGuard<'_, T> {
+        self.0
+            .read()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    pub fn borrow_mut(&self) -> std::sync::RwLockWriteGuard<'_, T> {
+        self.0
+            .write()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+impl<T: Clone> Clone for Lock<T> {
+    fn clone(&self) -> Self {
+        Lock::new(self.borrow().clone())
+    }
+}
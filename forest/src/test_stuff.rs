@@ -1,14 +1,19 @@
 use crate::{
     chunk::ChunkId,
+    forest::ChildEdit,
     indirect::enum_chunk,
     indirect_nav::*,
     indirect_node::IndirectChunk,
-    node_id::{IdOffset, NodeId},
-    tree::{Def, Label, Node, NodeNav},
+    nav::WithParent,
+    node_id::{HasId, IdOffset, NodeId},
+    payload::{Endian, Field, PayloadLayout, PayloadType},
+    sync::Shared,
+    tree::{Def, Label, Node, NodeData, NodeNav},
     uniform_chunk::{ChunkSchema, OffsetSchema, RootChunkSchema, UniformChunk},
+    util::ImSlice,
 };
 use rand::Rng;
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use std::{cell::RefCell, collections::HashMap};
 
 pub const PER_CHUNK_ITEM: usize = 5;
 
@@ -55,7 +60,8 @@ pub fn big_tree(size: usize, chunks: usize, chunk_size: usize) -> (Forest, NodeI
         IndirectChunk {
             def,
             payload: None,
-            traits: im_rc::HashMap::default(),
+            payload_layout: None,
+            traits: im::HashMap::default(),
         }
         .into(),
     );
@@ -66,8 +72,9 @@ pub fn big_tree(size: usize, chunks: usize, chunk_size: usize) -> (Forest, NodeI
             ChunkId(id),
             IndirectChunk {
                 def,
-                payload: None, //Some(im_rc::Vector::from_iter([1u8].iter().cloned()).into()),
-                traits: im_rc::HashMap::default(),
+                payload: None, //Some(im::Vector::from_iter([1u8].iter().cloned()).into()),
+                payload_layout: None,
+                traits: im::HashMap::default(),
             }
             .into(),
         );
@@ -75,32 +82,41 @@ pub fn big_tree(size: usize, chunks: usize, chunk_size: usize) -> (Forest, NodeI
         let parent_index = rng.borrow_mut().gen_range(0..nodes.len());
         let parent_id = nodes[parent_index];
 
-        let parent = forest.find_nodes_mut(ChunkId(parent_id)).unwrap();
-
-        match parent {
-            enum_chunk::Chunk::Indirect(basic) => {
-                basic
-                    .traits
-                    .entry(label)
-                    .or_insert_with(Vec::new)
-                    .push(ChunkId(id));
-            }
-            _ => panic!(),
-        };
+        forest
+            .edit(
+                ChunkId(parent_id),
+                |parent| match parent {
+                    enum_chunk::Chunk::Indirect(basic) => {
+                        let children = basic.traits.entry(label).or_insert_with(Vec::new);
+                        children.push(ChunkId(id));
+                        let edit = ChildEdit {
+                            label,
+                            index: children.len() - 1,
+                            removed: vec![],
+                            added: vec![ChunkId(id)],
+                        };
+                        ((), vec![edit])
+                    }
+                    _ => panic!(),
+                },
+                Forest::top_level_count,
+            )
+            .unwrap();
 
         nodes.push(id);
     }
 
     if chunks > 0 {
         // color channel schema
-        let sub_schema = ChunkSchema {
+        let sub_schema = Shared::new(ChunkSchema {
             def: new_def(),
             node_count: 1,
             bytes_per_node: 1,
             id_stride: 1,
             payload_size: Some(1),
+            payload_layout: None,
             traits: HashMap::default(),
-        };
+        });
 
         // Color schema (rgba)
         let schema = ChunkSchema {
@@ -109,6 +125,7 @@ pub fn big_tree(size: usize, chunks: usize, chunk_size: usize) -> (Forest, NodeI
             bytes_per_node: 4,
             id_stride: 5,
             payload_size: None,
+            payload_layout: None,
             traits: vec![
                 (
                     new_label(),
@@ -147,12 +164,11 @@ pub fn big_tree(size: usize, chunks: usize, chunk_size: usize) -> (Forest, NodeI
             .collect(),
         };
 
-        let chunk_schema = Rc::new(RootChunkSchema::new(schema));
+        let chunk_schema = Shared::new(RootChunkSchema::new(schema));
 
         for _ in 0..chunks {
             let id = new_node_id();
-            let data: im_rc::Vector<u8> = std::iter::repeat(&[1u8, 2, 3, 4])
-                .take(chunk_size)
+            let data: im::Vector<u8> = std::iter::repeat_n(&[1u8, 2, 3, 4], chunk_size)
                 .flat_map(|x| x.iter())
                 .cloned()
                 .collect();
@@ -168,18 +184,26 @@ pub fn big_tree(size: usize, chunks: usize, chunk_size: usize) -> (Forest, NodeI
             let parent_index = rng.borrow_mut().gen_range(0..nodes.len());
             let parent_id = nodes[parent_index];
 
-            let parent = forest.find_nodes_mut(ChunkId(parent_id)).unwrap();
-
-            match parent {
-                enum_chunk::Chunk::Indirect(basic) => {
-                    basic
-                        .traits
-                        .entry(label)
-                        .or_insert_with(Vec::new)
-                        .push(ChunkId(id));
-                }
-                _ => panic!(),
-            };
+            forest
+                .edit(
+                    ChunkId(parent_id),
+                    |parent| match parent {
+                        enum_chunk::Chunk::Indirect(basic) => {
+                            let children = basic.traits.entry(label).or_insert_with(Vec::new);
+                            children.push(ChunkId(id));
+                            let edit = ChildEdit {
+                                label,
+                                index: children.len() - 1,
+                                removed: vec![],
+                                added: vec![ChunkId(id)],
+                            };
+                            ((), vec![edit])
+                        }
+                        _ => panic!(),
+                    },
+                    Forest::top_level_count,
+                )
+                .unwrap();
         }
     }
 
@@ -197,7 +221,7 @@ pub fn simple_tree() -> (Forest, NodeId) {
 
     let def = new_def();
     let root_id = new_node_id();
-    let nodes = vec![root_id];
+    let nodes = [root_id];
     let label = new_label();
 
     forest.insert(
@@ -205,20 +229,22 @@ pub fn simple_tree() -> (Forest, NodeId) {
         IndirectChunk {
             def,
             payload: None,
-            traits: im_rc::HashMap::default(),
+            payload_layout: None,
+            traits: im::HashMap::default(),
         }
         .into(),
     );
 
     // color channel schema
-    let sub_schema = ChunkSchema {
+    let sub_schema = Shared::new(ChunkSchema {
         def: new_def(),
         node_count: 1,
         bytes_per_node: 1,
         id_stride: 1,
         payload_size: Some(1),
+        payload_layout: None,
         traits: HashMap::default(),
-    };
+    });
 
     // Color schema (rgba)
     let schema = ChunkSchema {
@@ -227,6 +253,7 @@ pub fn simple_tree() -> (Forest, NodeId) {
         bytes_per_node: 1,
         id_stride: 2,
         payload_size: None,
+        payload_layout: None,
         traits: vec![(
             new_label(),
             OffsetSchema {
@@ -239,12 +266,11 @@ pub fn simple_tree() -> (Forest, NodeId) {
         .collect(),
     };
 
-    let chunk_schema = Rc::new(RootChunkSchema::new(schema));
+    let chunk_schema = Shared::new(RootChunkSchema::new(schema));
 
     for _ in 0..1 {
         let id = new_node_id();
-        let data: im_rc::Vector<u8> = std::iter::repeat(&[1u8, 2])
-            .take(1)
+        let data: im::Vector<u8> = std::iter::repeat_n(&[1u8, 2], 1)
             .flat_map(|x| x.iter())
             .cloned()
             .collect();
@@ -259,23 +285,203 @@ pub fn simple_tree() -> (Forest, NodeId) {
         let parent_index = rng.borrow_mut().gen_range(0..nodes.len());
         let parent_id = nodes[parent_index];
 
-        let parent = forest.find_nodes_mut(ChunkId(parent_id)).unwrap();
+        forest
+            .edit(
+                ChunkId(parent_id),
+                |parent| match parent {
+                    enum_chunk::Chunk::Indirect(basic) => {
+                        let children = basic.traits.entry(label).or_insert_with(Vec::new);
+                        children.push(ChunkId(id));
+                        let edit = ChildEdit {
+                            label,
+                            index: children.len() - 1,
+                            removed: vec![],
+                            added: vec![ChunkId(id)],
+                        };
+                        ((), vec![edit])
+                    }
+                    _ => panic!(),
+                },
+                Forest::top_level_count,
+            )
+            .unwrap();
+    }
 
-        match parent {
-            enum_chunk::Chunk::Indirect(basic) => {
-                basic
-                    .traits
-                    .entry(label)
-                    .or_insert_with(Vec::new)
-                    .push(ChunkId(id));
-            }
-            _ => panic!(),
-        };
+    (forest, root_id)
+}
+
+/// Tree with an [IndirectChunk] root (id 0) with `size` leaf [IndirectChunk]s (ids 1 to size) under [COLORS].
+pub fn wide_tree(size: usize) -> (Forest, NodeId) {
+    let mut forest = Forest::new();
+    let leaf = || IndirectChunk {
+        def: Def(2),
+        payload: None,
+        payload_layout: None,
+        traits: im::HashMap::default(),
+    };
+    let children: Vec<ChunkId> = (1..=size as u128).map(|i| ChunkId(NodeId(i))).collect();
+    for child in children.iter() {
+        forest.insert(*child, leaf().into());
     }
+    let mut root = leaf();
+    root.def = Def(1);
+    root.traits.insert(COLORS, children);
+    forest.insert(ChunkId(NodeId(0)), root.into());
+    (forest, NodeId(0))
+}
+
+/// Labels of the color channels in [uniform_tree].
+pub const CHANNELS: [Label; 4] = [Label(10), Label(11), Label(12), Label(13)];
+/// Label [uniform_tree] parents its chunk under.
+pub const COLORS: Label = Label(1);
+
+/// Tree with sequential ids: an [IndirectChunk] root (id 0) with a [UniformChunk] of `count` rgba colors (ids 1 to count * 5).
+pub fn uniform_tree(count: usize) -> (Forest, NodeId) {
+    let mut forest = Forest::new();
+    let root_id = NodeId(0);
+    let chunk_id = ChunkId(NodeId(1));
 
+    let sub_schema = Shared::new(ChunkSchema {
+        def: Def(3),
+        node_count: 1,
+        bytes_per_node: 1,
+        id_stride: 1,
+        payload_size: Some(1),
+        payload_layout: None,
+        traits: HashMap::default(),
+    });
+    let schema = ChunkSchema {
+        def: Def(2),
+        node_count: count as u32,
+        bytes_per_node: 4,
+        id_stride: 5,
+        payload_size: None,
+        payload_layout: None,
+        traits: CHANNELS
+            .iter()
+            .enumerate()
+            .map(|(i, label)| {
+                (
+                    *label,
+                    OffsetSchema {
+                        id_offset: IdOffset(i as u32 + 1),
+                        byte_offset: i as u32,
+                        schema: sub_schema.clone(),
+                    },
+                )
+            })
+            .collect(),
+    };
+    let data: im::Vector<u8> = (0..count * 4).map(|i| i as u8).collect();
+    forest.insert(
+        chunk_id,
+        UniformChunk {
+            schema: Shared::new(RootChunkSchema::new(schema)),
+            data: data.into(),
+        }
+        .into(),
+    );
+    forest.insert(
+        ChunkId(root_id),
+        IndirectChunk {
+            def: Def(1),
+            payload: None,
+            payload_layout: None,
+            traits: std::iter::once((COLORS, vec![chunk_id])).collect(),
+        }
+        .into(),
+    );
     (forest, root_id)
 }
 
+/// Tree with sequential ids: an [IndirectChunk] root (id 0) with a [UniformChunk] of `count` points (ids 1 to count) under [COLORS].
+/// Point `i` has payload `{x: i, y: -i}`, described by its schema's [PayloadLayout].
+pub fn point_tree(count: usize) -> (Forest, NodeId) {
+    let mut forest = Forest::new();
+    let chunk_id = ChunkId(NodeId(1));
+    let field = |name: &str, offset| Field {
+        name: name.to_owned(),
+        offset,
+        payload_type: PayloadType::Float(4, Endian::Little),
+    };
+    let schema = ChunkSchema {
+        def: Def(4),
+        node_count: count as u32,
+        bytes_per_node: 8,
+        id_stride: 1,
+        payload_size: Some(8),
+        payload_layout: Some(PayloadLayout {
+            fields: vec![field("x", 0), field("y", 4)],
+        }),
+        traits: HashMap::default(),
+    };
+    let data: im::Vector<u8> = (0..count)
+        .flat_map(|i| [i as f32, -(i as f32)])
+        .flat_map(f32::to_le_bytes)
+        .collect();
+    forest.insert(
+        chunk_id,
+        UniformChunk {
+            schema: Shared::new(RootChunkSchema::new(schema)),
+            data: data.into(),
+        }
+        .into(),
+    );
+    forest.insert(
+        ChunkId(NodeId(0)),
+        IndirectChunk {
+            def: Def(1),
+            payload: None,
+            payload_layout: None,
+            traits: std::iter::once((COLORS, vec![chunk_id])).collect(),
+        }
+        .into(),
+    );
+    (forest, NodeId(0))
+}
+
+/// Simple node which refers to its children by id.
+/// Used to insert nodes with [crate::Forest::insert_or_replace_node].
+pub struct IdNode {
+    pub id: NodeId,
+    pub def: Def,
+    pub payload: Option<im::Vector<u8>>,
+    pub traits: HashMap<Label, Vec<NodeId>>,
+}
+
+impl NodeNav<NodeId> for IdNode {
+    type TTraitChildren = std::vec::IntoIter<NodeId>;
+    type TLabels = std::vec::IntoIter<Label>;
+
+    fn get_traits(&self) -> Self::TLabels {
+        self.traits.keys().cloned().collect::<Vec<_>>().into_iter()
+    }
+
+    fn get_trait(&self, label: Label) -> Self::TTraitChildren {
+        self.traits
+            .get(&label)
+            .cloned()
+            .unwrap_or_default()
+            .into_iter()
+    }
+}
+
+impl NodeData for IdNode {
+    fn get_def(&self) -> Def {
+        self.def
+    }
+
+    fn get_payload(&self) -> Option<ImSlice<'_>> {
+        self.payload.as_ref().map(|p| p.focus())
+    }
+}
+
+impl HasId for IdNode {
+    fn get_id(&self) -> NodeId {
+        self.id
+    }
+}
+
 pub fn walk_all<T: Node<T>>(n: T) -> usize {
     let mut count = 1;
     for t in n.get_traits() {
@@ -286,6 +492,29 @@ pub fn walk_all<T: Node<T>>(n: T) -> usize {
     count
 }
 
+/// Checks parents, indexes and siblings of everything under `n` agree with its traits.
+pub fn check_parents(n: impl WithParent + Node + HasId) {
+    for t in n.get_traits() {
+        let ids: Vec<NodeId> = n.get_trait(t).map(|c| c.get_id()).collect();
+        for (i, c) in n.get_trait(t).enumerate() {
+            let p = c.parent().unwrap();
+            assert_eq!(p.label, t);
+            // println!("{}  {}  {}", p.node.get_id().0, n.get_id().0, c.get_id().0);
+            assert_eq!(p.node.get_id(), n.get_id());
+            assert_eq!(c.index_in_parent(), Some(i));
+            assert_eq!(
+                c.prev_sibling().map(|s| s.get_id()),
+                i.checked_sub(1).map(|i| ids[i])
+            );
+            assert_eq!(
+                c.next_sibling().map(|s| s.get_id()),
+                ids.get(i + 1).cloned()
+            );
+            check_parents(c);
+        }
+    }
+}
+
 pub fn walk_direct_all(forest: &Forest, id: ChunkId) -> usize {
     let mut count = 1;
     let n = forest.find_nodes(id).unwrap();
@@ -301,9 +530,6 @@ pub fn walk_direct_all(forest: &Forest, id: ChunkId) -> usize {
 mod tests {
     use super::*;
     use crate::indirect::enum_chunk;
-    use crate::nav::{Nav, WithParent};
-    use crate::node_id::HasId;
-    use crate::tree::NodeNav;
 
     #[test]
     fn basic_nodes() {
@@ -352,7 +578,7 @@ mod tests {
 
         let new_node_id = || {
             let mut id = id.borrow_mut();
-            *id = *id + 1;
+            *id += 1;
             NodeId(*id)
         };
         //let new_node_id = || NodeId(rng.borrow_mut().gen());
@@ -361,14 +587,15 @@ mod tests {
         let new_def = || -> Def { Def(rng.borrow_mut().gen()) };
 
         // color channel schema
-        let sub_schema = ChunkSchema {
+        let sub_schema = Shared::new(ChunkSchema {
             def: new_def(),
             node_count: 1,
             bytes_per_node: 1,
             id_stride: 1,
             payload_size: Some(1),
+            payload_layout: None,
             traits: HashMap::default(),
-        };
+        });
 
         // Color schema (rgba)
         let schema = ChunkSchema {
@@ -377,6 +604,7 @@ mod tests {
             bytes_per_node: 2,
             id_stride: 5,
             payload_size: None,
+            payload_layout: None,
             traits: vec![
                 (
                     new_label(),
@@ -399,10 +627,10 @@ mod tests {
             .collect(),
         };
 
-        let chunk_schema = Rc::new(RootChunkSchema::new(schema));
+        let chunk_schema = Shared::new(RootChunkSchema::new(schema));
 
         let id = new_node_id();
-        let data: im_rc::Vector<u8> = [1u8, 2, 3, 4].iter().cloned().collect();
+        let data: im::Vector<u8> = [1u8, 2, 3, 4].iter().cloned().collect();
         forest.insert(
             ChunkId(id),
             enum_chunk::Chunk::Uniform(UniformChunk {
@@ -415,18 +643,6 @@ mod tests {
         check_parents(nav);
     }
 
-    pub fn check_parents(n: impl WithParent + Node + HasId) {
-        for t in n.get_traits() {
-            for c in n.get_trait(t) {
-                let p = c.parent().unwrap();
-                assert_eq!(p.label, t);
-                // println!("{}  {}  {}", p.node.get_id().0, n.get_id().0, c.get_id().0);
-                assert_eq!(p.node.get_id(), n.get_id());
-                check_parents(c);
-            }
-        }
-    }
-
     #[test]
     fn with_chunks() {
         const PER_CHUNK_ITEM: usize = 5;
@@ -450,6 +666,25 @@ mod tests {
         assert_eq!(n, size + chunks * chunk_size * PER_CHUNK_ITEM);
     }
 
+    #[cfg(feature = "sync")]
+    #[test]
+    fn share_between_threads() {
+        fn check_sync<T: Send + Sync>() {}
+        check_sync::<crate::Forest>();
+        check_sync::<crate::lazy::Forest>();
+        check_sync::<crate::history::History>();
+
+        let (forest, id) = big_tree(10, 10, 100);
+        std::thread::scope(|scope| {
+            let walks: Vec<_> = (0..4)
+                .map(|_| scope.spawn(|| walk_all(forest.nav_from(id).unwrap())))
+                .collect();
+            for walk in walks {
+                assert_eq!(walk.join().unwrap(), 10 + 10 * 100 * PER_CHUNK_ITEM);
+            }
+        });
+    }
+
     // #[test]
     // fn big() {
     //     let size = 100000;
@@ -465,8 +700,8 @@ mod tests {
             std::mem::size_of::<UniformChunk>(),
             std::mem::size_of::<IndirectChunk>(),
             std::mem::size_of::<enum_chunk::Chunk>(),
-            std::mem::size_of::<im_rc::HashMap<Label, Vec<ChunkId>, ahash::RandomState>>(),
-            std::mem::size_of::<im_rc::HashMap<Label, Vec<ChunkId>>>(),
+            std::mem::size_of::<im::HashMap<Label, Vec<ChunkId>, ahash::RandomState>>(),
+            std::mem::size_of::<im::HashMap<Label, Vec<ChunkId>>>(),
             std::mem::size_of::<std::collections::HashMap<Label, Vec<ChunkId>>>(),
         );
         // panic!();
@@ -1,10 +1,10 @@
 //! Core types of the tree abstraction.
 
-use crate::util::ImSlice;
+use crate::{payload::PayloadLayout, util::ImSlice};
 
 pub type IdBase = u128;
 
-#[derive(Clone, PartialEq, Eq, Ord, Hash, PartialOrd, Copy)]
+#[derive(Clone, PartialEq, Eq, Ord, Hash, PartialOrd, Copy, Debug)]
 pub struct Def(pub IdBase);
 #[derive(Clone, PartialEq, Eq, Ord, Hash, PartialOrd, Copy, Debug)]
 pub struct Label(pub IdBase);
@@ -25,7 +25,12 @@ pub trait NodeNav<TChild> {
 /// Combines navigation with data (def and payload)
 pub trait NodeData {
     fn get_def(&self) -> Def;
-    fn get_payload(&self) -> Option<ImSlice>;
+    fn get_payload(&self) -> Option<ImSlice<'_>>;
+
+    /// Fields of the payload, if known (see [crate::payload]).
+    fn get_payload_layout(&self) -> Option<&PayloadLayout> {
+        None
+    }
 }
 
 pub trait Node<TChild = Self>: NodeNav<TChild> + NodeData {}
@@ -1,12 +1,13 @@
 use std::{
+    hash::{Hash, Hasher},
     iter::{empty, Cloned, Empty},
-    rc::Rc,
-    usize,
 };
 
 use crate::{
     chunk::{Chunk, ChunkId},
     node_id::{HasId, IdOffset, NodeId},
+    payload::PayloadLayout,
+    sync::Shared,
     tree::{Def, Label, NodeData, NodeNav},
     util::{slice_with_length, ImSlice},
 };
@@ -15,26 +16,35 @@ use crate::{
 /// Owns the content. Compressed (one copy of schema, rest as blob)
 #[derive(Clone)]
 pub struct UniformChunk {
-    pub data: Box<im_rc::Vector<u8>>,
-    pub schema: Rc<RootChunkSchema>,
+    pub data: Box<im::Vector<u8>>,
+    pub schema: Shared<RootChunkSchema>,
 }
 
 impl PartialEq for UniformChunk {
     fn eq(&self, other: &Self) -> bool {
-        Rc::ptr_eq(&self.schema, &other.schema) & self.data.eq(&other.data)
+        Shared::ptr_eq(&self.schema, &other.schema) & self.data.eq(&other.data)
     }
 }
 
+/// Largest [ChunkSchema::id_stride] supported,
+/// since [RootChunkSchema::new] allocates lookup data for every id in the stride.
+pub const MAX_ID_STRIDE: u32 = 1 << 20;
+
+/// Most levels of nodes a [ChunkSchema] can have, since schemas are cloned, compared and hashed recursively.
+pub const MAX_SCHEMA_DEPTH: u32 = 256;
+
 pub struct RootChunkSchema {
     pub schema: ChunkSchema,
     /// Derived data (from schema) to enable fast lookup of views from id.
-    id_offset_to_byte_offset_and_schema: Vec<Option<OffsetInfo>>,
+    /// Shared by [RootChunkSchema::with_node_count]: only the entry for the top level node depends on `node_count`,
+    /// so [RootChunkSchema::lookup_schema] uses `schema` for it.
+    id_offset_to_byte_offset_and_schema: Shared<[Option<OffsetInfo>]>,
 }
 
 #[derive(Clone)]
 struct OffsetInfo {
     byte_offset: u32,
-    schema: ChunkSchema,
+    schema: Shared<ChunkSchema>,
     parent: ParentInfo,
 }
 
@@ -42,6 +52,7 @@ struct OffsetInfo {
 pub struct ParentInfo {
     /// None for top level nodes in chunk
     pub parent: Option<(IdOffset, Label)>,
+    /// Index within the parent's trait, or within the chunk for top level nodes.
     pub index: usize,
 }
 
@@ -58,7 +69,7 @@ impl RootChunkSchema {
 
         fn add(
             data: &mut [Option<OffsetInfo>],
-            s: &ChunkSchema,
+            s: &Shared<ChunkSchema>,
             byte_offset: u32,
             id_offset: usize,
             parent: ParentInfo,
@@ -88,8 +99,8 @@ impl RootChunkSchema {
         }
 
         add(
-            &mut data_outer.as_mut_slice(),
-            &schema,
+            data_outer.as_mut_slice(),
+            &Shared::new(schema.clone()),
             0,
             0,
             ParentInfo {
@@ -100,12 +111,35 @@ impl RootChunkSchema {
 
         RootChunkSchema {
             schema,
-            id_offset_to_byte_offset_and_schema: data_outer,
+            id_offset_to_byte_offset_and_schema: data_outer.into(),
+        }
+    }
+
+    /// Schema for `node_count` top level nodes of the same shape as this one.
+    /// Shares the derived lookup data, so this only copies the top level [ChunkSchema].
+    pub fn with_node_count(&self, node_count: u32) -> Self {
+        RootChunkSchema {
+            schema: ChunkSchema {
+                node_count,
+                ..self.schema.clone()
+            },
+            id_offset_to_byte_offset_and_schema: self.id_offset_to_byte_offset_and_schema.clone(),
         }
     }
+
+    /// True if `self` and `other` describe nodes of the same shape (they may differ in `node_count`).
+    pub fn same_shape(&self, other: &RootChunkSchema) -> bool {
+        Shared::ptr_eq(
+            &self.id_offset_to_byte_offset_and_schema,
+            &other.id_offset_to_byte_offset_and_schema,
+        ) || (ChunkSchema {
+            node_count: other.schema.node_count,
+            ..self.schema.clone()
+        }) == other.schema
+    }
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
 pub struct ChunkSchema {
     pub def: Def,
     /// number of nodes at this level
@@ -114,20 +148,38 @@ pub struct ChunkSchema {
     /// total number in subtree (nodes under traits + 1)
     pub id_stride: u32,
     pub payload_size: Option<u16>,
+    /// Fields of the payload. Must fit in `payload_size` (see [PayloadLayout::fits]).
+    pub payload_layout: Option<PayloadLayout>,
     pub traits: std::collections::HashMap<Label, OffsetSchema, ahash::RandomState>,
 }
 
+/// Hashes traits in label order, so equal schemas hash the same.
+impl Hash for ChunkSchema {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.def.hash(state);
+        self.node_count.hash(state);
+        self.bytes_per_node.hash(state);
+        self.id_stride.hash(state);
+        self.payload_size.hash(state);
+        self.payload_layout.hash(state);
+        let mut traits: Vec<_> = self.traits.iter().collect();
+        traits.sort_by_key(|(label, _)| **label);
+        traits.hash(state);
+    }
+}
+
 /// Offsets are for the first iteration (of a possible schema.node_count iterations)
 /// and are relative to the immediate parent (the node not the trait).
 /// Thus these offsets need to account for the parent's payload, the parent's id,
 /// and all traits which precede this one (including their repetitions via node_count).
 /// Note thats its allowed the layout in id space and byte space to differ, so which traits are preceding in each might not be the same.
 /// Its also allowed to leave unused gaps in either id space or byte space.
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub struct OffsetSchema {
     pub id_offset: IdOffset,
     pub byte_offset: u32,
-    pub schema: ChunkSchema,
+    /// Shared, so schemas can be cloned (and nested) without copying the schema of their traits.
+    pub schema: Shared<ChunkSchema>,
 }
 
 // Views
@@ -154,7 +206,7 @@ impl<'a> Chunk for &'a UniformChunk {
                 );
                 let view = ChunkInfo {
                     first_id: id,
-                    schema: &info.schema,
+                    schema: info.schema,
                     data,
                 };
                 Some(UniformChunkNode { view, offset: 0 })
@@ -169,11 +221,15 @@ impl<'a> Chunk for &'a UniformChunk {
             offset: 0,
         })
     }
+
+    fn id_extent(&self) -> IdOffset {
+        UniformChunk::id_extent(self)
+    }
 }
 
 /// For parent info: Allow viewing the tree of chunks as Node.
 /// Since this chunk is leaf only, returns Empty for everything.
-impl<'a> NodeNav<ChunkId> for &'a UniformChunk {
+impl NodeNav<ChunkId> for &UniformChunk {
     type TTraitChildren = Empty<ChunkId>;
     type TLabels = Empty<Label>;
 
@@ -188,7 +244,7 @@ impl<'a> NodeNav<ChunkId> for &'a UniformChunk {
 
 impl RootChunkSchema {
     /// Returns None if id not present.
-    pub fn lookup_schema(&self, first_id: NodeId, id: NodeId) -> Option<OffsetInfoRef> {
+    pub fn lookup_schema(&self, first_id: NodeId, id: NodeId) -> Option<OffsetInfoRef<'_>> {
         if id < first_id {
             None
         } else if id < first_id + IdOffset(self.schema.id_stride * self.schema.node_count) {
@@ -197,32 +253,30 @@ impl RootChunkSchema {
             let info = self.id_offset_to_byte_offset_and_schema[rem as usize].as_ref()?;
             let byte_offset = info.byte_offset + div * self.schema.bytes_per_node;
 
+            // Nodes under the top level node `div` are offset by whole top level strides,
+            // which moves their parent the same amount and leaves their index within the parent's trait unchanged.
             let parent = match info.parent.parent {
-                Some(info_parent) => ParentInfo {
+                Some((parent_offset, label)) => ParentInfo {
                     parent: Some((
-                        IdOffset(
-                            info_parent.0 .0
-                                + div
-                                    * self.id_offset_to_byte_offset_and_schema
-                                        [info_parent.0 .0 as usize]
-                                        .as_ref()
-                                        .unwrap()
-                                        .schema
-                                        .id_stride,
-                        ),
-                        info_parent.1,
+                        IdOffset(parent_offset.0 + div * self.schema.id_stride),
+                        label,
                     )),
-                    index: info.parent.index, // TODO: Index of parent? Used as index of child? Should this be div instead?
+                    index: info.parent.index,
                 },
                 None => ParentInfo {
                     parent: None,
-                    index: div as usize, // This is index within chunk at chunk top level, not index within trait.
+                    index: div as usize,
                 },
             };
 
+            // The shared lookup data may come from a schema with a different top level `node_count`.
+            let schema = match parent.parent {
+                Some(_) => &info.schema,
+                None => &self.schema,
+            };
             Some(OffsetInfoRef {
                 byte_offset,
-                schema: &info.schema,
+                schema,
                 parent,
             })
         } else {
@@ -242,7 +296,69 @@ impl UniformChunk {
     pub fn get_count(&self) -> usize {
         self.schema.schema.node_count as usize
     }
-    pub fn view(&self, id: NodeId) -> ChunkInfo {
+
+    /// Number of ids reserved by this chunk (starting at its first id).
+    pub fn id_extent(&self) -> IdOffset {
+        IdOffset(self.schema.schema.id_stride * self.schema.schema.node_count)
+    }
+
+    /// Chunk containing the top level nodes `start..end` of this chunk.
+    /// The data is shared copy on write, so this is `O(log(n))` in the size of the data.
+    pub fn slice(&self, start: usize, end: usize) -> UniformChunk {
+        let bytes_per_node = self.schema.schema.bytes_per_node as usize;
+        let mut data = (*self.data).clone();
+        self.with_data(
+            data.slice(start * bytes_per_node..end * bytes_per_node),
+            end - start,
+        )
+    }
+
+    /// Splits this chunk into its top level nodes before `index` and those from `index` on.
+    /// The second chunk starts at id `first_id + index * id_stride`, where `first_id` is the id of this one.
+    ///
+    /// `O(log(n))` in the size of the data. Panics if `index > self.get_count()`.
+    pub fn split_at(&self, index: usize) -> (UniformChunk, UniformChunk) {
+        assert!(index <= self.get_count(), "split index out of range");
+        let bytes_per_node = self.schema.schema.bytes_per_node as usize;
+        let (before, after) = (*self.data).clone().split_at(index * bytes_per_node);
+        (
+            self.with_data(before, index),
+            self.with_data(after, self.get_count() - index),
+        )
+    }
+
+    /// Joins this chunk (at `first_id`) with `other` (at `other_id`) into one chunk at `first_id`.
+    /// Returns None unless the chunks have the same shape and `other` starts right after the ids of this one,
+    /// or if the joined chunk's id or byte offsets would not fit in a u32 (see [crate::chunker::Shape::max_run]).
+    ///
+    /// `O(log(n))` in the size of the data.
+    pub fn try_concat(
+        &self,
+        first_id: NodeId,
+        other: &UniformChunk,
+        other_id: NodeId,
+    ) -> Option<UniformChunk> {
+        if first_id + self.id_extent() != other_id || !self.schema.same_shape(&other.schema) {
+            return None;
+        }
+        let schema = &self.schema.schema;
+        let node_count = u32::try_from(self.get_count() + other.get_count()).ok()?;
+        schema.id_stride.checked_mul(node_count)?;
+        schema.bytes_per_node.checked_mul(node_count)?;
+        let mut data = (*self.data).clone();
+        data.append((*other.data).clone());
+        Some(self.with_data(data, self.get_count() + other.get_count()))
+    }
+
+    /// Chunk of `node_count` top level nodes of the same shape as this one, stored in `data`.
+    fn with_data(&self, data: im::Vector<u8>, node_count: usize) -> UniformChunk {
+        UniformChunk {
+            data: Box::new(data),
+            schema: Shared::new(self.schema.with_node_count(node_count as u32)),
+        }
+    }
+
+    pub fn view(&self, id: NodeId) -> ChunkInfo<'_> {
         ChunkInfo {
             first_id: id,
             schema: &self.schema.schema,
@@ -275,7 +391,7 @@ impl<'a> NodeNav<UniformChunkNode<'a>> for UniformChunkNode<'a> {
                 let trait_data = slice_with_length(
                     node_data,
                     x.byte_offset as usize,
-                    x.schema.bytes_per_node as usize,
+                    (x.schema.bytes_per_node * x.schema.node_count) as usize,
                 );
                 let trait_first_id = self.get_id() + x.id_offset;
                 ChunkIterator::View(UniformChunkNode {
@@ -298,7 +414,7 @@ impl<'a> NodeData for UniformChunkNode<'a> {
         self.view.schema.def
     }
 
-    fn get_payload(&self) -> Option<ImSlice> {
+    fn get_payload(&self) -> Option<ImSlice<'_>> {
         match self.view.schema.payload_size {
             Some(p) => {
                 let node_data = self.data();
@@ -307,6 +423,10 @@ impl<'a> NodeData for UniformChunkNode<'a> {
             None => None,
         }
     }
+
+    fn get_payload_layout(&self) -> Option<&PayloadLayout> {
+        self.view.schema.payload_layout.as_ref()
+    }
 }
 
 // Views first item as chunk in as node
@@ -317,7 +437,10 @@ impl HasId for UniformChunkNode<'_> {
 }
 
 pub enum ChunkIterator<'a> {
+    /// Iterates from the node to the end of its [ChunkInfo].
     View(UniformChunkNode<'a>),
+    /// Yields just the node.
+    Single(Option<UniformChunkNode<'a>>),
     Empty,
 }
 
@@ -335,7 +458,126 @@ impl<'a> Iterator for ChunkIterator<'a> {
                     None
                 }
             }
+            ChunkIterator::Single(node) => node.take(),
+            ChunkIterator::Empty => None,
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = match self {
+            ChunkIterator::View(node) => {
+                node.view.schema.node_count.saturating_sub(node.offset) as usize
+            }
+            ChunkIterator::Single(node) => node.iter().len(),
+            ChunkIterator::Empty => 0,
+        };
+        (len, Some(len))
+    }
+
+    /// Jumps directly to the node (using the schema's id_stride) instead of visiting the ones before it.
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        match self {
+            ChunkIterator::View(ref mut node) => {
+                node.offset = u32::try_from(n)
+                    .ok()
+                    .and_then(|n| node.offset.checked_add(n))
+                    .unwrap_or(u32::MAX);
+                self.next()
+            }
+            ChunkIterator::Single(node) => node.take().filter(|_| n == 0),
             ChunkIterator::Empty => None,
         }
     }
 }
+
+impl ExactSizeIterator for ChunkIterator<'_> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{indirect::enum_chunk, test_stuff::uniform_tree};
+
+    fn colors(count: usize) -> UniformChunk {
+        let (forest, _) = uniform_tree(count);
+        match forest.find_nodes(ChunkId(NodeId(1))) {
+            Some(enum_chunk::Chunk::Uniform(chunk)) => chunk.clone(),
+            _ => panic!("expected uniform chunk"),
+        }
+    }
+
+    fn payload(chunk: &UniformChunk, first_id: u128, id: u128) -> Vec<u8> {
+        let node = chunk.get(NodeId(first_id), NodeId(id)).unwrap();
+        node.get_payload().unwrap().into_iter().cloned().collect()
+    }
+
+    #[test]
+    fn split_and_concat() {
+        let chunk = colors(5);
+        let (before, after) = chunk.split_at(2);
+        assert_eq!((before.get_count(), after.get_count()), (2, 3));
+        assert!(before.schema.same_shape(&after.schema));
+        // Channel 1 of color 3 (id 1 + 3 * 5 + 2) is channel 1 of color 1 in `after`, which starts at id 11.
+        assert_eq!(payload(&after, 11, 18), payload(&chunk, 1, 18));
+        assert!((&after).get(NodeId(11), NodeId(26)).is_none());
+
+        let joined = before.try_concat(NodeId(1), &after, NodeId(11)).unwrap();
+        assert_eq!(joined.get_count(), 5);
+        assert_eq!(joined.data, chunk.data);
+
+        // Ids must be contiguous.
+        assert!(before.try_concat(NodeId(1), &after, NodeId(12)).is_none());
+        assert!(after.try_concat(NodeId(11), &before, NodeId(1)).is_none());
+
+        let (empty, all) = chunk.split_at(0);
+        assert_eq!((empty.get_count(), all.get_count()), (0, 5));
+        assert_eq!(all.data, chunk.data);
+    }
+
+    #[test]
+    fn concat_requires_same_shape() {
+        let chunk = colors(2);
+        let other = UniformChunk {
+            data: chunk.data.clone(),
+            schema: Shared::new(RootChunkSchema::new(ChunkSchema {
+                def: Def(9),
+                ..chunk.schema.schema.clone()
+            })),
+        };
+        assert!(chunk.try_concat(NodeId(1), &other, NodeId(11)).is_none());
+
+        // Equal schemas which are not shared still match.
+        let copy = UniformChunk {
+            data: chunk.data.clone(),
+            schema: Shared::new(RootChunkSchema::new(chunk.schema.schema.clone())),
+        };
+        let joined = chunk.try_concat(NodeId(1), &copy, NodeId(11)).unwrap();
+        assert_eq!(joined.get_count(), 4);
+    }
+
+    #[test]
+    fn concat_fits_in_u32() {
+        // Only the schemas matter here, so the data is left empty.
+        let chunk = |id_stride: u32, bytes_per_node: u32, node_count: u32| UniformChunk {
+            data: Box::default(),
+            schema: Shared::new(RootChunkSchema::new(ChunkSchema {
+                def: Def(1),
+                node_count,
+                bytes_per_node,
+                id_stride,
+                payload_size: None,
+                payload_layout: None,
+                traits: Default::default(),
+            })),
+        };
+        // u32::MAX / (1 << 20) = 4095 nodes fit.
+        for (id_stride, bytes_per_node) in [(1 << 20, 0), (1, 1 << 20)] {
+            let first = chunk(id_stride, bytes_per_node, 4000);
+            let next = NodeId(1) + first.id_extent();
+            let fits = chunk(id_stride, bytes_per_node, 95);
+            let joined = first.try_concat(NodeId(1), &fits, next).unwrap();
+            assert_eq!(joined.get_count(), 4095);
+            let too_many = chunk(id_stride, bytes_per_node, 96);
+            assert!(first.try_concat(NodeId(1), &too_many, next).is_none());
+        }
+    }
+}
@@ -1,22 +1,22 @@
-use im_rc::vector::Focus;
+use im::vector::Focus;
 
-pub type ImSlice<'a> = im_rc::vector::Focus<'a, u8>;
+pub type ImSlice<'a> = im::vector::Focus<'a, u8>;
 
 pub fn slice_with_length(
-    focus: im_rc::vector::Focus<'_, u8>,
+    focus: im::vector::Focus<'_, u8>,
     offset: usize,
     length: usize,
 ) -> Focus<'_, u8> {
     focus.narrow(offset..offset + length)
 }
 
-pub type ImHashMap<K, V> = im_rc::HashMap<K, V, ahash::RandomState>;
+pub type ImHashMap<K, V> = im::HashMap<K, V, ahash::RandomState>;
 
 mod tests {
     #[test]
     fn narrow() {
         // Confirms focus.narrow indexes relative to the beginning of the restricted range (not clear from docs)
-        let data = im_rc::vector![0, 1, 2, 3, 4, 5];
+        let data = im::vector![0, 1, 2, 3, 4, 5];
 
         let mut s1 = data.focus().narrow(3..4);
         let mut s2 = s1.clone().narrow(0..1);